@@ -0,0 +1,348 @@
+// Couchbase Lite online backup and restore
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    CblRef, CouchbaseLiteError, Database, Error, Fleece, FleeceReference, MutableDict,
+    c_api::{CBLDatabase_BeginTransaction, CBLDatabase_EndTransaction, CBLError},
+    error::{Result, failure},
+    metadata::metadata_name,
+};
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BUNDLE_EXTENSION: &str = "cblite2";
+/** The extension of the manifest written next to a backup, outside of its bundle. */
+const MANIFEST_EXTENSION: &str = "manifest.json";
+/** The suffixes of the names of a backup being copied next to a database to restore it, and of
+a completely copied one. */
+const PARTIAL_RESTORE_SUFFIX: &str = ".restoring-partial";
+const RESTORE_SUFFIX: &str = ".restoring";
+/** SQLite's shared-memory index of the write-ahead log, rebuilt when the database is opened. */
+const SHM_FILE: &str = "db.sqlite3-shm";
+/** The number of bytes copied between two progress reports. */
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/** The progress of a backup or restore, reported as the files are copied, at least every
+megabyte. The total includes the companion database this crate keeps next to the database. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub bytes_copied: u64,
+    pub total_bytes: u64,
+}
+
+/** The description of a backup, recorded when it was made. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    /** The name of the database that was backed up. */
+    pub source_name: String,
+    pub created: SystemTime,
+    /** True if the backup is encrypted, and must be opened with the source database's key. */
+    pub encrypted: bool,
+    /** True if the backup includes a copy of the companion database holding this crate's state
+    about the database, next to it. */
+    pub includes_metadata: bool,
+}
+
+impl BackupInfo {
+    /** Reads the description of the backup at `backup_path`, the path returned by
+    `Database::backup_to`. */
+    pub fn read<P: AsRef<Path>>(backup_path: P) -> Result<Self> {
+        let json = fs::read_to_string(manifest_path(backup_path.as_ref())).map_err(io_error)?;
+        let doc = Fleece::parse_json(&json)?;
+        let dict = doc.as_dict();
+        Ok(Self {
+            source_name: dict
+                .get("sourceName")
+                .as_string()
+                .unwrap_or_default()
+                .to_string(),
+            created: UNIX_EPOCH + Duration::from_secs(dict.get("created").as_u64_or_0()),
+            encrypted: dict.get("encrypted").as_bool_or_false(),
+            includes_metadata: dict.get("metadata").as_bool_or_false(),
+        })
+    }
+}
+
+impl Database {
+    /** Backs up the open database into `directory`, as a database named `name` that can be
+    opened directly or put back in place with `restore_from`. Returns the path of the backup;
+    its description (see `BackupInfo`) is written next to it, as is a copy of the companion
+    database holding this crate's state about the database, like its index configurations and
+    maintenance schedule.
+
+    The files are copied inside a transaction on each database, so that other connections to
+    them in this process wait for the copy to finish before writing. Writers in other processes
+    aren't held off: if another process may write to the database, close it everywhere and use
+    `Database::copy_file` instead. Blobs are included, and an encrypted database stays encrypted
+    with the same key. */
+    pub fn backup_to<P: AsRef<Path>>(
+        &self,
+        directory: P,
+        name: &str,
+        progress: Option<&mut dyn FnMut(BackupProgress)>,
+    ) -> Result<PathBuf> {
+        let target = bundle_path(directory.as_ref(), name);
+        let target_metadata = metadata_bundle_path(&target);
+        if target.exists() || target_metadata.exists() || manifest_path(&target).exists() {
+            return Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+        }
+
+        let metadata = self.open_metadata()?;
+        let mut bundles = vec![(self.path(), target.clone())];
+        if let Some(metadata) = &metadata {
+            bundles.push((metadata.path(), target_metadata.clone()));
+        }
+        let remove_copies = || {
+            let _ = fs::remove_dir_all(&target);
+            let _ = fs::remove_dir_all(&target_metadata);
+        };
+
+        begin_transaction(self)?;
+        let copied = match &metadata {
+            Some(metadata) => begin_transaction(metadata).and_then(|_| {
+                let copied = copy_bundles(&bundles, progress);
+                end_transaction(metadata).map(|_| copied)
+            }),
+            None => Ok(copy_bundles(&bundles, progress)),
+        };
+        let ended = end_transaction(self);
+        let closed = metadata.map_or(Ok(()), Database::close);
+        let copied = copied.and_then(|copied| ended.and(closed).and(copied.map_err(io_error)));
+        if let Err(err) = copied {
+            remove_copies();
+            return Err(err);
+        }
+
+        let mut manifest = MutableDict::new();
+        manifest.at("sourceName").put_string(self.name());
+        manifest.at("created").put_i64(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs() as i64),
+        );
+        manifest
+            .at("encrypted")
+            .put_bool(self.config().get_encryption_key().is_some());
+        manifest.at("metadata").put_bool(bundles.len() > 1);
+        fs::write(manifest_path(&target), manifest.to_json()).map_err(io_error)?;
+        Ok(target)
+    }
+
+    /** Replaces the database `name` in `in_directory` with the backup at `backup_path`, the path
+    returned by `backup_to`, along with this crate's companion database. Fails with `Busy` if the
+    database is open in this process; it must not be open in another process either, which
+    can't be checked.
+
+    The backup is first copied next to the database, so a failure while copying leaves the
+    database untouched. Then the database is deleted and the copies renamed in its place. If the
+    process stops between these two steps, the database is missing until
+    `recover_interrupted_restore` is called, which apps using `restore_from` should do before
+    opening the database. */
+    pub fn restore_from<P: AsRef<Path>, Q: AsRef<Path>>(
+        backup_path: P,
+        name: &str,
+        in_directory: Q,
+        progress: Option<&mut dyn FnMut(BackupProgress)>,
+    ) -> Result<()> {
+        let backup_path = backup_path.as_ref();
+        let info = BackupInfo::read(backup_path)?;
+        let in_directory = in_directory.as_ref();
+        let target = bundle_path(in_directory, name);
+        let partial = bundle_path(in_directory, &format!("{}{}", name, PARTIAL_RESTORE_SUFFIX));
+        let staged = bundle_path(in_directory, &format!("{}{}", name, RESTORE_SUFFIX));
+
+        let mut bundles = vec![(backup_path.to_path_buf(), partial.clone())];
+        if info.includes_metadata {
+            bundles.push((
+                metadata_bundle_path(backup_path),
+                metadata_bundle_path(&partial),
+            ));
+        }
+        let remove_copies = |bundle: &Path| {
+            let _ = fs::remove_dir_all(bundle);
+            let _ = fs::remove_dir_all(metadata_bundle_path(bundle));
+        };
+
+        // The staged copy is only given its final name once complete, its companion first:
+        remove_copies(&partial);
+        remove_copies(&staged);
+        if let Err(err) = copy_bundles(&bundles, progress).and_then(|_| stage(&partial, &staged)) {
+            remove_copies(&partial);
+            return Err(io_error(err));
+        }
+
+        // Deleting the database fails if it's open in this process:
+        if target.exists() {
+            if let Err(err) = Self::delete_file(name, in_directory) {
+                remove_copies(&staged);
+                return Err(err);
+            }
+        }
+        Self::delete_metadata(name, in_directory)?;
+        move_in_place(&staged, &target).map_err(io_error)
+    }
+
+    /** Completes a `restore_from` of the database `name` in `in_directory` that was interrupted
+    after deleting the database, by moving the restored copy in place. Returns true if there was
+    such a restore to complete. A restore interrupted before that point left the database
+    untouched, and its leftovers are removed. */
+    pub fn recover_interrupted_restore<P: AsRef<Path>>(
+        name: &str,
+        in_directory: P,
+    ) -> Result<bool> {
+        let in_directory = in_directory.as_ref();
+        let target = bundle_path(in_directory, name);
+        let partial = bundle_path(in_directory, &format!("{}{}", name, PARTIAL_RESTORE_SUFFIX));
+        let staged = bundle_path(in_directory, &format!("{}{}", name, RESTORE_SUFFIX));
+
+        let _ = fs::remove_dir_all(&partial);
+        let _ = fs::remove_dir_all(metadata_bundle_path(&partial));
+        if !staged.exists() || target.exists() {
+            let _ = fs::remove_dir_all(metadata_bundle_path(&staged));
+            if staged.exists() {
+                fs::remove_dir_all(&staged).map_err(io_error)?;
+            }
+            return Ok(false);
+        }
+        // Any companion left describes the deleted database:
+        Self::delete_metadata(name, in_directory)?;
+        move_in_place(&staged, &target).map_err(io_error)?;
+        Ok(true)
+    }
+}
+
+fn bundle_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}.{}", name, BUNDLE_EXTENSION))
+}
+
+fn manifest_path(bundle: &Path) -> PathBuf {
+    bundle.with_extension(MANIFEST_EXTENSION)
+}
+
+/** The path of the copy of this crate's companion database kept next to a database bundle. */
+fn metadata_bundle_path(bundle: &Path) -> PathBuf {
+    let name = bundle.file_stem().unwrap_or_default().to_string_lossy();
+    bundle.with_file_name(format!("{}.{}", metadata_name(&name), BUNDLE_EXTENSION))
+}
+
+fn begin_transaction(db: &Database) -> Result<()> {
+    let mut err = CBLError::default();
+    if unsafe { CBLDatabase_BeginTransaction(db.get_ref(), &mut err) } {
+        Ok(())
+    } else {
+        failure(err)
+    }
+}
+
+fn end_transaction(db: &Database) -> Result<()> {
+    let mut err = CBLError::default();
+    if unsafe { CBLDatabase_EndTransaction(db.get_ref(), false, &mut err) } {
+        Ok(())
+    } else {
+        failure(err)
+    }
+}
+
+/** Renames a copied bundle to its staged name, after its companion if it has one, so that the
+staged bundle is only there once both are complete. */
+fn stage(partial: &Path, staged: &Path) -> io::Result<()> {
+    let metadata = metadata_bundle_path(partial);
+    if metadata.exists() {
+        fs::rename(metadata, metadata_bundle_path(staged))?;
+    }
+    fs::rename(partial, staged)
+}
+
+/** Moves a staged bundle and its companion in place of a database that was deleted, the
+companion first, since the staged bundle marks a restore to complete. */
+fn move_in_place(staged: &Path, target: &Path) -> io::Result<()> {
+    let metadata = metadata_bundle_path(staged);
+    if metadata.exists() {
+        fs::rename(metadata, metadata_bundle_path(target))?;
+    }
+    fs::rename(staged, target)
+}
+
+/** Copies database bundles to their targets, including their attachments directories, except
+for files that are specific to an open database. */
+fn copy_bundles(
+    bundles: &[(PathBuf, PathBuf)],
+    mut progress: Option<&mut dyn FnMut(BackupProgress)>,
+) -> io::Result<()> {
+    let mut files = vec![];
+    for (source, target) in bundles {
+        let mut bundle_files = vec![];
+        list_files(source, Path::new(""), &mut bundle_files)?;
+        bundle_files.retain(|(path, _)| path != Path::new(SHM_FILE));
+        files.extend(
+            bundle_files
+                .into_iter()
+                .map(|(path, size)| (source.join(&path), target.join(&path), size)),
+        );
+    }
+    let mut report = BackupProgress {
+        bytes_copied: 0,
+        total_bytes: files.iter().map(|(_, _, size)| size).sum(),
+    };
+
+    let mut buffer = vec![0; COPY_CHUNK_SIZE];
+    for (_, target) in bundles {
+        fs::create_dir_all(target)?;
+    }
+    for (source, target, _) in files {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut reader = fs::File::open(source)?;
+        let mut writer = fs::File::create(target)?;
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read])?;
+            report.bytes_copied += read as u64;
+            if let Some(progress) = progress.as_mut() {
+                progress(report);
+            }
+        }
+        writer.sync_all()?;
+    }
+    Ok(())
+}
+
+/** Lists the files under `root.join(relative)`, with their paths relative to `root` and sizes. */
+fn list_files(root: &Path, relative: &Path, files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_files(root, &path, files)?;
+        } else {
+            files.push((path, metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+fn io_error(_: io::Error) -> Error {
+    Error::cbl_error(CouchbaseLiteError::IOError)
+}
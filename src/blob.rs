@@ -16,15 +16,15 @@
 //
 
 use crate::{
-    CblRef, Database, Dict, FleeceReference, Result, Slot, check_io, check_ptr, failure, release,
-    retain,
+    CblRef, Database, Dict, FleeceReference, Result, Slot, ToFleece, check_io, check_ptr, failure,
+    release, retain,
     slice::{from_bytes, from_str},
     c_api::{
         CBLBlob, CBLBlobReadStream, CBLBlobReader_Close, CBLBlobReader_Read, CBLBlobWriteStream,
         CBLBlobWriter_Close, CBLBlobWriter_Create, CBLBlobWriter_Write, CBLBlob_Content,
         CBLBlob_ContentType, CBLBlob_CreateWithData, CBLBlob_CreateWithStream, CBLBlob_Digest,
-        CBLBlob_Length, CBLBlob_OpenContentStream, CBLBlob_Properties, CBLError, FLDict_GetBlob,
-        FLSlot_SetBlob, FLValue_AsDict,
+        CBLBlob_Length, CBLBlob_OpenContentStream, CBLBlob_Properties, CBLDatabase_GetBlob,
+        CBLError, FLDict_GetBlob, FLSlot_SetBlob, FLValue_AsDict,
     },
 };
 
@@ -70,11 +70,14 @@ impl Blob {
     // called by FleeceReference::as_blob()
     pub(crate) fn from_value<V: FleeceReference>(value: &V) -> Option<Self> {
         unsafe {
+            // The blob belongs to the document, so it has to be retained
             let blob = FLDict_GetBlob(FLValue_AsDict(value._fleece_ref()));
             if blob.is_null() {
                 None
             } else {
-                Some(Self { cbl_ref: blob })
+                Some(Self {
+                    cbl_ref: retain(blob as *mut CBLBlob),
+                })
             }
         }
     }
@@ -143,6 +146,26 @@ impl Clone for Blob {
     }
 }
 
+//////// DATABASE BLOB ACCESS:
+
+impl Database {
+    /** Returns the blob described by `properties`, the metadata dictionary of a blob in a
+    document, or `None` if its content isn't in the database. */
+    pub fn get_blob(&self, properties: &Dict) -> Result<Option<Blob>> {
+        unsafe {
+            let mut err = CBLError::default();
+            let blob = CBLDatabase_GetBlob(self.get_ref(), properties.get_ref(), &mut err);
+            if !blob.is_null() {
+                Ok(Some(Blob { cbl_ref: blob }))
+            } else if err.code == 0 {
+                Ok(None)
+            } else {
+                failure(err)
+            }
+        }
+    }
+}
+
 //////// BLOB ADDITIONS FOR ARRAY / DICT:
 
 impl Slot<'_> {
@@ -152,6 +175,13 @@ impl Slot<'_> {
     }
 }
 
+/** Blobs are stored as a reference to their contents, as with `Slot::put_blob`. */
+impl ToFleece for Blob {
+    fn to_fleece(&self, slot: Slot) {
+        unsafe { FLSlot_SetBlob(slot.get_ref(), self.get_ref() as *mut CBLBlob) }
+    }
+}
+
 //////// BLOB READER
 
 /** A stream for reading Blob conents. */
@@ -0,0 +1,442 @@
+// Couchbase Lite ready-made replication conflict resolvers
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{ConflictResolver, Dict, Document, MutableArray, MutableDict, Slot, Value, ValueType};
+
+use std::collections::HashMap;
+use std::fmt;
+
+//////// STRATEGIES:
+
+/** How a conflict between the local and the remote revisions of a document is resolved.
+A revision is `None` when the document was deleted on that side; resolving to `None` deletes
+the document. */
+pub enum ConflictStrategy {
+    /** The local revision wins, deleted or not. */
+    LocalWins,
+    /** The remote revision wins, deleted or not. */
+    RemoteWins,
+    /** A deletion wins over a modification; if neither side is deleted, the remote revision
+    wins. */
+    DeleteWins,
+    /** The revision with the latest timestamp in this property wins: a number of milliseconds
+    since the Unix epoch, or an ISO-8601 date. A revision without a timestamp, or deleted,
+    loses; the remote revision wins ties. */
+    LastWriteWins(String),
+    /** The properties of both revisions are merged, field by field. A deletion can't be
+    merged: if either side is deleted, the remote revision wins. */
+    Merge(FieldMerge),
+    /** A resolver of your own. */
+    Custom(ConflictResolver),
+}
+
+impl fmt::Debug for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LocalWins => f.write_str("LocalWins"),
+            Self::RemoteWins => f.write_str("RemoteWins"),
+            Self::DeleteWins => f.write_str("DeleteWins"),
+            Self::LastWriteWins(property) => {
+                f.debug_tuple("LastWriteWins").field(property).finish()
+            }
+            Self::Merge(merge) => f.debug_tuple("Merge").field(merge).finish(),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl ConflictStrategy {
+    /** Resolves a conflict, returning the winning revision, or `None` to delete the document. */
+    pub fn resolve(
+        &self,
+        document_id: &str,
+        local: Option<Document>,
+        remote: Option<Document>,
+    ) -> Option<Document> {
+        match self {
+            Self::LocalWins => local,
+            Self::RemoteWins => remote,
+            Self::DeleteWins => local.and(remote),
+            Self::LastWriteWins(property) => {
+                let timestamp = |doc: &Option<Document>| {
+                    doc.as_ref()
+                        .and_then(|doc| doc.properties().get(property).as_timestamp())
+                        .map(|timestamp| timestamp.0)
+                };
+                if timestamp(&local) > timestamp(&remote) {
+                    local
+                } else {
+                    remote
+                }
+            }
+            Self::Merge(merge) => match (&local, &remote) {
+                (Some(local), Some(remote)) => Some(merge.merge(document_id, local, remote)),
+                _ => remote,
+            },
+            Self::Custom(resolver) => resolver(document_id, local, remote),
+        }
+    }
+
+    /** Returns the strategy as a resolver for `ReplicationConfigurationContext`. */
+    pub fn into_resolver(self) -> ConflictResolver {
+        Box::new(move |document_id, local, remote| self.resolve(document_id, local, remote))
+    }
+}
+
+//////// POLICY:
+
+/** A composition of conflict strategies: a default strategy, strategies for some documents,
+and optionally deletions winning over everything else.
+
+```no_run
+# use couchbase_lite::conflict::{ConflictPolicy, ConflictStrategy, FieldMerge, MergeRule};
+let resolver = ConflictPolicy::new(ConflictStrategy::LastWriteWins("updated_at".into()))
+    .delete_wins()
+    .for_documents(
+        |id| id.starts_with("cart:"),
+        ConflictStrategy::Merge(FieldMerge::new().rule("items", MergeRule::Union)),
+    )
+    .into_resolver();
+``` */
+pub struct ConflictPolicy {
+    delete_wins: bool,
+    overrides: Vec<(DocumentPredicate, ConflictStrategy)>,
+    strategy: ConflictStrategy,
+}
+
+type DocumentPredicate = Box<dyn Fn(&str) -> bool>;
+
+impl fmt::Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConflictPolicy")
+            .field("delete_wins", &self.delete_wins)
+            .field(
+                "overrides",
+                &self
+                    .overrides
+                    .iter()
+                    .map(|(_, strategy)| strategy)
+                    .collect::<Vec<_>>(),
+            )
+            .field("strategy", &self.strategy)
+            .finish()
+    }
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        Self::new(ConflictStrategy::RemoteWins)
+    }
+}
+
+impl ConflictPolicy {
+    /** Creates a policy resolving every conflict with `strategy`. */
+    pub fn new(strategy: ConflictStrategy) -> Self {
+        Self {
+            delete_wins: false,
+            overrides: vec![],
+            strategy,
+        }
+    }
+
+    /** Makes a deletion win over a modification, whatever the strategy of the document. */
+    #[must_use]
+    pub const fn delete_wins(mut self) -> Self {
+        self.delete_wins = true;
+        self
+    }
+
+    /** Resolves the conflicts of the documents whose ID matches `predicate` with `strategy`.
+    The first matching override applies. */
+    #[must_use]
+    pub fn for_documents<F>(mut self, predicate: F, strategy: ConflictStrategy) -> Self
+    where
+        F: Fn(&str) -> bool + 'static,
+    {
+        self.overrides.push((Box::new(predicate), strategy));
+        self
+    }
+
+    /** Resolves a conflict, returning the winning revision, or `None` to delete the document. */
+    pub fn resolve(
+        &self,
+        document_id: &str,
+        local: Option<Document>,
+        remote: Option<Document>,
+    ) -> Option<Document> {
+        if self.delete_wins && (local.is_none() || remote.is_none()) {
+            return None;
+        }
+        self.overrides
+            .iter()
+            .find(|(predicate, _)| predicate(document_id))
+            .map_or(&self.strategy, |(_, strategy)| strategy)
+            .resolve(document_id, local, remote)
+    }
+
+    /** Returns the policy as a resolver for `ReplicationConfigurationContext`. */
+    pub fn into_resolver(self) -> ConflictResolver {
+        Box::new(move |document_id, local, remote| self.resolve(document_id, local, remote))
+    }
+}
+
+//////// FIELD MERGE:
+
+/** How the values of a property are merged. Numeric rules apply to numbers, and `Union` to
+arrays; for values of other types, the remote value wins. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeRule {
+    /** The local value wins. */
+    Local,
+    /** The remote value wins. */
+    Remote,
+    /** A counter: the increments made on both sides since the common ancestor are added up.
+    Without an ancestor, the greater value wins. */
+    Sum,
+    /** The greater number wins. */
+    Max,
+    /** The smaller number wins. */
+    Min,
+    /** A set: the items added on either side are kept, and the items of the common ancestor
+    removed on either side are removed. */
+    Union,
+}
+
+/** A field-level merge of the properties of two conflicting revisions.
+
+Properties changed on one side only keep that change, dictionaries are merged recursively, and
+properties changed on both sides are merged with the rule of their path (like `stats.views`),
+or else the fallback rule (`MergeRule::Remote` by default).
+
+Couchbase Lite doesn't give conflict resolvers the common ancestor of the revisions, which
+tells which side changed a property: to merge three-way, provide it with `ancestor`, for
+instance from a copy of the last synced revision kept by the app. Without an ancestor, every
+property that differs is considered changed on both sides. */
+pub struct FieldMerge {
+    rules: HashMap<String, MergeRule>,
+    fallback: MergeRule,
+    ancestor: Option<AncestorFunction>,
+}
+
+type AncestorFunction = Box<dyn Fn(&str) -> Option<Document>>;
+
+impl fmt::Debug for FieldMerge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FieldMerge")
+            .field("rules", &self.rules)
+            .field("fallback", &self.fallback)
+            .field("ancestor", &self.ancestor.is_some())
+            .finish()
+    }
+}
+
+impl Default for FieldMerge {
+    fn default() -> Self {
+        Self {
+            rules: HashMap::new(),
+            fallback: MergeRule::Remote,
+            ancestor: None,
+        }
+    }
+}
+
+impl FieldMerge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Merges the property at `path`, with components separated by dots, with `rule`. */
+    #[must_use]
+    pub fn rule(mut self, path: &str, rule: MergeRule) -> Self {
+        self.rules.insert(path.to_string(), rule);
+        self
+    }
+
+    /** Sets the rule of the properties changed on both sides that have no rule of their own. */
+    #[must_use]
+    pub const fn fallback(mut self, rule: MergeRule) -> Self {
+        self.fallback = rule;
+        self
+    }
+
+    /** Sets the function returning the common ancestor of the conflicting revisions of a
+    document, given its ID. */
+    #[must_use]
+    pub fn ancestor<F>(mut self, ancestor: F) -> Self
+    where
+        F: Fn(&str) -> Option<Document> + 'static,
+    {
+        self.ancestor = Some(Box::new(ancestor));
+        self
+    }
+
+    /** Returns a new revision of the document with the merged properties. */
+    pub fn merge(&self, document_id: &str, local: &Document, remote: &Document) -> Document {
+        let ancestor = self
+            .ancestor
+            .as_ref()
+            .and_then(|ancestor| ancestor(document_id));
+        let base = ancestor.as_ref().map(Document::properties);
+        let merged = self.merge_dicts("", base.as_ref(), &local.properties(), &remote.properties());
+
+        let mut doc = Document::new_with_id(document_id);
+        doc.set_properties(&merged);
+        doc
+    }
+
+    fn merge_dicts(
+        &self,
+        path: &str,
+        base: Option<&Dict>,
+        local: &Dict,
+        remote: &Dict,
+    ) -> MutableDict {
+        let mut keys: Vec<String> = local.iter().map(|(key, _)| key).collect();
+        for (key, _) in remote.iter() {
+            if local.get(&key).get_type() == ValueType::Undefined {
+                keys.push(key);
+            }
+        }
+
+        let mut merged = MutableDict::new();
+        for key in keys {
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            let base = base.map(|base| base.get(&key));
+            let (local, remote) = (local.get(&key), remote.get(&key));
+            if !self.merge_values(&path, base.as_ref(), &local, &remote, merged.at(&key)) {
+                // The slot was left unset, but setting it added the key
+                merged.remove(&key);
+            }
+        }
+        merged
+    }
+
+    /** Stores the merged value into `slot`, returning false if the property is removed. */
+    fn merge_values(
+        &self,
+        path: &str,
+        base: Option<&Value>,
+        local: &Value,
+        remote: &Value,
+        slot: Slot,
+    ) -> bool {
+        let rule = self.rules.get(path).copied();
+        if rule.is_none() {
+            if local == remote {
+                return put_defined(slot, local);
+            }
+            if let Some(base) = base {
+                if local == base {
+                    return put_defined(slot, remote);
+                }
+                if remote == base {
+                    return put_defined(slot, local);
+                }
+            }
+            if local.get_type() == ValueType::Dict && remote.get_type() == ValueType::Dict {
+                let base = base
+                    .filter(|base| base.get_type() == ValueType::Dict)
+                    .map(Value::as_dict);
+                let merged =
+                    self.merge_dicts(path, base.as_ref(), &local.as_dict(), &remote.as_dict());
+                slot.put_value(&merged);
+                return true;
+            }
+        }
+
+        match rule.unwrap_or(self.fallback) {
+            MergeRule::Local => put_defined(slot, local),
+            MergeRule::Remote => put_defined(slot, remote),
+            MergeRule::Sum | MergeRule::Max | MergeRule::Min
+                if !(local.is_number() && remote.is_number()) =>
+            {
+                put_defined(slot, remote)
+            }
+            MergeRule::Sum => match base.filter(|base| base.is_number()) {
+                Some(base) if local.is_integer() && remote.is_integer() && base.is_integer() => {
+                    slot.put_i64(local.as_i64_or_0() + remote.as_i64_or_0() - base.as_i64_or_0());
+                    true
+                }
+                Some(base) => {
+                    slot.put_f64(local.as_f64_or_0() + remote.as_f64_or_0() - base.as_f64_or_0());
+                    true
+                }
+                None => put_extreme(slot, local, remote, true),
+            },
+            MergeRule::Max => put_extreme(slot, local, remote, true),
+            MergeRule::Min => put_extreme(slot, local, remote, false),
+            MergeRule::Union => {
+                if local.get_type() == ValueType::Array && remote.get_type() == ValueType::Array {
+                    slot.put_value(&union(base, local, remote));
+                    true
+                } else {
+                    put_defined(slot, remote)
+                }
+            }
+        }
+    }
+}
+
+/** Stores `value` into `slot`, unless it's undefined: a property missing on that side. Returns
+whether it was stored. */
+fn put_defined(slot: Slot, value: &Value) -> bool {
+    let defined = value.get_type() != ValueType::Undefined;
+    if defined {
+        slot.put_value(value);
+    }
+    defined
+}
+
+fn put_extreme(slot: Slot, local: &Value, remote: &Value, greatest: bool) -> bool {
+    let local_is_greater = local.as_f64_or_0() > remote.as_f64_or_0();
+    put_defined(
+        slot,
+        if local_is_greater == greatest {
+            local
+        } else {
+            remote
+        },
+    )
+}
+
+fn union(base: Option<&Value>, local: &Value, remote: &Value) -> MutableArray {
+    let contains = |array: &Value, item: &Value| array.as_array().iter().any(|i| &i == item);
+    let removed = |item: &Value| {
+        base.is_some_and(|base| {
+            base.get_type() == ValueType::Array
+                && contains(base, item)
+                && !(contains(local, item) && contains(remote, item))
+        })
+    };
+
+    let mut items: Vec<Value> = local.as_array().iter().collect();
+    items.extend(remote.as_array().iter());
+
+    let mut merged = MutableArray::new();
+    let mut added: Vec<Value> = vec![];
+    for item in items {
+        if !removed(&item) && !added.contains(&item) {
+            merged.append().put_value(&item);
+            added.push(item);
+        }
+    }
+    merged
+}
@@ -0,0 +1,536 @@
+// Couchbase Lite field-level encryption with AES-256-GCM
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    base64, encryptable::Encryptable, Array, CouchbaseLiteError, Database, Dict, Document, Error,
+    Fleece, FleeceReference, EncryptionError, EncryptionKey, MutableArray, MutableDict,
+    PropertyDecryptor, PropertyEncryptor, Result, Slot, Value, ValueType, warn,
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/** The algorithm name recorded with values encrypted by this module, as in JSON Web Encryption. */
+pub const ALGORITHM: &str = "A256GCM";
+
+/** The `@type` of the dictionary standing for a value encrypted at rest. */
+const LOCAL_ENCRYPTION_TYPE: &str = "encrypted";
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+//////// KEY PROVIDER:
+
+/** Looks up the AES-256 keys encrypting properties, by key ID (`kid`).
+
+Keys are rotated by changing the current key ID: new values are encrypted with the current key,
+while values encrypted with older keys are decrypted with the key recorded along with them, as
+long as the provider still knows it.
+
+Return `EncryptionError::Temporary` from these methods when the keys can't be read right now,
+for instance from a locked key store: the replicator will retry the document later. */
+pub trait KeyProvider: Send + Sync {
+    /** Returns the ID of the key to encrypt new values with. */
+    fn current_key_id(&self) -> std::result::Result<String, EncryptionError>;
+
+    /** Returns the key with the ID `kid`, or `None` if there is no such key. */
+    fn key(&self, kid: &str) -> std::result::Result<Option<EncryptionKey>, EncryptionError>;
+}
+
+/** A `KeyProvider` holding its keys in memory. */
+#[derive(Debug, Default)]
+pub struct KeyRing {
+    keys: RwLock<HashMap<String, EncryptionKey>>,
+    current: RwLock<Option<String>>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Creates a key ring with a single key, which is the current one. */
+    pub fn with_key(kid: &str, key: EncryptionKey) -> Self {
+        let ring = Self::new();
+        ring.rotate(kid, key);
+        ring
+    }
+
+    /** Adds a key, for decrypting values encrypted with it. */
+    pub fn add_key(&self, kid: &str, key: EncryptionKey) {
+        self.keys.write().unwrap().insert(kid.to_string(), key);
+    }
+
+    /** Removes a key: the values still encrypted with it can't be decrypted anymore. */
+    pub fn remove_key(&self, kid: &str) {
+        self.keys.write().unwrap().remove(kid);
+        let mut current = self.current.write().unwrap();
+        if current.as_deref() == Some(kid) {
+            *current = None;
+        }
+    }
+
+    /** Adds a key and makes it the current one, keeping the previous keys for decryption. */
+    pub fn rotate(&self, kid: &str, key: EncryptionKey) {
+        self.add_key(kid, key);
+        *self.current.write().unwrap() = Some(kid.to_string());
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key_id(&self) -> std::result::Result<String, EncryptionError> {
+        self.current
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(EncryptionError::Temporary)
+    }
+
+    fn key(&self, kid: &str) -> std::result::Result<Option<EncryptionKey>, EncryptionError> {
+        Ok(self.keys.read().unwrap().get(kid).cloned())
+    }
+}
+
+//////// REPLICATION CALLBACKS:
+
+/** Returns a property encryptor for `ReplicationConfigurationContext`, encrypting with the
+current key of `provider` and recording its ID and `ALGORITHM` with the value.
+
+A key that can't be found fails the document with `EncryptionError::Temporary`, since it may
+be provisioned later; an encryption failure fails it with `EncryptionError::Permanent`. */
+pub fn encryptor(provider: Arc<dyn KeyProvider>) -> PropertyEncryptor {
+    Box::new(move |context, input| {
+        let kid = provider.current_key_id()?;
+        let key = provider.key(&kid)?.ok_or(EncryptionError::Temporary)?;
+        let key_path = context.key_path.as_deref().unwrap_or_default();
+        let sealed = seal(&key, &associated_data(key_path), input)?;
+        context.algorithm = Some(ALGORITHM.to_string());
+        context.kid = Some(kid);
+        Ok(sealed)
+    })
+}
+
+/** Returns a property decryptor for `ReplicationConfigurationContext`, decrypting values
+encrypted by `encryptor` with the key of `provider` they were encrypted with.
+
+As with `encryptor`, a key that can't be found fails the document with
+`EncryptionError::Temporary`. Values that can't ever be decrypted fail it with
+`EncryptionError::Permanent`: values of another algorithm or without key ID, and values that
+were corrupted or tampered with. */
+pub fn decryptor(provider: Arc<dyn KeyProvider>) -> PropertyDecryptor {
+    Box::new(move |context, input| {
+        if context.algorithm.as_deref() != Some(ALGORITHM) {
+            return Err(EncryptionError::Permanent);
+        }
+        let kid = context.kid.as_deref().ok_or(EncryptionError::Permanent)?;
+        let key = provider.key(kid)?.ok_or(EncryptionError::Temporary)?;
+        let key_path = context.key_path.as_deref().unwrap_or_default();
+        open(&key, &associated_data(key_path), input)
+    })
+}
+
+/** Binds the encrypted value to its property, so that it can't be moved to another one. */
+fn associated_data(key_path: &str) -> Vec<u8> {
+    key_path.as_bytes().to_vec()
+}
+
+//////// LOCAL ENCRYPTION:
+
+/** The key provider a `Database` instance encrypts encryptable values at rest with, if any. */
+#[derive(Clone, Default)]
+pub(crate) struct LocalEncryption {
+    provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl LocalEncryption {
+    pub(crate) const fn none() -> Self {
+        Self { provider: None }
+    }
+}
+
+impl fmt::Debug for LocalEncryption {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("LocalEncryption")
+            .field("enabled", &self.provider.is_some())
+            .finish()
+    }
+}
+
+impl Database {
+    /** Turns on, or off with `None`, the encryption at rest of encryptable values.
+
+    While it's on, the encryptables of the documents saved through this instance are stored
+    encrypted with the current key of `provider`, and those of the documents read with
+    `get_document` are decrypted, so that `get_encryptable_value` returns them as they were set
+    while sensitive properties stay protected even if the database file is extracted without
+    its key. A value that can't be decrypted is logged and left as stored, and
+    `try_get_encryptable_value` tells why; a document whose values can't be encrypted isn't
+    saved. `MutableDict::try_set_encryptable_value` and `Slot::try_put_encrypt` encrypt a value
+    as soon as it's set instead.
+
+    The setting belongs to this instance and to the clones made from it afterwards: other
+    connections to the same database, the `Database` given to listeners, and the documents
+    returned by queries and replication callbacks don't have it.
+
+    Values encrypted at rest are still encryptables: the replicator encrypts them again with its
+    own encryptor when pushing them, and devices pulling them need the same keys to decrypt
+    them. */
+    pub fn set_local_key_provider(&mut self, provider: Option<Arc<dyn KeyProvider>>) {
+        self.local_encryption = LocalEncryption { provider };
+    }
+
+    /** Returns the encryptable to store at `key_path`, the dot-separated path of the property
+    in its document: encrypted at rest with the current key, or unchanged if local encryption
+    is off or the value is already encrypted. The value is bound to its key path, so it can't be
+    moved to another property. */
+    pub fn encrypt_local(
+        &self,
+        key_path: &str,
+        encryptable: &Encryptable,
+    ) -> std::result::Result<Encryptable, EncryptionError> {
+        let provider = match &self.local_encryption.provider {
+            Some(provider) if !is_locally_encrypted(encryptable) => provider,
+            _ => return Ok(encryptable.clone()),
+        };
+        let kid = provider.current_key_id()?;
+        let key = provider.key(&kid)?.ok_or(EncryptionError::Temporary)?;
+        let mut wrapper = MutableDict::new();
+        wrapper.at("value").put_value(&encryptable.get_value());
+        let sealed = seal(
+            &key,
+            &associated_data(key_path),
+            wrapper.to_json().as_bytes(),
+        )?;
+
+        let mut encrypted = MutableDict::new();
+        encrypted.at("@type").put_string(LOCAL_ENCRYPTION_TYPE);
+        encrypted.at("alg").put_string(ALGORITHM);
+        encrypted.at("kid").put_string(&kid);
+        encrypted.at("data").put_string(base64::encode(&sealed));
+        Ok(Encryptable::create_with_dict(encrypted.as_dict()))
+    }
+
+    /** Returns the encryptable stored at `key_path` with its value decrypted, if it's encrypted
+    at rest, or else the encryptable itself.
+
+    A key that can't be found, or local encryption being off, fails with
+    `EncryptionError::Temporary`. Values that can't ever be decrypted fail with
+    `EncryptionError::Permanent`: values of another algorithm, values read at another key path
+    than the one they were stored at, and values that were corrupted or tampered with. */
+    pub fn decrypt_local(
+        &self,
+        key_path: &str,
+        encryptable: &Encryptable,
+    ) -> std::result::Result<Encryptable, EncryptionError> {
+        if !is_locally_encrypted(encryptable) {
+            return Ok(encryptable.clone());
+        }
+        let value = encryptable.get_value();
+        let encrypted = value.as_dict();
+        if encrypted.get("alg").as_string() != Some(ALGORITHM) {
+            return Err(EncryptionError::Permanent);
+        }
+        let kid = encrypted.get("kid");
+        let kid = kid.as_string().ok_or(EncryptionError::Permanent)?;
+        let data = encrypted
+            .get("data")
+            .as_string()
+            .and_then(base64::decode)
+            .ok_or(EncryptionError::Permanent)?;
+
+        let provider = self
+            .local_encryption
+            .provider
+            .as_ref()
+            .ok_or(EncryptionError::Temporary)?;
+        let key = provider.key(kid)?.ok_or(EncryptionError::Temporary)?;
+        let json = open(&key, &associated_data(key_path), &data)?;
+
+        // The value was encrypted wrapped in a dictionary, as JSON can't have a scalar at its root
+        let json = String::from_utf8(json).map_err(|_| EncryptionError::Permanent)?;
+        let doc = Fleece::parse_json(&json).map_err(|_| EncryptionError::Permanent)?;
+        Ok(Encryptable::create_with_value(doc.as_dict().get("value")))
+    }
+}
+
+//////// DOCUMENT ENCRYPTION:
+
+/* A changed value of a document's properties: an encryptable, or a copy of a collection with
+changed values. */
+enum Replacement {
+    Encryptable(Encryptable),
+    Dict(MutableDict),
+    Array(MutableArray),
+}
+
+impl Replacement {
+    fn put(&self, slot: Slot) {
+        match self {
+            Self::Encryptable(encryptable) => slot.put_encrypt(encryptable),
+            Self::Dict(dict) => slot.put_value(dict),
+            Self::Array(array) => slot.put_value(array),
+        }
+    }
+}
+
+type EncryptableMapping<'m> =
+    dyn FnMut(&str, &Encryptable) -> std::result::Result<Option<Encryptable>, EncryptionError> + 'm;
+
+/** Returns the replacement of a value whose encryptables, at any depth, are changed by `map`,
+given their key paths, or `None` if none is changed. */
+fn map_encryptables(
+    value: Value,
+    key_path: &str,
+    map: &mut EncryptableMapping,
+) -> std::result::Result<Option<Replacement>, EncryptionError> {
+    match value.get_type() {
+        ValueType::Dict if value.as_dict().is_encryptable() => {
+            Ok(map(key_path, &value.get_encryptable_value())?.map(Replacement::Encryptable))
+        }
+        ValueType::Dict => {
+            Ok(map_dict_encryptables(value.as_dict(), key_path, map)?.map(Replacement::Dict))
+        }
+        ValueType::Array => {
+            Ok(map_array_encryptables(value.as_array(), key_path, map)?.map(Replacement::Array))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn map_dict_encryptables(
+    dict: Dict,
+    key_path: &str,
+    map: &mut EncryptableMapping,
+) -> std::result::Result<Option<MutableDict>, EncryptionError> {
+    let mut copy = None;
+    for (key, value) in dict.iter() {
+        let path = if key_path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", key_path, key)
+        };
+        if let Some(replacement) = map_encryptables(value, &path, map)? {
+            let copy = copy.get_or_insert_with(|| MutableDict::from_dict(&dict));
+            replacement.put(copy.at(&key));
+        }
+    }
+    Ok(copy)
+}
+
+fn map_array_encryptables(
+    array: Array,
+    key_path: &str,
+    map: &mut EncryptableMapping,
+) -> std::result::Result<Option<MutableArray>, EncryptionError> {
+    let mut copy: Option<MutableArray> = None;
+    for (index, value) in array.iter().enumerate() {
+        let path = format!("{}[{}]", key_path, index);
+        if let Some(replacement) = map_encryptables(value, &path, map)? {
+            let copy = copy.get_or_insert_with(|| MutableArray::from_array(&array));
+            if let Some(slot) = copy.at(index as u32) {
+                replacement.put(slot);
+            }
+        }
+    }
+    Ok(copy)
+}
+
+impl Database {
+    /* Decrypts the encryptables of a document read from this database that are encrypted at
+    rest, in memory. Those that can't be decrypted are logged and left as stored. */
+    pub(crate) fn decrypt_document(&self, document: &mut Document) {
+        if self.local_encryption.provider.is_none() {
+            return;
+        }
+        let properties = document.properties();
+        let decrypted = map_dict_encryptables(properties, "", &mut |key_path, encryptable| {
+            if !is_locally_encrypted(encryptable) {
+                return Ok(None);
+            }
+            match self.decrypt_local(key_path, encryptable) {
+                Ok(decrypted) => Ok(Some(decrypted)),
+                Err(err) => {
+                    warn!(
+                        "Decrypting property {:?} of document {:?} failed: {:?}",
+                        key_path,
+                        document.id(),
+                        err
+                    );
+                    Ok(None)
+                }
+            }
+        });
+        if let Ok(Some(decrypted)) = decrypted {
+            document.set_properties(&decrypted);
+        }
+    }
+
+    /* Encrypts the encryptables of a document about to be saved to this database, returning its
+    properties as they were set, to put back once it's saved, if any had to be. */
+    pub(crate) fn encrypt_document(&self, document: &mut Document) -> Result<Option<MutableDict>> {
+        if self.local_encryption.provider.is_none() {
+            return Ok(None);
+        }
+        let encrypted =
+            map_dict_encryptables(document.properties(), "", &mut |key_path, encryptable| {
+                if is_locally_encrypted(encryptable) {
+                    return Ok(None);
+                }
+                self.encrypt_local(key_path, encryptable).map(Some)
+            })
+            .map_err(crypto_error)?;
+        Ok(encrypted.map(|encrypted| {
+            let properties = document.mutable_properties();
+            document.set_properties(&encrypted);
+            properties
+        }))
+    }
+}
+
+/** Returns true if the value of the encryptable is encrypted at rest. */
+pub fn is_locally_encrypted(encryptable: &Encryptable) -> bool {
+    let value = encryptable.get_value();
+    value.as_dict().get("@type").as_string() == Some(LOCAL_ENCRYPTION_TYPE)
+}
+
+/* The fleece accessors report failures as Couchbase Lite errors; `Database::encrypt_local` and
+`Database::decrypt_local` tell temporary failures from permanent ones. */
+fn crypto_error(_: EncryptionError) -> Error {
+    Error::cbl_error(CouchbaseLiteError::Crypto)
+}
+
+impl MutableDict {
+    /** Stores an encryptable value, encrypted at rest if `database` has a local key provider
+    (see `Database::set_local_key_provider`). The value is bound to `key` as its key path, so
+    `dict` should be the properties of a document; nested properties are set with
+    `Slot::try_put_encrypt` and their full key path. If the value can't be encrypted, nothing is
+    stored and a `CouchbaseLiteError::Crypto` error is returned. */
+    pub fn try_set_encryptable_value(
+        dict: &Self,
+        key: &str,
+        encryptable: &Encryptable,
+        database: &Database,
+    ) -> Result<()> {
+        let encryptable = database
+            .encrypt_local(key, encryptable)
+            .map_err(crypto_error)?;
+        Self::set_encryptable_value(dict, key, &encryptable);
+        Ok(())
+    }
+}
+
+impl<'s> Slot<'s> {
+    /** Stores an encryptable value, encrypted at rest if `database` has a local key provider,
+    bound to `key_path`, the dot-separated path of the property in its document. If the value
+    can't be encrypted, nothing is stored and a `CouchbaseLiteError::Crypto` error is
+    returned. */
+    pub fn try_put_encrypt(
+        self,
+        value: &Encryptable,
+        database: &Database,
+        key_path: &str,
+    ) -> Result<()> {
+        let value = database
+            .encrypt_local(key_path, value)
+            .map_err(crypto_error)?;
+        self.put_encrypt(&value);
+        Ok(())
+    }
+}
+
+impl Value {
+    /** Returns the encryptable value, decrypted if it was encrypted at rest at `key_path`. A
+    value that can't be decrypted fails with a `CouchbaseLiteError::Crypto` error. */
+    pub fn try_get_encryptable_value(
+        &self,
+        database: &Database,
+        key_path: &str,
+    ) -> Result<Encryptable> {
+        database
+            .decrypt_local(key_path, &self.get_encryptable_value())
+            .map_err(crypto_error)
+    }
+}
+
+impl Dict {
+    /** Returns the encryptable value, decrypted if it was encrypted at rest at `key_path`. A
+    value that can't be decrypted fails with a `CouchbaseLiteError::Crypto` error. */
+    pub fn try_get_encryptable_value(
+        &self,
+        database: &Database,
+        key_path: &str,
+    ) -> Result<Encryptable> {
+        database
+            .decrypt_local(key_path, &self.get_encryptable_value())
+            .map_err(crypto_error)
+    }
+}
+
+//////// AES-GCM:
+
+/** Encrypts `plaintext` with a random nonce, returning the nonce followed by the ciphertext and
+its authentication tag. */
+pub(crate) fn seal(
+    key: &EncryptionKey,
+    associated_data: &[u8],
+    plaintext: &[u8],
+) -> std::result::Result<Vec<u8>, EncryptionError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.bytes()));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| EncryptionError::Permanent)?;
+
+    let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/** Decrypts the output of `seal`, checking its authentication tag. */
+pub(crate) fn open(
+    key: &EncryptionKey,
+    associated_data: &[u8],
+    sealed: &[u8],
+) -> std::result::Result<Vec<u8>, EncryptionError> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(EncryptionError::Permanent);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.bytes()));
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| EncryptionError::Permanent)
+}
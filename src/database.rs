@@ -23,24 +23,40 @@ use crate::{
         CBLDatabase, CBLDatabaseConfiguration, CBLDatabaseConfiguration_Default,
         CBLDatabase_AddChangeListener, CBLDatabase_BeginTransaction,
         CBLDatabase_BufferNotifications, CBLDatabase_ChangeEncryptionKey, CBLDatabase_Close,
-        CBLDatabase_Count, CBLDatabase_Delete, CBLDatabase_EndTransaction, CBLDatabase_Name,
-        CBLDatabase_Open, CBLDatabase_Path, CBLDatabase_PerformMaintenance,
+        CBLDatabase_Config, CBLDatabase_Count, CBLDatabase_Delete, CBLDatabase_EndTransaction,
+        CBLDatabase_Name, CBLDatabase_Open, CBLDatabase_Path, CBLDatabase_PerformMaintenance,
         CBLDatabase_SendNotifications, CBLEncryptionKey, CBLError, CBL_DatabaseExists,
         CBL_DeleteDatabase, CBLEncryptionKey_FromPassword, FLString, kCBLMaintenanceTypeCompact,
-        kCBLEncryptionNone, kCBLMaintenanceTypeFullOptimize, kCBLMaintenanceTypeIntegrityCheck,
-        kCBLMaintenanceTypeOptimize, kCBLMaintenanceTypeReindex, CBL_CopyDatabase,
+        kCBLEncryptionNone, kCBLEncryptionAES256, kCBLMaintenanceTypeFullOptimize,
+        kCBLMaintenanceTypeIntegrityCheck, kCBLMaintenanceTypeOptimize, kCBLMaintenanceTypeReindex,
+        CBL_CopyDatabase,
     },
-    Listener, check_error, Error, CouchbaseLiteError,
+    Listener, check_error, Error, CouchbaseLiteError, Query, QueryLanguage,
+    query::QueryCache,
 };
 use std::path::{Path, PathBuf};
 use std::ptr;
-
-#[derive(Debug, Clone)]
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fmt;
+use std::fs;
+use std::sync::atomic;
+
+/** An encryption key for a database. */
+#[derive(Clone)]
 pub struct EncryptionKey {
     cbl_ref: Box<CBLEncryptionKey>,
 }
 
 impl EncryptionKey {
+    /** The salt and number of rounds Couchbase Lite used before 3.0 to derive keys from
+    passwords, with PBKDF2-HMAC-SHA1. */
+    #[cfg(feature = "legacy-key")]
+    const LEGACY_PASSWORD_SALT: &'static [u8] = b"Salty McNaCl";
+    #[cfg(feature = "legacy-key")]
+    const LEGACY_PASSWORD_ROUNDS: u32 = 64000;
+
+    /** Derives an AES-256 key from a password, with PBKDF2-HMAC-SHA256. */
     pub fn new_from_password(password: &str) -> Option<Self> {
         unsafe {
             let key = CBLEncryptionKey {
@@ -61,6 +77,56 @@ impl EncryptionKey {
             }
         }
     }
+
+    /** Derives an AES-256 key from a password the way Couchbase Lite did before 3.0, with
+    PBKDF2-HMAC-SHA1. Use this to open databases that were encrypted with a password by older
+    versions. Requires the `legacy-key` feature. */
+    #[cfg(feature = "legacy-key")]
+    pub fn new_from_password_sha1(password: &str) -> Self {
+        let mut encryption_key = Self::from_bytes([0; 32]);
+        pbkdf2::pbkdf2_hmac::<sha1::Sha1>(
+            password.as_bytes(),
+            Self::LEGACY_PASSWORD_SALT,
+            Self::LEGACY_PASSWORD_ROUNDS,
+            &mut encryption_key.cbl_ref.bytes,
+        );
+        encryption_key
+    }
+
+    /** Creates an AES-256 key from raw key bytes. */
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self {
+            cbl_ref: Box::new(CBLEncryptionKey {
+                algorithm: kCBLEncryptionAES256,
+                bytes,
+            }),
+        }
+    }
+
+    /** Returns the raw key bytes. */
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.cbl_ref.bytes
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("algorithm", &self.cbl_ref.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        // Clears this instance's buffer only: clones, and copies made by callers or passed to
+        // Couchbase Lite, aren't covered. Volatile writes, so the compiler can't elide zeroing
+        // memory that is about to be freed.
+        for byte in &mut self.cbl_ref.bytes {
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    }
 }
 
 impl CblRef for EncryptionKey {
@@ -77,6 +143,54 @@ pub struct DatabaseConfiguration<'a> {
     pub encryption_key: Option<EncryptionKey>,
 }
 
+/** Owned database configuration options, built with chained setters:
+
+    DatabaseConfig::new().directory("/data/db").encryption_key(key)
+
+Options left unset take Couchbase Lite's defaults. */
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseConfig {
+    directory: Option<PathBuf>,
+    encryption_key: Option<EncryptionKey>,
+}
+
+impl DatabaseConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Sets the parent directory of the database. */
+    #[must_use]
+    pub fn directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.directory = Some(directory.into());
+        self
+    }
+
+    /** Sets the key the database is encrypted with. */
+    #[must_use]
+    pub fn encryption_key(mut self, encryption_key: EncryptionKey) -> Self {
+        self.encryption_key = Some(encryption_key);
+        self
+    }
+
+    pub fn get_directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    pub const fn get_encryption_key(&self) -> Option<&EncryptionKey> {
+        self.encryption_key.as_ref()
+    }
+}
+
+impl<'a> From<DatabaseConfiguration<'a>> for DatabaseConfig {
+    fn from(config: DatabaseConfiguration<'a>) -> Self {
+        Self {
+            directory: Some(config.directory.to_path_buf()),
+            encryption_key: config.encryption_key,
+        }
+    }
+}
+
 enum_from_primitive! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum MaintenanceType {
@@ -123,12 +237,49 @@ unsafe extern "C" fn c_database_buffer_notifications(
     callback(&database);
 }
 
+/** Size and content statistics of a database, as returned by `Database::stats`. Sizes are in
+bytes. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatabaseStats {
+    /** The number of live documents. */
+    pub document_count: u64,
+    /** The number of deleted documents (tombstones) that haven't been purged yet. */
+    pub deleted_count: u64,
+    /** The size of the SQLite database file. */
+    pub file_size: u64,
+    /** The size of the SQLite write-ahead log, which is checkpointed into the database file
+    by compaction. */
+    pub wal_size: u64,
+    /** The total size of the blobs in the attachments directory. */
+    pub attachments_size: u64,
+    /** The number of indexes. */
+    pub index_count: u64,
+}
+
+impl DatabaseStats {
+    /** The total disk usage of the database: file, write-ahead log and attachments. */
+    pub const fn total_size(&self) -> u64 {
+        self.file_size + self.wal_size + self.attachments_size
+    }
+}
+
 /** A connection to an open database. */
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct Database {
     cbl_ref: *mut CBLDatabase,
+    pub(crate) query_cache: Rc<RefCell<QueryCache>>,
+    #[cfg(feature = "crypto")]
+    pub(crate) local_encryption: crate::crypto::LocalEncryption,
+}
+
+impl PartialEq for Database {
+    fn eq(&self, other: &Self) -> bool {
+        self.cbl_ref == other.cbl_ref
+    }
 }
 
+impl Eq for Database {}
+
 impl CblRef for Database {
     type Output = *mut CBLDatabase;
     fn get_ref(&self) -> Self::Output {
@@ -139,13 +290,16 @@ impl CblRef for Database {
 impl Database {
     //////// CONSTRUCTORS:
     pub(crate) fn retain(cbl_ref: *mut CBLDatabase) -> Self {
-        Self {
-            cbl_ref: unsafe { retain(cbl_ref) },
-        }
+        Self::wrap(unsafe { retain(cbl_ref) })
     }
 
-    pub(crate) const fn wrap(cbl_ref: *mut CBLDatabase) -> Self {
-        Self { cbl_ref }
+    pub(crate) fn wrap(cbl_ref: *mut CBLDatabase) -> Self {
+        Self {
+            cbl_ref,
+            query_cache: Rc::new(RefCell::new(QueryCache::default())),
+            #[cfg(feature = "crypto")]
+            local_encryption: crate::crypto::LocalEncryption::none(),
+        }
     }
 
     /** Opens a database, or creates it if it doesn't exist yet, returning a new `Database`
@@ -167,6 +321,29 @@ impl Database {
         }
     }
 
+    /** Opens a database, or creates it if it doesn't exist yet, with an owned configuration.
+    See `open`. */
+    pub fn open_with_config(name: &str, config: &DatabaseConfig) -> Result<Self> {
+        unsafe {
+            let mut c_config: CBLDatabaseConfiguration = CBLDatabaseConfiguration_Default();
+            let directory = match &config.directory {
+                Some(directory) => Some(
+                    directory
+                        .to_str()
+                        .ok_or(Error::cbl_error(CouchbaseLiteError::InvalidParameter))?,
+                ),
+                None => None,
+            };
+            if let Some(directory) = directory {
+                c_config.directory = from_str(directory).get_ref();
+            }
+            if let Some(encryption_key) = &config.encryption_key {
+                c_config.encryptionKey = *encryption_key.get_ref();
+            }
+            Self::_open(name, &c_config)
+        }
+    }
+
     unsafe fn _open(name: &str, config_ptr: *const CBLDatabaseConfiguration) -> Result<Self> {
         let mut err = CBLError::default();
         let db_ref = CBLDatabase_Open(from_str(name).get_ref(), config_ptr, &mut err);
@@ -196,7 +373,7 @@ impl Database {
         config: Option<DatabaseConfiguration>,
     ) -> Result<()> {
         let config = config
-            .map(|cfg| {
+            .map(|cfg| -> Result<CBLDatabaseConfiguration> {
                 let mut c_config: CBLDatabaseConfiguration =
                     unsafe { CBLDatabaseConfiguration_Default() };
 
@@ -240,6 +417,18 @@ impl Database {
 
     /** Deletes a database file. If the database file is open, an error is returned. */
     pub fn delete_file<P: AsRef<Path>>(name: &str, in_directory: P) -> Result<bool> {
+        let deleted = Self::delete_database_file(name, in_directory.as_ref())?;
+        if deleted {
+            // The metadata only describes the deleted database
+            let _ = Self::delete_metadata(name, in_directory);
+        }
+        Ok(deleted)
+    }
+
+    pub(crate) fn delete_database_file<P: AsRef<Path>>(
+        name: &str,
+        in_directory: P,
+    ) -> Result<bool> {
         unsafe {
             let mut error = CBLError::default();
             if CBL_DeleteDatabase(
@@ -266,7 +455,14 @@ impl Database {
     /** Closes and deletes a database. If there are any other connections to the database,
     an error is returned. */
     pub fn delete(self) -> Result<()> {
-        unsafe { check_bool(|error| CBLDatabase_Delete(self.get_ref(), error)) }
+        let name = self.name().to_string();
+        let directory = self.path().parent().map(Path::to_path_buf);
+        unsafe { check_bool(|error| CBLDatabase_Delete(self.get_ref(), error))? };
+        // The metadata only describes the deleted database
+        if let Some(directory) = directory {
+            let _ = Self::delete_metadata(&name, directory);
+        }
+        Ok(())
     }
 
     /** Compacts a database file, freeing up unused disk space. */
@@ -302,13 +498,11 @@ impl Database {
         result
     }
 
-    /** Encrypts or decrypts a database, or changes its encryption key. */
-    pub fn change_encryption_key(&mut self, encryption_key: &EncryptionKey) -> Result<()> {
-        unsafe {
-            check_bool(|error| {
-                CBLDatabase_ChangeEncryptionKey(self.get_ref(), encryption_key.get_ref(), error)
-            })
-        }
+    /** Encrypts or decrypts a database, or changes its encryption key.
+    Passing `None` decrypts the database. */
+    pub fn change_encryption_key(&mut self, encryption_key: Option<&EncryptionKey>) -> Result<()> {
+        let key = encryption_key.map_or(ptr::null(), EncryptionKey::get_ref);
+        unsafe { check_bool(|error| CBLDatabase_ChangeEncryptionKey(self.get_ref(), key, error)) }
     }
 
     //////// ACCESSORS:
@@ -323,11 +517,50 @@ impl Database {
         unsafe { PathBuf::from(CBLDatabase_Path(self.get_ref()).to_string().unwrap()) }
     }
 
+    /** Returns the configuration the database was opened with, including the directory it was
+    actually opened in when none was given. */
+    pub fn config(&self) -> DatabaseConfig {
+        unsafe {
+            let c_config = CBLDatabase_Config(self.get_ref());
+            DatabaseConfig {
+                directory: c_config.directory.to_string().map(PathBuf::from),
+                encryption_key: if c_config.encryptionKey.algorithm == kCBLEncryptionNone {
+                    None
+                } else {
+                    Some(EncryptionKey {
+                        cbl_ref: Box::new(c_config.encryptionKey),
+                    })
+                },
+            }
+        }
+    }
+
     /** Returns the number of documents in the database. */
     pub fn count(&self) -> u64 {
         unsafe { CBLDatabase_Count(self.get_ref()) }
     }
 
+    /** Returns document counts and the disk usage of the database's files. */
+    pub fn stats(&self) -> Result<DatabaseStats> {
+        let path = self.path();
+        let deleted = Query::new(
+            self,
+            QueryLanguage::N1QL,
+            "SELECT COUNT(*) FROM _ WHERE meta().deleted",
+        )?
+        .execute()?
+        .next()
+        .map_or(0, |row| row.get(0).as_u64_or_0());
+        Ok(DatabaseStats {
+            document_count: self.count(),
+            deleted_count: deleted,
+            file_size: file_size(&path.join("db.sqlite3")),
+            wal_size: file_size(&path.join("db.sqlite3-wal")),
+            attachments_size: directory_size(&path.join("Attachments")),
+            index_count: self.get_index_names().count().into(),
+        })
+    }
+
     //////// NOTIFICATIONS:
 
     /** Registers a database change listener function. It will be called after one or more
@@ -391,6 +624,27 @@ impl Drop for Database {
 
 impl Clone for Database {
     fn clone(&self) -> Self {
-        Self::retain(self.get_ref())
+        Self {
+            cbl_ref: unsafe { retain(self.get_ref()) },
+            query_cache: self.query_cache.clone(),
+            #[cfg(feature = "crypto")]
+            local_encryption: self.local_encryption.clone(),
+        }
     }
 }
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+fn directory_size(path: &Path) -> u64 {
+    fs::read_dir(path).map_or(0, |entries| {
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+                _ => file_size(&entry.path()),
+            })
+            .sum()
+    })
+}
@@ -105,7 +105,11 @@ impl Database {
                     failure(error)
                 };
             }
-            Ok(Document::wrap(doc))
+            #[allow(unused_mut)]
+            let mut doc = Document::wrap(doc);
+            #[cfg(feature = "crypto")]
+            self.decrypt_document(&mut doc);
+            Ok(doc)
         }
     }
 
@@ -115,9 +119,9 @@ impl Database {
     `save_document_with_concurency_control` or
     `save_document_resolving` instead. */
     pub fn save_document(&mut self, doc: &mut Document) -> Result<()> {
-        unsafe {
-            check_bool(|error| CBLDatabase_SaveDocument(self.get_ref(), doc.get_ref(), error))
-        }
+        self.saving(doc, |db, doc| unsafe {
+            check_bool(|error| CBLDatabase_SaveDocument(db.get_ref(), doc.get_ref(), error))
+        })
     }
 
     /** Saves a new or modified document to the database.
@@ -131,16 +135,16 @@ impl Database {
         concurrency: ConcurrencyControl,
     ) -> Result<()> {
         let c_concurrency = concurrency as u8;
-        unsafe {
+        self.saving(doc, |db, doc| unsafe {
             check_bool(|error| {
                 CBLDatabase_SaveDocumentWithConcurrencyControl(
-                    self.get_ref(),
+                    db.get_ref(),
                     doc.get_ref(),
                     c_concurrency,
                     error,
                 )
             })
-        }
+        })
     }
 
     /** Saves a new or modified document to the database. This function is the same as
@@ -151,21 +155,37 @@ impl Database {
         doc: &mut Document,
         conflict_handler: ConflictHandler,
     ) -> Result<Document> {
-        unsafe {
+        self.saving(doc, |db, doc| unsafe {
             let callback = conflict_handler as *mut std::ffi::c_void;
-            match check_bool(|error| {
+            check_bool(|error| {
                 CBLDatabase_SaveDocumentWithConflictHandler(
-                    self.get_ref(),
+                    db.get_ref(),
                     doc.get_ref(),
                     Some(c_conflict_handler),
                     callback,
                     error,
                 )
-            }) {
-                Ok(_) => Ok(doc.clone()),
-                Err(err) => Err(err),
+            })
+        })?;
+        Ok(doc.clone())
+    }
+
+    /* Saves a document with `save`, storing its encryptables encrypted at rest, and putting
+    them back as they were set once it's done, if a local key provider is set. */
+    fn saving(
+        &self,
+        doc: &mut Document,
+        save: impl FnOnce(&Self, &mut Document) -> Result<()>,
+    ) -> Result<()> {
+        #[cfg(feature = "crypto")]
+        {
+            if let Some(properties) = self.encrypt_document(doc)? {
+                let result = save(self, doc);
+                doc.set_properties(&properties);
+                return result;
             }
         }
+        save(self, doc)
     }
 
     /** Deletes a document from the database. Deletions are replicated. */
@@ -32,10 +32,11 @@ use std::fmt;
 pub struct Error {
     pub code: ErrorCode,
     pub(crate) internal_info: Option<u32>,
+    pub(crate) query_position: Option<usize>,
 }
 
 /** The enum that stores the error domain and code for an Error. */
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum ErrorCode {
     CouchbaseLite(CouchbaseLiteError),
     POSIX(i32),
@@ -50,7 +51,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 enum_from_primitive! {
     /** Couchbase Lite error codes. */
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum CouchbaseLiteError {
         AssertionFailed = 1,    // Internal assertion failure
         Unimplemented,          // Oops, an unimplemented API call
@@ -89,7 +90,7 @@ enum_from_primitive! {
 
 enum_from_primitive! {
     /** Fleece error codes. */
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum FleeceError {
         MemoryError = 1,    // Out of memory, or allocation failed
         OutOfRange,         // Array index or iterator out of range
@@ -107,7 +108,7 @@ enum_from_primitive! {
 
 enum_from_primitive! {
     /** Network error codes defined by Couchbase Lite. */
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum NetworkError {
         DNSFailure = 1,            // DNS lookup failed
         UnknownHost,               // DNS server doesn't know the hostname
@@ -138,6 +139,7 @@ impl Error {
         Self {
             code: ErrorCode::new(err),
             internal_info: Some(err.internal_info),
+            query_position: None,
         }
     }
 
@@ -145,6 +147,17 @@ impl Error {
         Self {
             code: ErrorCode::CouchbaseLite(e),
             internal_info: None,
+            query_position: None,
+        }
+    }
+
+    /** Creates an error with a code, for callbacks (like migration steps) that fail with an
+    error of their own. */
+    pub const fn from_code(code: ErrorCode) -> Self {
+        Self {
+            code,
+            internal_info: None,
+            query_position: None,
         }
     }
 
@@ -152,6 +165,7 @@ impl Error {
         Self {
             code: ErrorCode::from_fleece(e as i32),
             internal_info: None,
+            query_position: None,
         }
     }
 
@@ -206,6 +220,19 @@ impl Error {
                 })
         }
     }
+
+    /** Returns the byte offset in the query string where the parser gave up, if this error was
+    returned by `Query::new` and Couchbase Lite could locate the failure. */
+    pub const fn query_position(&self) -> Option<usize> {
+        self.query_position
+    }
+
+    /** Returns the details of a query compilation failure, as for `query_position`, given the
+    query string that was passed to `Query::new`. */
+    pub fn query_parse_error(&self, query: &str) -> Option<QueryParseError> {
+        self.query_position
+            .map(|position| QueryParseError::new(self.message(), query, position))
+    }
 }
 
 impl std::error::Error for Error {}
@@ -222,6 +249,75 @@ impl fmt::Display for Error {
     }
 }
 
+//////// QUERY PARSE ERROR:
+
+/** Details of a query that failed to compile: the error message, and where in the query string
+the parser gave up. `position` is the (approximate) byte offset reported by Couchbase Lite;
+`line` and `column` are the same location, 1-based, with the column counted in characters. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryParseError {
+    pub message: String,
+    pub query: String,
+    pub position: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl QueryParseError {
+    pub(crate) fn new(message: String, query: &str, position: usize) -> Self {
+        let mut position = position.min(query.len());
+        while !query.is_char_boundary(position) {
+            position -= 1;
+        }
+        let before = &query[..position];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            message,
+            query: query.to_string(),
+            position,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /** Renders the query string with a caret (`^`) under the failing position, on the line
+    following the one containing the error:
+
+    ```text
+    SELECT name FROM _ WHERE
+                            ^
+    ```
+    */
+    pub fn render_caret(&self) -> String {
+        let mut rendered = String::new();
+        for (i, line) in self.query.split('\n').enumerate() {
+            rendered.push_str(line);
+            rendered.push('\n');
+            if i + 1 == self.line {
+                let indent: String = line
+                    .chars()
+                    .take(self.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                rendered.push_str(&indent);
+                rendered.push_str("^\n");
+            }
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        fmt.write_fmt(format_args!(
+            "{} (line {}, column {})",
+            self.message, self.line, self.column
+        ))
+    }
+}
+
+impl std::error::Error for QueryParseError {}
+
 impl ErrorCode {
     fn new(err: &CBLError) -> Self {
         match u32::from(err.domain) {
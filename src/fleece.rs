@@ -314,11 +314,11 @@ impl Value {
         }
     }
 
+    /** Returns the encryptable value. Values encrypted at rest are decrypted when the document is
+    read from a database with a local key provider; one that can't be is returned as stored, and
+    `try_get_encryptable_value` tells why. */
     pub fn get_encryptable_value(&self) -> Encryptable {
-        unsafe {
-            let encryptable = FLDict_GetEncryptableValue(FLValue_AsDict(self.get_ref()));
-            Encryptable::retain(encryptable as *mut CBLEncryptable)
-        }
+        self.as_dict().get_encryptable_value()
     }
 
     pub fn find_doc(&self) -> Option<Fleece> {
@@ -587,9 +587,17 @@ impl Dict {
         }
     }
 
+    /** Returns the encryptable value. Values encrypted at rest are decrypted when the document is
+    read from a database with a local key provider; one that can't be is returned as stored, and
+    `try_get_encryptable_value` tells why. */
     pub fn get_encryptable_value(&self) -> Encryptable {
         unsafe {
             let encryptable = FLDict_GetEncryptableValue(self.get_ref());
+            if encryptable.is_null() && !self.get_ref().is_null() && self.is_encryptable() {
+                // Only the encryptables of saved documents are found; those of mutable
+                // dictionaries are read from their properties
+                return Encryptable::create_with_value(self.get("value"));
+            }
             Encryptable::retain(encryptable as *mut CBLEncryptable)
         }
     }
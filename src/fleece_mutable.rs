@@ -16,7 +16,7 @@
 //
 
 use crate::{
-    CblRef, CouchbaseLiteError, Error, ErrorCode, Result, encryptable,
+    CblRef, CouchbaseLiteError, Error, Result, Timestamp, encryptable,
     slice::{from_bytes, from_str},
     c_api::{
         FLArray_AsMutable, FLArray_MutableCopy, FLDict_AsMutable, FLDict_MutableCopy,
@@ -24,8 +24,8 @@ use crate::{
         FLMutableArray_New, FLMutableArray_Remove, FLMutableArray_Set, FLMutableDict,
         FLMutableDict_IsChanged, FLMutableDict_New, FLMutableDict_Remove, FLMutableDict_RemoveAll,
         FLMutableDict_Set, FLSlot, FLSlot_SetBool, FLSlot_SetDouble, FLSlot_SetEncryptableValue,
-        FLSlot_SetInt, FLSlot_SetNull, FLSlot_SetString, FLSlot_SetValue, FLValue, FLValue_Release,
-        FLValue_Retain,
+        FLSlot_SetInt, FLSlot_SetUInt, FLSlot_SetNull, FLSlot_SetString, FLSlot_SetValue, FLValue,
+        FLValue_Release, FLValue_Retain,
     },
     fleece::{Array, ArrayIterator, Dict, DictIterator, DictKey, FleeceReference, Value},
     encryptable::Encryptable,
@@ -114,10 +114,7 @@ impl MutableArray {
             unsafe { FLMutableArray_Insert(self.get_ref(), index, 1) }
             Ok(())
         } else {
-            Err(Error {
-                code: ErrorCode::CouchbaseLite(CouchbaseLiteError::MemoryError),
-                internal_info: None,
-            })
+            Err(Error::cbl_error(CouchbaseLiteError::MemoryError))
         }
     }
 
@@ -307,6 +304,8 @@ impl MutableDict {
             .collect::<HashMap<String, String>>()
     }
 
+    /** Stores an encryptable value as is, which can't fail: it's only encrypted by the
+    replicator. `try_set_encryptable_value` also encrypts it at rest. */
     pub fn set_encryptable_value(dict: &Self, key: &str, encryptable: &Encryptable) {
         unsafe {
             FLSlot_SetEncryptableValue(
@@ -462,6 +461,10 @@ impl<'s> Slot<'s> {
         unsafe { FLSlot_SetInt(self.get_ref(), value.into()) }
     }
 
+    pub fn put_u64<INT: Into<u64>>(self, value: INT) {
+        unsafe { FLSlot_SetUInt(self.get_ref(), value.into()) }
+    }
+
     pub fn put_f64<F: Into<f64>>(self, value: F) {
         unsafe { FLSlot_SetDouble(self.get_ref(), value.into()) }
     }
@@ -478,7 +481,151 @@ impl<'s> Slot<'s> {
         unsafe { FLSlot_SetValue(self.get_ref(), value._fleece_ref()) }
     }
 
+    /** Stores an encryptable value as is, which can't fail: it's only encrypted by the
+    replicator. `try_put_encrypt` also encrypts it at rest. */
     pub fn put_encrypt(self, value: &encryptable::Encryptable) {
         unsafe { FLSlot_SetEncryptableValue(self.get_ref(), value.get_ref()) }
     }
 }
+
+//////// TO FLEECE:
+
+/** A value that can be stored into a `Slot`, converted to the equivalent Fleece value.
+This is used for instance by `Query::bind` to assign query parameters. */
+pub trait ToFleece {
+    fn to_fleece(&self, slot: Slot);
+}
+
+impl<'s> Slot<'s> {
+    /** Stores any value implementing `ToFleece`. */
+    pub fn put<T: ToFleece + ?Sized>(self, value: &T) {
+        value.to_fleece(self);
+    }
+}
+
+impl<T: ToFleece + ?Sized> ToFleece for &T {
+    fn to_fleece(&self, slot: Slot) {
+        (**self).to_fleece(slot);
+    }
+}
+
+impl ToFleece for bool {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_bool(*self);
+    }
+}
+
+macro_rules! int_to_fleece {
+    ($($t:ty),*) => {$(
+        impl ToFleece for $t {
+            fn to_fleece(&self, slot: Slot) {
+                slot.put_i64(*self);
+            }
+        }
+    )*};
+}
+
+int_to_fleece!(i8, i16, i32, i64, u8, u16, u32);
+
+impl ToFleece for u64 {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_u64(*self);
+    }
+}
+
+impl ToFleece for usize {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_u64(*self as u64);
+    }
+}
+
+impl ToFleece for isize {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_i64(*self as i64);
+    }
+}
+
+impl ToFleece for f32 {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_f64(*self);
+    }
+}
+
+impl ToFleece for f64 {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_f64(*self);
+    }
+}
+
+impl ToFleece for str {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_string(self);
+    }
+}
+
+impl ToFleece for String {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_string(self);
+    }
+}
+
+/** Timestamps are stored as their number of milliseconds since the Unix epoch. */
+impl ToFleece for Timestamp {
+    fn to_fleece(&self, slot: Slot) {
+        slot.put_i64(self.0);
+    }
+}
+
+/** `None` is stored as a JSON `null`. */
+impl<T: ToFleece> ToFleece for Option<T> {
+    fn to_fleece(&self, slot: Slot) {
+        match self {
+            Some(value) => value.to_fleece(slot),
+            None => slot.put_null(),
+        }
+    }
+}
+
+impl<T: ToFleece> ToFleece for [T] {
+    fn to_fleece(&self, slot: Slot) {
+        let mut array = MutableArray::new();
+        for item in self {
+            item.to_fleece(array.append());
+        }
+        slot.put_value(&array);
+    }
+}
+
+impl<T: ToFleece, const N: usize> ToFleece for [T; N] {
+    fn to_fleece(&self, slot: Slot) {
+        self[..].to_fleece(slot);
+    }
+}
+
+impl<T: ToFleece> ToFleece for Vec<T> {
+    fn to_fleece(&self, slot: Slot) {
+        self[..].to_fleece(slot);
+    }
+}
+
+impl<K: AsRef<str>, T: ToFleece> ToFleece for HashMap<K, T> {
+    fn to_fleece(&self, slot: Slot) {
+        let mut dict = MutableDict::new();
+        for (key, value) in self {
+            value.to_fleece(dict.at(key.as_ref()));
+        }
+        slot.put_value(&dict);
+    }
+}
+
+macro_rules! fleece_reference_to_fleece {
+    ($($t:ty),*) => {$(
+        impl ToFleece for $t {
+            fn to_fleece(&self, slot: Slot) {
+                slot.put_value(self);
+            }
+        }
+    )*};
+}
+
+fleece_reference_to_fleece!(Value, Array, Dict, MutableArray, MutableDict);
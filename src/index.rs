@@ -1,37 +1,179 @@
 use crate::{
-    CblRef, Database,
+    CblRef, Database, Fleece, MutableDict, warn,
     c_api::{
         CBLValueIndexConfiguration, CBLDatabase_GetIndexNames, CBLDatabase_DeleteIndex, CBLError,
-        CBLDatabase_CreateValueIndex,
+        CBLDatabase_CreateValueIndex, CBLFullTextIndexConfiguration,
+        CBLDatabase_CreateFullTextIndex,
     },
     error::{Result, failure},
-    slice::from_str,
+    slice::{from_str, NULL_SLICE},
     QueryLanguage, Array,
+    query::invalidate_prepared_queries,
 };
 
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ValueIndexConfiguration {
-    cbl_ref: CBLValueIndexConfiguration,
+    query_language: QueryLanguage,
+    expressions: String,
 }
 
 impl CblRef for ValueIndexConfiguration {
     type Output = CBLValueIndexConfiguration;
     fn get_ref(&self) -> Self::Output {
-        self.cbl_ref
+        CBLValueIndexConfiguration {
+            expressionLanguage: self.query_language as u32,
+            expressions: from_str(&self.expressions).get_ref(),
+        }
     }
 }
 
 impl ValueIndexConfiguration {
     pub fn new(query_language: QueryLanguage, expressions: &str) -> Self {
-        let slice = from_str(expressions);
         Self {
-            cbl_ref: CBLValueIndexConfiguration {
-                expressionLanguage: query_language as u32,
-                expressions: slice.get_ref(),
-            },
+            query_language,
+            expressions: expressions.to_string(),
+        }
+    }
+
+    pub const fn query_language(&self) -> QueryLanguage {
+        self.query_language
+    }
+
+    pub fn expressions(&self) -> &str {
+        &self.expressions
+    }
+}
+
+/** Configuration of a full-text index, required to use the `MATCH` operator in a query. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullTextIndexConfiguration {
+    query_language: QueryLanguage,
+    expressions: String,
+    ignore_accents: bool,
+    language: Option<String>,
+}
+
+impl CblRef for FullTextIndexConfiguration {
+    type Output = CBLFullTextIndexConfiguration;
+    fn get_ref(&self) -> Self::Output {
+        CBLFullTextIndexConfiguration {
+            expressionLanguage: self.query_language as u32,
+            expressions: from_str(&self.expressions).get_ref(),
+            ignoreAccents: self.ignore_accents,
+            language: self
+                .language
+                .as_ref()
+                .map_or(NULL_SLICE, |language| from_str(language).get_ref()),
         }
     }
 }
 
+impl FullTextIndexConfiguration {
+    /** Creates a full-text index configuration. `language` is the dominant language of the text,
+    as an ISO-639 code or an English name (e.g. `en` or `english`), which enables stemming and
+    stop-words; `None` disables them. */
+    pub fn new(
+        query_language: QueryLanguage,
+        expressions: &str,
+        ignore_accents: bool,
+        language: Option<&str>,
+    ) -> Self {
+        Self {
+            query_language,
+            expressions: expressions.to_string(),
+            ignore_accents,
+            language: language.map(str::to_string),
+        }
+    }
+
+    pub const fn query_language(&self) -> QueryLanguage {
+        self.query_language
+    }
+
+    pub fn expressions(&self) -> &str {
+        &self.expressions
+    }
+
+    pub const fn ignore_accents(&self) -> bool {
+        self.ignore_accents
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+}
+
+/** The configuration of an index of either kind. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexConfiguration {
+    Value(ValueIndexConfiguration),
+    FullText(FullTextIndexConfiguration),
+}
+
+impl From<ValueIndexConfiguration> for IndexConfiguration {
+    fn from(config: ValueIndexConfiguration) -> Self {
+        Self::Value(config)
+    }
+}
+
+impl From<FullTextIndexConfiguration> for IndexConfiguration {
+    fn from(config: FullTextIndexConfiguration) -> Self {
+        Self::FullText(config)
+    }
+}
+
+//////// INDEX SCHEMA:
+
+/* The configuration of each index is recorded under its name with this prefix. */
+const INDEX_CONFIGURATION_PREFIX: &str = "index_configuration:";
+
+/** The set of indexes a database should have, by name. See `Database::sync_indexes`. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexSchema {
+    indexes: BTreeMap<String, IndexConfiguration>,
+}
+
+impl IndexSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Adds an index to the schema, replacing any index of the same name. */
+    #[must_use]
+    pub fn index<C: Into<IndexConfiguration>>(mut self, name: &str, config: C) -> Self {
+        self.indexes.insert(name.to_string(), config.into());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IndexConfiguration> {
+        self.indexes.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &IndexConfiguration)> {
+        self.indexes.iter()
+    }
+}
+
+/** What `Database::sync_indexes` did, listing index names by outcome. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexSyncReport {
+    pub created: Vec<String>,
+    pub recreated: Vec<String>,
+    pub dropped: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub adopted: Vec<String>, // Existing indexes of unknown configuration, see `sync_indexes`
+}
+
+impl IndexSyncReport {
+    /** True if the database's indexes were already in sync with the schema. `adopted` indexes
+    aren't counted, Couchbase Lite only rebuilt them if their definition differed. */
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.recreated.is_empty() && self.dropped.is_empty()
+    }
+}
+
 impl Database {
     pub fn create_index(&self, name: &str, config: &ValueIndexConfiguration) -> Result<bool> {
         let mut err = CBLError::default();
@@ -45,6 +187,33 @@ impl Database {
             )
         };
         if !err {
+            invalidate_prepared_queries();
+            self.record_index_configuration(name, Some(&config.clone().into()));
+            return Ok(r);
+        }
+        failure(err)
+    }
+
+    /** Creates a full-text index. If a different index with that name already exists,
+    it's deleted and re-created. */
+    pub fn create_full_text_index(
+        &self,
+        name: &str,
+        config: &FullTextIndexConfiguration,
+    ) -> Result<bool> {
+        let mut err = CBLError::default();
+        let slice = from_str(name);
+        let r = unsafe {
+            CBLDatabase_CreateFullTextIndex(
+                self.get_ref(),
+                slice.get_ref(),
+                config.get_ref(),
+                &mut err,
+            )
+        };
+        if !err {
+            invalidate_prepared_queries();
+            self.record_index_configuration(name, Some(&config.clone().into()));
             return Ok(r);
         }
         failure(err)
@@ -55,6 +224,8 @@ impl Database {
         let slice = from_str(name);
         let r = unsafe { CBLDatabase_DeleteIndex(self.get_ref(), slice.get_ref(), &mut err) };
         if !err {
+            invalidate_prepared_queries();
+            self.record_index_configuration(name, None);
             return Ok(r);
         }
         failure(err)
@@ -64,4 +235,128 @@ impl Database {
         let arr = unsafe { CBLDatabase_GetIndexNames(self.get_ref()) };
         Array::wrap(arr)
     }
+
+    /** Returns the configuration an index was created with.
+    Couchbase Lite can't report the definition of an index, so this is read from a record kept
+    by `create_index`, `create_full_text_index` and `sync_indexes` in a companion database next
+    to this one. It is `None` for indexes created by another library, or whose configuration
+    couldn't be recorded, which is logged as a warning. */
+    pub fn index_configuration(&self, name: &str) -> Result<Option<IndexConfiguration>> {
+        Ok(self.index_configurations()?.remove(name))
+    }
+
+    /** Brings the database's indexes in line with `schema`: creates the missing indexes,
+    re-creates the ones whose configuration changed, and deletes the ones the schema doesn't
+    list.
+    Existing indexes whose configuration isn't known (see `index_configuration`) can't be
+    compared to the schema: they are created again with the schema's configuration, which
+    Couchbase Lite only rebuilds if it differs, and reported as `adopted`. */
+    pub fn sync_indexes(&self, schema: &IndexSchema) -> Result<IndexSyncReport> {
+        let mut report = IndexSyncReport::default();
+        let recorded = self.index_configurations()?;
+        let existing: Vec<String> = self
+            .get_index_names()
+            .iter()
+            .filter_map(|name| name.as_string().map(str::to_string))
+            .collect();
+
+        for name in &existing {
+            if schema.get(name).is_none() {
+                self.delete_index(name)?;
+                report.dropped.push(name.clone());
+            }
+        }
+        for (name, config) in schema.iter() {
+            if !existing.contains(name) {
+                report.created.push(name.clone());
+            } else {
+                match recorded.get(name) {
+                    Some(recorded) if recorded == config => {
+                        report.unchanged.push(name.clone());
+                        continue;
+                    }
+                    Some(_) => report.recreated.push(name.clone()),
+                    None => report.adopted.push(name.clone()),
+                }
+            }
+            match config {
+                IndexConfiguration::Value(config) => self.create_index(name, config)?,
+                IndexConfiguration::FullText(config) => {
+                    self.create_full_text_index(name, config)?
+                }
+            };
+        }
+        Ok(report)
+    }
+
+    //////// INDEX CONFIGURATION RECORD:
+
+    pub(crate) fn index_configurations(&self) -> Result<BTreeMap<String, IndexConfiguration>> {
+        let records = self.read_metadata_records(INDEX_CONFIGURATION_PREFIX)?;
+        let mut configs = BTreeMap::new();
+        for (key, json) in records {
+            let doc = Fleece::parse_json(&json)?;
+            let dict = doc.as_dict();
+            let query_language = match dict.get("language").as_string() {
+                Some("N1QL") => QueryLanguage::N1QL,
+                Some(_) => QueryLanguage::JSON,
+                None => continue,
+            };
+            let expressions = dict.get("expressions");
+            let expressions = match expressions.as_string() {
+                Some(expressions) => expressions,
+                None => continue,
+            };
+            let config = if dict.get("fullText").as_bool_or_false() {
+                IndexConfiguration::FullText(FullTextIndexConfiguration::new(
+                    query_language,
+                    expressions,
+                    dict.get("ignoreAccents").as_bool_or_false(),
+                    dict.get("textLanguage").as_string(),
+                ))
+            } else {
+                IndexConfiguration::Value(ValueIndexConfiguration::new(query_language, expressions))
+            };
+            configs.insert(key[INDEX_CONFIGURATION_PREFIX.len()..].to_string(), config);
+        }
+        Ok(configs)
+    }
+
+    /** Records the configuration of an index, or that it was deleted with `None`, logging a
+    failure: the index itself was already created or deleted, which can't be undone. Each index
+    has its own record, replaced in a single write. */
+    fn record_index_configuration(&self, name: &str, config: Option<&IndexConfiguration>) {
+        let key = format!("{}{}", INDEX_CONFIGURATION_PREFIX, name);
+        let result = match config {
+            Some(config) => self.write_metadata(&key, &index_configuration_record(config)),
+            None => self.delete_metadata_record(&key),
+        };
+        if let Err(err) = result {
+            warn!(
+                "Recording the configuration of index {:?} failed: {}",
+                name, err
+            );
+        }
+    }
+}
+
+fn index_configuration_record(config: &IndexConfiguration) -> MutableDict {
+    let mut record = MutableDict::new();
+    let (query_language, expressions) = match config {
+        IndexConfiguration::Value(config) => (config.query_language, &config.expressions),
+        IndexConfiguration::FullText(config) => {
+            record.at("fullText").put_bool(true);
+            record.at("ignoreAccents").put_bool(config.ignore_accents);
+            if let Some(language) = &config.language {
+                record.at("textLanguage").put_string(language);
+            }
+            (config.query_language, &config.expressions)
+        }
+    };
+    record.at("language").put_string(match query_language {
+        QueryLanguage::N1QL => "N1QL",
+        QueryLanguage::JSON => "JSON",
+    });
+    record.at("expressions").put_string(expressions);
+    record
 }
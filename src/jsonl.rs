@@ -0,0 +1,387 @@
+// Couchbase Lite whole-database export and import, as JSON Lines
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    base64, Blob, ConcurrencyControl, CouchbaseLiteError, Database, Document, Error, ErrorCode,
+    Fleece, FleeceReference, MutableArray, MutableDict, Query, QueryLanguage, Slot, Timestamp,
+    Value, ValueType, error::Result, query::json_string,
+};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+//////// OPTIONS:
+
+/** How `Database::export_jsonl` writes the content of blobs. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobExport {
+    /** As Base64, in a `data` property of the blob's metadata. */
+    Inline,
+    /** As a file per blob in this directory, named after the blob's digest. */
+    Directory(PathBuf),
+    /** Not at all: only the blob's metadata is written. */
+    MetadataOnly,
+}
+
+/** Options of `Database::export_jsonl`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub blobs: BlobExport,
+    /** Whether to write the expiration time of documents that have one. */
+    pub include_expiration: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            blobs: BlobExport::Inline,
+            include_expiration: true,
+        }
+    }
+}
+
+/** Options of `Database::import_jsonl`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportOptions {
+    /** The number of documents saved per transaction. */
+    pub batch_size: usize,
+    /** Whether documents that already exist in the database are overwritten, or left alone. */
+    pub overwrite: bool,
+    /** The directory of the blob files, for an export made with `BlobExport::Directory`. */
+    pub blob_directory: Option<PathBuf>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            overwrite: false,
+            blob_directory: None,
+        }
+    }
+}
+
+/** What `Database::import_jsonl` did. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /** The number of documents saved. */
+    pub imported: usize,
+    /** The IDs of the documents that already existed in the database: skipped, or overwritten
+    if `ImportOptions::overwrite` is set. */
+    pub conflicts: Vec<String>,
+}
+
+//////// DATABASE:
+
+impl Database {
+    /** Writes every document of the database to `writer` as JSON Lines: one JSON object per
+    line, with the document's `id`, `rev` (revision ID), `expiration` (a timestamp in
+    milliseconds, if any) and `properties`. Returns the number of documents written.
+
+    Revision IDs are informative only: `import_jsonl` creates new revisions. */
+    pub fn export_jsonl<W: io::Write>(
+        &self,
+        mut writer: W,
+        options: &ExportOptions,
+    ) -> Result<usize> {
+        if let BlobExport::Directory(directory) = &options.blobs {
+            fs::create_dir_all(directory).map_err(io_error)?;
+        }
+        let ids = Query::new(self, QueryLanguage::N1QL, "SELECT meta().id FROM _")?;
+        let mut count = 0;
+        for row in ids.execute()? {
+            let id = row.get(0).as_string().unwrap_or_default().to_string();
+            let doc = self.get_document(&id)?;
+
+            let mut line = format!("{{\"id\":{}", json_string(doc.id()));
+            if let Some(revision_id) = doc.revision_id() {
+                line.push_str(&format!(",\"rev\":{}", json_string(revision_id)));
+            }
+            if options.include_expiration {
+                if let Some(Timestamp(expiration)) = self.document_expiration(doc.id())? {
+                    line.push_str(&format!(",\"expiration\":{}", expiration));
+                }
+            }
+            line.push_str(",\"properties\":");
+            line.push_str(&export_value(&doc.properties().as_value(), &options.blobs)?);
+            line.push_str("}\n");
+
+            writer.write_all(line.as_bytes()).map_err(io_error)?;
+            count += 1;
+        }
+        writer.flush().map_err(io_error)?;
+        Ok(count)
+    }
+
+    /** Recreates documents written by `export_jsonl`, preserving their IDs, expiration times and
+    blobs, in transactions of `ImportOptions::batch_size` documents. Empty lines are ignored;
+    an invalid line fails the import with `InvalidParameter`, after the batches before it
+    were saved.
+
+    Expirations are set just after each batch is committed, since Couchbase Lite can't set them
+    inside a transaction; if that fails, the batch is undone, by purging the documents it
+    created and saving the previous properties of the ones it overwrote. */
+    pub fn import_jsonl<R: io::BufRead>(
+        &mut self,
+        reader: R,
+        options: &ImportOptions,
+    ) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let mut batch = vec![];
+        for line in reader.lines() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            batch.push(parse_line(&line, options)?);
+            if batch.len() >= options.batch_size.max(1) {
+                self.import_batch(&mut batch, options, &mut report)?;
+            }
+        }
+        self.import_batch(&mut batch, options, &mut report)?;
+        Ok(report)
+    }
+
+    fn import_batch(
+        &mut self,
+        batch: &mut Vec<ImportedDocument>,
+        options: &ImportOptions,
+        report: &mut ImportReport,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let (imported, conflicts) = self.in_transaction(|db| {
+            let mut imported = vec![];
+            let mut conflicts = vec![];
+            for entry in batch.iter() {
+                let (mut doc, previous) = match db.get_document(&entry.id) {
+                    Ok(existing) => {
+                        conflicts.push(entry.id.clone());
+                        if !options.overwrite {
+                            continue;
+                        }
+                        let previous = PreviousDocument {
+                            properties: existing.properties_as_json(),
+                            expiration: db.document_expiration(&entry.id)?,
+                        };
+                        (existing, Some(previous))
+                    }
+                    Err(err)
+                        if err.code == ErrorCode::CouchbaseLite(CouchbaseLiteError::NotFound) =>
+                    {
+                        (Document::new_with_id(&entry.id), None)
+                    }
+                    Err(err) => return Err(err),
+                };
+                doc.set_properties(&entry.properties);
+                db.save_document_with_concurency_control(
+                    &mut doc,
+                    ConcurrencyControl::LastWriteWins,
+                )?;
+                imported.push((entry, previous));
+            }
+            Ok((imported, conflicts))
+        })?;
+
+        // Couchbase Lite 3.0 deadlocks when an expiration is set inside a transaction, so they
+        // are set after the commit; if that fails, the batch is undone.
+        for (entry, _) in &imported {
+            if let Err(err) = self.set_document_expiration(&entry.id, entry.expiration) {
+                self.undo_import(&imported);
+                return Err(err);
+            }
+        }
+        report.imported += imported.len();
+        report.conflicts.extend(conflicts);
+        batch.clear();
+        Ok(())
+    }
+
+    // Purges the documents a batch created, and restores the ones it overwrote, as far as
+    // possible: this is only reached after a failure.
+    fn undo_import(&mut self, imported: &[(&ImportedDocument, Option<PreviousDocument>)]) {
+        let _ = self.in_transaction(|db| {
+            for (entry, previous) in imported {
+                match previous {
+                    None => db.purge_document_by_id(&entry.id)?,
+                    Some(previous) => {
+                        let mut doc = db.get_document(&entry.id)?;
+                        doc.set_properties_as_json(&previous.properties)?;
+                        db.save_document_with_concurency_control(
+                            &mut doc,
+                            ConcurrencyControl::LastWriteWins,
+                        )?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        for (entry, previous) in imported {
+            if let Some(previous) = previous {
+                let _ = self.set_document_expiration(&entry.id, previous.expiration);
+            }
+        }
+    }
+}
+
+//////// INTERNALS:
+
+/** The state of a document before an import overwrote it. */
+struct PreviousDocument {
+    properties: String,
+    expiration: Option<Timestamp>,
+}
+
+struct ImportedDocument {
+    id: String,
+    properties: MutableDict,
+    expiration: Option<Timestamp>,
+    /** New blobs must stay alive until the document referencing them is saved. */
+    _blobs: Vec<Blob>,
+}
+
+fn parse_line(line: &str, options: &ImportOptions) -> Result<ImportedDocument> {
+    let invalid = || Error::cbl_error(CouchbaseLiteError::InvalidParameter);
+    let doc = Fleece::parse_json(line).map_err(|_| invalid())?;
+    let entry = doc.as_dict();
+    let id = entry.get("id").as_string().ok_or_else(invalid)?.to_string();
+    let properties = entry.get("properties");
+    if properties.get_type() != ValueType::Dict {
+        return Err(invalid());
+    }
+
+    let mut imported = MutableDict::new();
+    let mut blobs = vec![];
+    for (key, value) in properties.as_dict().iter() {
+        import_value(&value, imported.at(&key), options, &mut blobs)?;
+    }
+    Ok(ImportedDocument {
+        id,
+        properties: imported,
+        expiration: entry.get("expiration").as_i64().map(Timestamp),
+        _blobs: blobs,
+    })
+}
+
+/** The file name of a blob's content in an export directory: its digest, made safe for file
+systems. */
+fn blob_file_name(digest: &str) -> String {
+    digest.replace('/', "_").replace('+', "-")
+}
+
+// The JSON form of a document's value, with the content of blobs written as per `blobs`.
+fn export_value(value: &Value, blobs: &BlobExport) -> Result<String> {
+    match value.get_type() {
+        ValueType::Dict => {
+            let is_blob = value.is_blob();
+            let mut entries = vec![];
+            for (key, value) in value.as_dict().iter() {
+                if !(is_blob && key == "data") {
+                    entries.push(format!(
+                        "{}:{}",
+                        json_string(&key),
+                        export_value(&value, blobs)?
+                    ));
+                }
+            }
+            if let (true, Some(blob)) = (is_blob, value.as_blob()) {
+                match blobs {
+                    BlobExport::Inline => {
+                        let content = blob.load_content()?;
+                        entries.push(format!("\"data\":\"{}\"", base64::encode(&content)));
+                    }
+                    BlobExport::Directory(directory) => {
+                        let path = directory.join(blob_file_name(blob.digest()));
+                        if !path.exists() {
+                            fs::write(path, blob.load_content()?).map_err(io_error)?;
+                        }
+                    }
+                    BlobExport::MetadataOnly => {}
+                }
+            }
+            Ok(format!("{{{}}}", entries.join(",")))
+        }
+        ValueType::Array => {
+            let mut items = vec![];
+            for value in value.as_array().iter() {
+                items.push(export_value(&value, blobs)?);
+            }
+            Ok(format!("[{}]", items.join(",")))
+        }
+        _ => Ok(value.to_json()),
+    }
+}
+
+/** Stores an exported value into `slot`, turning blob metadata with content, inline or in the
+blob directory, back into a blob. */
+fn import_value(
+    value: &Value,
+    slot: Slot,
+    options: &ImportOptions,
+    blobs: &mut Vec<Blob>,
+) -> Result<()> {
+    match value.get_type() {
+        ValueType::Dict => {
+            let dict = value.as_dict();
+            if value.is_blob() {
+                if let Some(content) = blob_content(value, options.blob_directory.as_deref())? {
+                    let content_type = dict.get("content_type");
+                    let content_type = content_type.as_string().unwrap_or_default();
+                    let mut blob = Blob::new_from_data(&content, content_type);
+                    slot.put_blob(&mut blob);
+                    blobs.push(blob);
+                    return Ok(());
+                }
+            }
+            let mut imported = MutableDict::new();
+            for (key, value) in dict.iter() {
+                import_value(&value, imported.at(&key), options, blobs)?;
+            }
+            slot.put_value(&imported);
+        }
+        ValueType::Array => {
+            let mut imported = MutableArray::new();
+            for value in value.as_array().iter() {
+                import_value(&value, imported.append(), options, blobs)?;
+            }
+            slot.put_value(&imported);
+        }
+        _ => slot.put_value(value),
+    }
+    Ok(())
+}
+
+fn blob_content(blob: &Value, blob_directory: Option<&Path>) -> Result<Option<Vec<u8>>> {
+    let dict = blob.as_dict();
+    if let Some(data) = dict.get("data").as_string() {
+        return base64::decode(data)
+            .map(Some)
+            .ok_or_else(|| Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+    }
+    match (blob_directory, dict.get("digest").as_string()) {
+        (Some(directory), Some(digest)) => fs::read(directory.join(blob_file_name(digest)))
+            .map(Some)
+            .map_err(io_error),
+        _ => Ok(None),
+    }
+}
+
+fn io_error(_: io::Error) -> Error {
+    Error::cbl_error(CouchbaseLiteError::IOError)
+}
@@ -32,8 +32,18 @@
 
 #[macro_use]
 extern crate enum_primitive;
-
+#[cfg(feature = "crypto")]
+extern crate aes_gcm;
+#[cfg(feature = "legacy-key")]
+extern crate pbkdf2;
+#[cfg(feature = "legacy-key")]
+extern crate sha1;
+
+pub mod backup;
 pub mod blob;
+pub mod conflict;
+#[cfg(feature = "crypto")]
+pub mod crypto;
 pub mod database;
 pub mod document;
 pub mod encryptable;
@@ -41,12 +51,19 @@ pub mod error;
 pub mod fleece;
 pub mod fleece_mutable;
 pub mod index;
+pub mod jsonl;
 pub mod logging;
+pub mod maintenance;
+pub mod metrics;
+pub mod migration;
 pub mod query;
+pub mod query_builder;
 pub mod replicator;
 pub mod slice;
 
+mod base64;
 mod c_api;
+mod metadata;
 
 use self::c_api::{
     CBLListenerToken, CBLRefCounted, CBL_DumpInstances, CBL_InstanceCount, CBL_Release, CBL_Retain,
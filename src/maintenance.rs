@@ -0,0 +1,457 @@
+// Couchbase Lite automatic database maintenance
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    CouchbaseLiteError, Database, Dict, Error, ErrorCode, Fleece, FleeceReference, MaintenanceType,
+    MutableDict, Query, QueryLanguage, Value, ValueType, error::Result,
+};
+
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//////// POLICY:
+
+/** When `Database::maybe_run_maintenance` should run each kind of maintenance.
+Every condition is disabled by default. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenancePolicy {
+    compact_wal_size: Option<u64>,
+    compact_deletions: Option<u64>,
+    compact_interval: Option<Duration>,
+    optimize_interval: Option<Duration>,
+    integrity_check_interval: Option<Duration>,
+}
+
+impl MaintenancePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** A reasonable policy for a device: compact when the write-ahead log exceeds 32 MB or after
+    1000 deletions, optimize daily and check integrity weekly. */
+    pub fn recommended() -> Self {
+        Self::new()
+            .compact_when_wal_exceeds(32 * 1024 * 1024)
+            .compact_after_deletions(1000)
+            .optimize_every(Duration::from_secs(24 * 60 * 60))
+            .check_integrity_every(Duration::from_secs(7 * 24 * 60 * 60))
+    }
+
+    /** Compacts when the write-ahead log is larger than `bytes`. */
+    #[must_use]
+    pub const fn compact_when_wal_exceeds(mut self, bytes: u64) -> Self {
+        self.compact_wal_size = Some(bytes);
+        self
+    }
+
+    /** Compacts once `count` documents have been deleted since the last compaction. */
+    #[must_use]
+    pub const fn compact_after_deletions(mut self, count: u64) -> Self {
+        self.compact_deletions = Some(count);
+        self
+    }
+
+    /** Compacts when the last compaction is older than `interval`. */
+    #[must_use]
+    pub const fn compact_every(mut self, interval: Duration) -> Self {
+        self.compact_interval = Some(interval);
+        self
+    }
+
+    /** Optimizes the indexes when the last optimization is older than `interval`. */
+    #[must_use]
+    pub const fn optimize_every(mut self, interval: Duration) -> Self {
+        self.optimize_interval = Some(interval);
+        self
+    }
+
+    /** Checks integrity when the last check is older than `interval`. */
+    #[must_use]
+    pub const fn check_integrity_every(mut self, interval: Duration) -> Self {
+        self.integrity_check_interval = Some(interval);
+        self
+    }
+}
+
+//////// REPORT:
+
+/** The condition of a `MaintenancePolicy` that caused maintenance to run. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceTrigger {
+    /** The write-ahead log had this size, in bytes. */
+    WalSize(u64),
+    /** This many documents were deleted since the last compaction. */
+    Deletions(u64),
+    /** The interval since the last run of this kind of maintenance elapsed. */
+    Interval,
+}
+
+/** A maintenance operation that was run, why and how long it took. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceRun {
+    pub maintenance_type: MaintenanceType,
+    pub trigger: MaintenanceTrigger,
+    pub duration: Duration,
+}
+
+/** What `Database::maybe_run_maintenance` ran, in order. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub runs: Vec<MaintenanceRun>,
+}
+
+impl MaintenanceReport {
+    /** True if no maintenance was due. */
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+}
+
+//////// SCHEDULER:
+
+/** Runs a `MaintenancePolicy` periodically on a background thread, created by
+`Database::start_maintenance`. The thread uses its own connection to the database.
+
+# Lifetime
+
+The thread stops when this object is dropped; dropping it waits for any maintenance in progress
+to finish. */
+#[derive(Debug)]
+pub struct MaintenanceScheduler {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    reports: Receiver<Result<MaintenanceReport>>,
+}
+
+impl MaintenanceScheduler {
+    /** The channel receiving the outcome of each maintenance check. */
+    pub const fn reports(&self) -> &Receiver<Result<MaintenanceReport>> {
+        &self.reports
+    }
+}
+
+impl Drop for MaintenanceScheduler {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up and stops it
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//////// INTEGRITY CHECK:
+
+/** A problem found by `Database::check_integrity`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityProblem {
+    /** The database file or its indexes failed the storage engine's integrity check. The
+    database should be restored from a backup, or deleted and pulled again. */
+    Corruption(Error),
+    /** A document refers to a blob whose content isn't in the database, at the property `path`
+    (e.g. `photos[2].thumbnail`). Pulling the document again may restore it. */
+    MissingBlob {
+        document_id: String,
+        path: String,
+        digest: String,
+    },
+    /** An index whose configuration was recorded by `Database::create_index` or
+    `Database::create_full_text_index` isn't among the database's indexes, e.g. because it was
+    deleted by another library, or the database file was replaced. Only recorded indexes are
+    checked, by name: Couchbase Lite can't report the definition of an index, nor check its
+    contents. `Database::sync_indexes` can re-create it. */
+    RecordedIndexMissing(String),
+}
+
+/** The problems found by `Database::check_integrity`. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl IntegrityReport {
+    /** True if no problem was found. */
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /** True if the database file itself is damaged. */
+    pub fn is_corrupt(&self) -> bool {
+        self.problems
+            .iter()
+            .any(|problem| matches!(problem, IntegrityProblem::Corruption(_)))
+    }
+}
+
+//////// DATABASE:
+
+const MAINTENANCE_STATE_KEY: &str = "maintenance_state";
+
+/** When each kind of maintenance last ran, in seconds since the Unix epoch, and the number of
+deleted documents at the last compaction. */
+#[derive(Debug, Clone, Copy, Default)]
+struct MaintenanceState {
+    last_compact: Option<i64>,
+    last_optimize: Option<i64>,
+    last_integrity_check: Option<i64>,
+    deleted_at_compact: u64,
+}
+
+impl Database {
+    /** Runs the maintenance that `policy` says is due, and reports what ran. The time each kind
+    of maintenance last ran is recorded in the companion database this crate keeps next to the
+    database, so intervals carry over across sessions. */
+    pub fn maybe_run_maintenance(
+        &mut self,
+        policy: &MaintenancePolicy,
+    ) -> Result<MaintenanceReport> {
+        let mut report = MaintenanceReport::default();
+        let mut state = self.maintenance_state()?;
+        let now = unix_time();
+        let due = |last: Option<i64>, interval: Option<Duration>| match (last, interval) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(last), Some(interval)) => now.saturating_sub(last) >= interval.as_secs() as i64,
+        };
+
+        if due(state.last_integrity_check, policy.integrity_check_interval) {
+            let run = self.run_maintenance(
+                MaintenanceType::IntegrityCheck,
+                MaintenanceTrigger::Interval,
+            )?;
+            report.runs.push(run);
+            state.last_integrity_check = Some(now);
+        }
+
+        let stats = self.stats()?;
+        let deletions = stats.deleted_count.saturating_sub(state.deleted_at_compact);
+        let compact_trigger = match policy {
+            MaintenancePolicy {
+                compact_wal_size: Some(size),
+                ..
+            } if stats.wal_size > *size => Some(MaintenanceTrigger::WalSize(stats.wal_size)),
+            MaintenancePolicy {
+                compact_deletions: Some(count),
+                ..
+            } if deletions >= *count => Some(MaintenanceTrigger::Deletions(deletions)),
+            _ if due(state.last_compact, policy.compact_interval) => {
+                Some(MaintenanceTrigger::Interval)
+            }
+            _ => None,
+        };
+        if let Some(trigger) = compact_trigger {
+            report
+                .runs
+                .push(self.run_maintenance(MaintenanceType::Compact, trigger)?);
+            state.last_compact = Some(now);
+            state.deleted_at_compact = stats.deleted_count;
+        } else if stats.deleted_count < state.deleted_at_compact {
+            // Tombstones were purged since the last compaction
+            state.deleted_at_compact = stats.deleted_count;
+        }
+
+        if due(state.last_optimize, policy.optimize_interval) {
+            let run =
+                self.run_maintenance(MaintenanceType::Optimize, MaintenanceTrigger::Interval)?;
+            report.runs.push(run);
+            state.last_optimize = Some(now);
+        }
+
+        self.record_maintenance_state(&state)?;
+        Ok(report)
+    }
+
+    /** Starts checking `policy` every `interval` on a background thread, beginning immediately.
+    The outcome of each check is sent to the scheduler's `reports` channel. */
+    pub fn start_maintenance(
+        &self,
+        policy: MaintenancePolicy,
+        interval: Duration,
+    ) -> MaintenanceScheduler {
+        let name = self.name().to_string();
+        let config = self.config();
+        let (stop_sender, stop_receiver) = channel::<()>();
+        let (report_sender, report_receiver) = channel();
+
+        let thread = thread::spawn(move || {
+            let mut db = match Database::open_with_config(&name, &config) {
+                Ok(db) => db,
+                Err(err) => {
+                    let _ = report_sender.send(Err(err));
+                    return;
+                }
+            };
+            loop {
+                if report_sender
+                    .send(db.maybe_run_maintenance(&policy))
+                    .is_err()
+                {
+                    return;
+                }
+                match stop_receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+
+        MaintenanceScheduler {
+            stop: Some(stop_sender),
+            thread: Some(thread),
+            reports: report_receiver,
+        }
+    }
+
+    /** Checks the database for problems: runs the storage engine's integrity check, then looks
+    for blobs referenced by documents but missing from the database, and for indexes whose
+    configuration was recorded by this crate but that no longer exist. Errors that aren't caused by corruption, like I/O errors, are
+    returned as errors rather than reported. */
+    pub fn check_integrity(&mut self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+        if let Err(err) = self.perform_maintenance(MaintenanceType::IntegrityCheck) {
+            if !is_corruption(&err) {
+                return Err(err);
+            }
+            report.problems.push(IntegrityProblem::Corruption(err));
+        }
+
+        let ids = Query::new(self, QueryLanguage::N1QL, "SELECT meta().id FROM _")?;
+        for row in ids.execute()? {
+            let document_id = row.get(0).as_string().unwrap_or_default().to_string();
+            let document = self.get_document(&document_id)?;
+            let mut blobs = vec![];
+            find_blobs(document.properties().as_value(), String::new(), &mut blobs);
+            for (path, properties) in blobs {
+                if self.get_blob(&properties)?.is_none() {
+                    report.problems.push(IntegrityProblem::MissingBlob {
+                        document_id: document_id.clone(),
+                        path,
+                        digest: properties
+                            .get("digest")
+                            .as_string()
+                            .unwrap_or_default()
+                            .to_string(),
+                    });
+                }
+            }
+        }
+
+        let existing = self.get_index_names();
+        for name in self.index_configurations()?.into_keys() {
+            if !existing
+                .iter()
+                .any(|index| index.as_string() == Some(&name))
+            {
+                report
+                    .problems
+                    .push(IntegrityProblem::RecordedIndexMissing(name));
+            }
+        }
+        Ok(report)
+    }
+
+    fn run_maintenance(
+        &mut self,
+        maintenance_type: MaintenanceType,
+        trigger: MaintenanceTrigger,
+    ) -> Result<MaintenanceRun> {
+        let start = Instant::now();
+        self.perform_maintenance(maintenance_type)?;
+        Ok(MaintenanceRun {
+            maintenance_type,
+            trigger,
+            duration: start.elapsed(),
+        })
+    }
+
+    //////// MAINTENANCE STATE RECORD:
+
+    fn maintenance_state(&self) -> Result<MaintenanceState> {
+        let json = match self.read_metadata(MAINTENANCE_STATE_KEY)? {
+            Some(json) => json,
+            None => return Ok(MaintenanceState::default()),
+        };
+        let doc = Fleece::parse_json(&json)?;
+        let dict = doc.as_dict();
+        Ok(MaintenanceState {
+            last_compact: dict.get("lastCompact").as_i64(),
+            last_optimize: dict.get("lastOptimize").as_i64(),
+            last_integrity_check: dict.get("lastIntegrityCheck").as_i64(),
+            deleted_at_compact: dict.get("deletedAtCompact").as_u64_or_0(),
+        })
+    }
+
+    fn record_maintenance_state(&self, state: &MaintenanceState) -> Result<()> {
+        let mut record = MutableDict::new();
+        for (key, time) in [
+            ("lastCompact", state.last_compact),
+            ("lastOptimize", state.last_optimize),
+            ("lastIntegrityCheck", state.last_integrity_check),
+        ] {
+            if let Some(time) = time {
+                record.at(key).put_i64(time);
+            }
+        }
+        record
+            .at("deletedAtCompact")
+            .put_i64(state.deleted_at_compact as i64);
+        self.write_metadata(MAINTENANCE_STATE_KEY, &record)
+    }
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+fn is_corruption(err: &Error) -> bool {
+    const SQLITE_CORRUPT: i32 = 11;
+    const SQLITE_NOTADB: i32 = 26;
+    matches!(
+        err.code,
+        ErrorCode::CouchbaseLite(
+            CouchbaseLiteError::CorruptData
+                | CouchbaseLiteError::CorruptRevisionData
+                | CouchbaseLiteError::NotADatabaseFile
+        ) | ErrorCode::SQLite(SQLITE_CORRUPT | SQLITE_NOTADB)
+    )
+}
+
+/** Collects the blob dictionaries in a document's properties, with their property paths. */
+fn find_blobs(value: Value, path: String, blobs: &mut Vec<(String, Dict)>) {
+    match value.get_type() {
+        ValueType::Dict if value.is_blob() => blobs.push((path, value.as_dict())),
+        ValueType::Dict => {
+            for (key, value) in value.as_dict().iter() {
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                find_blobs(value, path, blobs);
+            }
+        }
+        ValueType::Array => {
+            for (i, value) in value.as_array().iter().enumerate() {
+                find_blobs(value, format!("{}[{}]", path, i), blobs);
+            }
+        }
+        _ => {}
+    }
+}
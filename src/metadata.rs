@@ -0,0 +1,143 @@
+// Couchbase Lite wrapper metadata
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    CouchbaseLiteError, Database, DatabaseConfig, Document, ErrorCode, FleeceReference,
+    MutableDict, Query, QueryLanguage, error::Result,
+};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/* The state this crate keeps about a database, like the configurations of its indexes, is stored
+in a companion database next to it, named after it with the suffix below. Couchbase Lite 3.0 has
+no local (non-replicated) documents, and files added to the database's bundle belong to
+Couchbase Lite; the companion database is never replicated, and isn't encrypted since it only
+holds bookkeeping. */
+const METADATA_SUFFIX: &str = ".rust-metadata";
+
+pub(crate) fn metadata_name(name: &str) -> String {
+    format!("{}{}", name, METADATA_SUFFIX)
+}
+
+impl Database {
+    fn metadata_directory(&self) -> PathBuf {
+        self.path()
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf)
+    }
+
+    /** Opens the companion database, if it exists. */
+    pub(crate) fn open_metadata(&self) -> Result<Option<Database>> {
+        let directory = self.metadata_directory();
+        let name = metadata_name(self.name());
+        if !Database::exists(&name, &directory) {
+            return Ok(None);
+        }
+        Database::open_with_config(&name, &DatabaseConfig::new().directory(directory)).map(Some)
+    }
+
+    /** Reads a record of the companion database, as JSON. Returns `None` if it was never
+    written, without creating the companion database. */
+    pub(crate) fn read_metadata(&self, key: &str) -> Result<Option<String>> {
+        let directory = self.metadata_directory();
+        let name = metadata_name(self.name());
+        if !Database::exists(&name, &directory) {
+            return Ok(None);
+        }
+        let metadata =
+            Database::open_with_config(&name, &DatabaseConfig::new().directory(directory))?;
+        let result = match metadata.get_document(key) {
+            Ok(document) if !document.is_deleted() => Ok(Some(document.properties_as_json())),
+            Ok(_) => Ok(None),
+            Err(err) if err.code == ErrorCode::CouchbaseLite(CouchbaseLiteError::NotFound) => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        };
+        metadata.close()?;
+        result
+    }
+
+    /** Writes a record of the companion database, creating it if needed. */
+    pub(crate) fn write_metadata(&self, key: &str, record: &MutableDict) -> Result<()> {
+        let directory = self.metadata_directory();
+        let mut metadata = Database::open_with_config(
+            &metadata_name(self.name()),
+            &DatabaseConfig::new().directory(directory),
+        )?;
+        let mut document = Document::new_with_id(key);
+        document.set_properties_as_json(&record.to_json())?;
+        // Saved with last-write-wins concurrency control, replacing any existing record
+        let result = metadata.save_document(&mut document);
+        metadata.close()?;
+        result
+    }
+
+    /** Reads the records of the companion database whose key starts with `prefix`, as JSON, by
+    key. */
+    pub(crate) fn read_metadata_records(&self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        let directory = self.metadata_directory();
+        let name = metadata_name(self.name());
+        if !Database::exists(&name, &directory) {
+            return Ok(BTreeMap::new());
+        }
+        let metadata =
+            Database::open_with_config(&name, &DatabaseConfig::new().directory(directory))?;
+        let result = (|| {
+            let mut records = BTreeMap::new();
+            let ids = Query::new(&metadata, QueryLanguage::N1QL, "SELECT meta().id FROM _")?;
+            for row in ids.execute()? {
+                let id = row.get(0);
+                let id = match id.as_string() {
+                    Some(id) if id.starts_with(prefix) => id,
+                    _ => continue,
+                };
+                // Every write replaces a whole record, so each one is read as it was written
+                let document = metadata.get_document(id)?;
+                records.insert(id.to_string(), document.properties_as_json());
+            }
+            Ok(records)
+        })();
+        metadata.close()?;
+        result
+    }
+
+    /** Deletes a record of the companion database, if it exists. */
+    pub(crate) fn delete_metadata_record(&self, key: &str) -> Result<()> {
+        let directory = self.metadata_directory();
+        let name = metadata_name(self.name());
+        if !Database::exists(&name, &directory) {
+            return Ok(());
+        }
+        let mut metadata =
+            Database::open_with_config(&name, &DatabaseConfig::new().directory(directory))?;
+        let result = match metadata.purge_document_by_id(key) {
+            Err(err) if err.code == ErrorCode::CouchbaseLite(CouchbaseLiteError::NotFound) => {
+                Ok(())
+            }
+            result => result,
+        };
+        metadata.close()?;
+        result
+    }
+
+    /** Deletes the companion database of a database, if any. */
+    pub(crate) fn delete_metadata<P: AsRef<Path>>(name: &str, in_directory: P) -> Result<bool> {
+        Database::delete_database_file(&metadata_name(name), in_directory)
+    }
+}
@@ -0,0 +1,373 @@
+// Couchbase Lite replication metrics
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    Direction, ErrorCode, ReplicatedDocument, ReplicationConfigurationContext, ReplicationFilter,
+    Replicator, ReplicatorActivityLevel, ReplicatorStatus,
+};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//////// SNAPSHOT:
+
+/** The counters of a `ReplicationMetrics`, at the time of `ReplicationMetrics::snapshot`. */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub documents_pushed: u64, // Documents pushed successfully, deletions included
+    pub documents_pulled: u64, // Documents pulled successfully, deletions included
+    pub bytes_pushed: u64,     // Estimated size of the documents pushed, see `instrument`
+    pub bytes_pulled: u64,     // Estimated size of the documents pulled, see `instrument`
+    pub errors: HashMap<ErrorCode, u64>, // Replicator and document errors, by code
+    pub activity_time: HashMap<ReplicatorActivityLevel, Duration>, // Time spent in each state
+    pub reconnects: u64,       // Connections attempted after the first one
+}
+
+impl MetricsSnapshot {
+    /** Returns the number of errors of any code. */
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    /** Returns the time the replicator spent in an activity level. */
+    pub fn time_in(&self, activity: ReplicatorActivityLevel) -> Duration {
+        self.activity_time
+            .get(&activity)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+//////// COLLECTOR:
+
+/** Collects the metrics of a replicator, for monitoring: the documents and bytes transferred,
+the errors by code, the time spent in each activity level, and the reconnections.
+
+The collector is fed by a change listener and a document listener, added by `attach`, and by
+the replication filters wrapped by `instrument`, which measure the bytes. It can be cloned, for
+instance to take snapshots from another thread; the clones share their counters. */
+#[derive(Debug, Default, Clone)]
+pub struct ReplicationMetrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    counters: MetricsSnapshot,
+    activity: Option<(ReplicatorActivityLevel, Instant)>,
+    last_error: Option<ErrorCode>,
+    pending_sizes: HashMap<(Direction, String), u64>, // Sizes of the documents being replicated
+}
+
+impl ReplicationMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Adds the listeners collecting the metrics of a replicator to it. */
+    #[must_use]
+    pub fn attach(&self, replicator: Replicator) -> Replicator {
+        let status_metrics = self.clone();
+        let document_metrics = self.clone();
+        replicator
+            .add_change_listener(Box::new(move |status| {
+                status_metrics.record_status(&status);
+            }))
+            .add_document_listener(Box::new(move |direction, documents| {
+                document_metrics.record_documents(direction, &documents);
+            }))
+    }
+
+    /** Wraps the push and pull filters of the context of a replicator to create, so that the
+    bytes it transfers are counted.
+
+    Couchbase Lite doesn't report the bytes it transfers, and its progress only counts
+    documents: they are estimated from the size, as JSON, of the bodies of the documents the
+    filters let through, which are in memory, and counted once the document listener reports
+    them replicated. Deletions count for no bytes, and attachments aren't counted. Without
+    it, the byte counters stay at zero. */
+    #[must_use]
+    pub fn instrument(
+        &self,
+        mut context: Box<ReplicationConfigurationContext>,
+    ) -> Box<ReplicationConfigurationContext> {
+        context.push_filter =
+            Some(self.sizing_filter(Direction::Pushed, context.push_filter.take()));
+        context.pull_filter =
+            Some(self.sizing_filter(Direction::Pulled, context.pull_filter.take()));
+        context
+    }
+
+    /** Returns the current counters. The time spent in the current activity level is counted
+    up to now. */
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let state = self.state.lock().unwrap();
+        let mut snapshot = state.counters.clone();
+        if let Some((activity, since)) = state.activity {
+            *snapshot.activity_time.entry(activity).or_default() += since.elapsed();
+        }
+        snapshot
+    }
+
+    /** Sets all the counters back to zero. */
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.counters = MetricsSnapshot::default();
+        if let Some((_, since)) = state.activity.as_mut() {
+            *since = Instant::now();
+        }
+    }
+
+    fn record_status(&self, status: &ReplicatorStatus) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let previous = state.activity.map(|(activity, _)| activity);
+        if previous != Some(status.activity) {
+            if let Some((activity, since)) = state.activity {
+                *state.counters.activity_time.entry(activity).or_default() += now - since;
+            }
+            // The replicator reconnects when it goes offline, or loses its connection
+            if status.activity == ReplicatorActivityLevel::Connecting
+                && previous.is_some_and(|previous| previous != ReplicatorActivityLevel::Stopped)
+            {
+                state.counters.reconnects += 1;
+            }
+            state.activity = Some((status.activity, now));
+        }
+
+        // The same error is reported by every status, until the replicator recovers
+        let error = status.error.as_ref().err().map(|err| err.code);
+        if let Some(code) = error {
+            if state.last_error != Some(code) {
+                *state.counters.errors.entry(code).or_default() += 1;
+            }
+        }
+        state.last_error = error;
+    }
+
+    fn sizing_filter(
+        &self,
+        direction: Direction,
+        filter: Option<ReplicationFilter>,
+    ) -> ReplicationFilter {
+        let metrics = self.clone();
+        Box::new(move |document, is_deleted, is_access_removed| {
+            let replicated = match &filter {
+                Some(filter) => filter(document, is_deleted, is_access_removed),
+                None => true,
+            };
+            if replicated && !is_deleted {
+                let size = document.properties_as_json().len() as u64;
+                metrics
+                    .state
+                    .lock()
+                    .unwrap()
+                    .pending_sizes
+                    .insert((direction, document.id().to_string()), size);
+            }
+            replicated
+        })
+    }
+
+    fn record_documents(&self, direction: Direction, documents: &[ReplicatedDocument]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let counters = &mut state.counters;
+        for document in documents {
+            // A document failing is filtered again when it's retried
+            let size = state
+                .pending_sizes
+                .remove(&(direction, document.id.clone()))
+                .unwrap_or_default();
+            match &document.error {
+                Ok(()) => match direction {
+                    Direction::Pushed => {
+                        counters.documents_pushed += 1;
+                        counters.bytes_pushed += size;
+                    }
+                    Direction::Pulled => {
+                        counters.documents_pulled += 1;
+                        counters.bytes_pulled += size;
+                    }
+                },
+                Err(err) => *counters.errors.entry(err.code).or_default() += 1,
+            }
+        }
+    }
+}
+
+//////// PROMETHEUS:
+
+#[cfg(feature = "prometheus")]
+impl MetricsSnapshot {
+    /** Returns the counters in the Prometheus text exposition format, with the given labels
+    added to every sample, e.g. to tell replicators apart. */
+    pub fn to_prometheus(&self, labels: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+
+        write_metric_header(
+            &mut out,
+            "cbl_replication_documents_total",
+            "Documents replicated successfully.",
+        );
+        for (direction, count) in [
+            ("push", self.documents_pushed),
+            ("pull", self.documents_pulled),
+        ] {
+            let sample_labels = with_label(labels, ("direction", direction));
+            write_sample(
+                &mut out,
+                "cbl_replication_documents_total",
+                &sample_labels,
+                count,
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cbl_replication_bytes_total",
+            "Estimated size of the documents replicated successfully.",
+        );
+        for (direction, count) in [("push", self.bytes_pushed), ("pull", self.bytes_pulled)] {
+            let sample_labels = with_label(labels, ("direction", direction));
+            write_sample(
+                &mut out,
+                "cbl_replication_bytes_total",
+                &sample_labels,
+                count,
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cbl_replication_errors_total",
+            "Replication errors, by domain and code.",
+        );
+        let mut errors: Vec<(&'static str, String, u64)> = self
+            .errors
+            .iter()
+            .map(|(code, count)| {
+                let (domain, code) = error_labels(*code);
+                (domain, code, *count)
+            })
+            .collect();
+        errors.sort();
+        for (domain, code, count) in errors {
+            let mut sample_labels = with_label(labels, ("domain", domain));
+            sample_labels.push(("code", &code));
+            write_sample(
+                &mut out,
+                "cbl_replication_errors_total",
+                &sample_labels,
+                count,
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cbl_replication_activity_seconds_total",
+            "Time spent by the replicator in each activity level.",
+        );
+        for (activity, name) in [
+            (ReplicatorActivityLevel::Stopped, "stopped"),
+            (ReplicatorActivityLevel::Offline, "offline"),
+            (ReplicatorActivityLevel::Connecting, "connecting"),
+            (ReplicatorActivityLevel::Idle, "idle"),
+            (ReplicatorActivityLevel::Busy, "busy"),
+        ] {
+            let sample_labels = with_label(labels, ("level", name));
+            write_sample(
+                &mut out,
+                "cbl_replication_activity_seconds_total",
+                &sample_labels,
+                self.time_in(activity).as_secs_f64(),
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cbl_replication_reconnects_total",
+            "Connections attempted by the replicator after the first one.",
+        );
+        write_sample(
+            &mut out,
+            "cbl_replication_reconnects_total",
+            labels,
+            self.reconnects,
+        );
+
+        out
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn write_metric_header(out: &mut String, name: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} counter\n",
+        name, help, name
+    ));
+}
+
+#[cfg(feature = "prometheus")]
+fn write_sample<T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: T,
+) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+            .collect();
+        out.push_str(&format!("{{{}}}", labels.join(",")));
+    }
+    out.push_str(&format!(" {}\n", value));
+}
+
+#[cfg(feature = "prometheus")]
+fn with_label<'a>(
+    labels: &[(&'a str, &'a str)],
+    label: (&'a str, &'a str),
+) -> Vec<(&'a str, &'a str)> {
+    let mut labels = labels.to_vec();
+    labels.push(label);
+    labels
+}
+
+#[cfg(feature = "prometheus")]
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(feature = "prometheus")]
+fn error_labels(code: ErrorCode) -> (&'static str, String) {
+    match code {
+        ErrorCode::CouchbaseLite(err) => ("CouchbaseLite", format!("{:?}", err)),
+        ErrorCode::POSIX(err) => ("POSIX", err.to_string()),
+        ErrorCode::SQLite(err) => ("SQLite", err.to_string()),
+        ErrorCode::Fleece(err) => ("Fleece", format!("{:?}", err)),
+        ErrorCode::Network(err) => ("Network", format!("{:?}", err)),
+        ErrorCode::WebSocket(err) => ("WebSocket", err.to_string()),
+    }
+}
@@ -0,0 +1,340 @@
+// Couchbase Lite schema migrations of document data
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use crate::{
+    ConcurrencyControl, CouchbaseLiteError, Database, Document, Error, ErrorCode, MutableDict,
+    Query, QueryLanguage, error::Result,
+};
+
+use std::fmt;
+
+/** The ID of the document storing the schema version, unless set with `Migrator::document_id`. */
+pub const DEFAULT_DOCUMENT_ID: &str = "schema_version";
+
+/** A migration step run on the whole database. */
+pub type DatabaseMigration = Box<dyn Fn(&mut Database) -> Result<()>>;
+
+/** A migration step run on the properties of each document matching a filter. */
+pub type DocumentMigration = Box<dyn Fn(&mut MutableDict) -> Result<()>>;
+
+enum MigrationAction {
+    Database(DatabaseMigration),
+    Documents {
+        filter: String,
+        transform: DocumentMigration,
+    },
+}
+
+struct MigrationStep {
+    version: u64,
+    action: MigrationAction,
+}
+
+/** What `Migrator::run` did. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /** The schema version before the migration. */
+    pub from_version: u64,
+    /** The schema version after the migration. */
+    pub to_version: u64,
+    /** The versions of the steps that ran, in order. */
+    pub applied: Vec<u64>,
+    /** The number of documents modified by per-document steps. */
+    pub documents_migrated: usize,
+}
+
+/** Brings the data of a database up to date with the current version of the app, by running
+numbered migration steps in order.
+
+The schema version of the database, initially 0, is stored in a document (`schema_version` by
+default) that is saved in the same transaction as each step, so a step is either entirely
+applied and recorded, or not at all. Per-document steps are run in batches of documents, one
+transaction per batch, recording the last document migrated: if the migration is interrupted,
+the next `run` resumes after it.
+
+The version document must not be replicated: a database pulling it from an already migrated
+peer would skip its own migration steps. `ReplicatorConfiguration::builder` excludes the
+default ID from replication; a different ID set with `document_id` must be given to the
+builder's `excluded_document_ids`, or to `ReplicatorConfiguration::excluded_document_ids`. */
+pub struct Migrator {
+    document_id: String,
+    batch_size: usize,
+    steps: Vec<MigrationStep>,
+}
+
+impl fmt::Debug for Migrator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Migrator")
+            .field("document_id", &self.document_id)
+            .field("batch_size", &self.batch_size)
+            .field(
+                "versions",
+                &self
+                    .steps
+                    .iter()
+                    .map(|step| step.version)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Default for Migrator {
+    fn default() -> Self {
+        Self {
+            document_id: DEFAULT_DOCUMENT_ID.to_string(),
+            batch_size: 500,
+            steps: vec![],
+        }
+    }
+}
+
+impl Migrator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Sets the ID of the document storing the schema version. */
+    #[must_use]
+    pub fn document_id(mut self, document_id: &str) -> Self {
+        self.document_id = document_id.to_string();
+        self
+    }
+
+    /** Sets the number of documents migrated per transaction by per-document steps. */
+    #[must_use]
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /** Adds a step bringing the database to schema `version`, run in a transaction. */
+    #[must_use]
+    pub fn step<F>(mut self, version: u64, migration: F) -> Self
+    where
+        F: Fn(&mut Database) -> Result<()> + 'static,
+    {
+        self.add_step(version, MigrationAction::Database(Box::new(migration)));
+        self
+    }
+
+    /** Adds a step bringing the database to schema `version` by transforming the properties of
+    the documents matching `filter`, a N1QL `WHERE` condition such as `type = 'user'`.
+    An empty filter matches every document. */
+    #[must_use]
+    pub fn transform<F>(mut self, version: u64, filter: &str, transform: F) -> Self
+    where
+        F: Fn(&mut MutableDict) -> Result<()> + 'static,
+    {
+        self.add_step(
+            version,
+            MigrationAction::Documents {
+                filter: filter.to_string(),
+                transform: Box::new(transform),
+            },
+        );
+        self
+    }
+
+    fn add_step(&mut self, version: u64, action: MigrationAction) {
+        self.steps.retain(|step| step.version != version);
+        self.steps.push(MigrationStep { version, action });
+        self.steps.sort_by_key(|step| step.version);
+    }
+
+    /** The schema version the steps bring a database to. */
+    pub fn latest_version(&self) -> u64 {
+        self.steps.last().map_or(0, |step| step.version)
+    }
+
+    /** Returns the schema version of a database: the version of the last step applied. */
+    pub fn current_version(&self, db: &Database) -> Result<u64> {
+        Ok(self.read_state(db)?.version)
+    }
+
+    /** Runs the steps whose version is higher than the database's schema version, in order.
+    A failing step stops the migration and returns its error; the steps before it, and the
+    batches of documents already migrated by it, stay applied. */
+    pub fn run(&self, db: &mut Database) -> Result<MigrationReport> {
+        let state = self.read_state(db)?;
+        let mut report = MigrationReport {
+            from_version: state.version,
+            to_version: state.version,
+            ..Default::default()
+        };
+
+        for step in self
+            .steps
+            .iter()
+            .filter(|step| step.version > state.version)
+        {
+            match &step.action {
+                MigrationAction::Database(migration) => {
+                    db.in_transaction(|db| {
+                        migration(db)?;
+                        self.write_state(db, step.version, None)
+                    })?;
+                }
+                MigrationAction::Documents { filter, transform } => {
+                    let after = match &state.resume {
+                        Some((version, after)) if *version == step.version => Some(after.clone()),
+                        _ => None,
+                    };
+                    report.documents_migrated += self.migrate_documents(
+                        db,
+                        report.to_version,
+                        step,
+                        filter,
+                        transform,
+                        after,
+                    )?;
+                }
+            }
+            report.applied.push(step.version);
+            report.to_version = step.version;
+        }
+        Ok(report)
+    }
+
+    /** Runs a per-document step, in batches. `applied_version` is the version of the last step
+    fully applied, which stays the schema version until the step is done. */
+    fn migrate_documents(
+        &self,
+        db: &mut Database,
+        applied_version: u64,
+        step: &MigrationStep,
+        filter: &str,
+        transform: &DocumentMigration,
+        mut after: Option<String>,
+    ) -> Result<usize> {
+        let mut conditions = vec![
+            "meta().id > $after".to_string(),
+            "meta().id != $state_id".to_string(),
+        ];
+        if !filter.trim().is_empty() {
+            conditions.push(format!("({})", filter));
+        }
+        let query = Query::new(
+            db,
+            QueryLanguage::N1QL,
+            &format!(
+                "SELECT meta().id FROM _ WHERE {} ORDER BY meta().id LIMIT {}",
+                conditions.join(" AND "),
+                self.batch_size
+            ),
+        )?;
+
+        query.bind("state_id", self.document_id.as_str())?;
+
+        let mut migrated = 0;
+        loop {
+            query.bind("after", after.as_deref().unwrap_or(""))?;
+            let ids: Vec<String> = query
+                .execute()?
+                .filter_map(|row| row.get(0).as_string().map(str::to_string))
+                .collect();
+            let done = ids.len() < self.batch_size;
+            let last = ids.last().cloned();
+
+            db.in_transaction(|db| {
+                for id in &ids {
+                    let mut doc = db.get_document(id)?;
+                    let mut properties = doc.mutable_properties();
+                    transform(&mut properties)?;
+                    db.save_document_with_concurency_control(
+                        &mut doc,
+                        ConcurrencyControl::FailOnConflict,
+                    )?;
+                }
+                if done {
+                    self.write_state(db, step.version, None)
+                } else {
+                    self.write_state(
+                        db,
+                        applied_version,
+                        last.as_deref().map(|id| (step.version, id)),
+                    )
+                }
+            })?;
+            migrated += ids.len();
+            if done {
+                return Ok(migrated);
+            }
+            after = last;
+        }
+    }
+
+    //////// STATE DOCUMENT:
+
+    fn read_state(&self, db: &Database) -> Result<MigrationState> {
+        let doc = match db.get_document(&self.document_id) {
+            Ok(doc) => doc,
+            Err(Error {
+                code: ErrorCode::CouchbaseLite(CouchbaseLiteError::NotFound),
+                ..
+            }) => return Ok(MigrationState::default()),
+            Err(err) => return Err(err),
+        };
+        let properties = doc.properties();
+        let resume = properties.get("resume").as_dict();
+        Ok(MigrationState {
+            version: properties.get("version").as_u64_or_0(),
+            resume: match (
+                resume.get("version").as_u64(),
+                resume.get("after").as_string(),
+            ) {
+                (Some(version), Some(after)) => Some((version, after.to_string())),
+                _ => None,
+            },
+        })
+    }
+
+    /** Records the schema version and, while a per-document step is in progress, the step's
+    version and the ID of the last document it migrated. */
+    fn write_state(
+        &self,
+        db: &mut Database,
+        version: u64,
+        resume: Option<(u64, &str)>,
+    ) -> Result<()> {
+        let mut doc = match db.get_document(&self.document_id) {
+            Ok(doc) => doc,
+            Err(Error {
+                code: ErrorCode::CouchbaseLite(CouchbaseLiteError::NotFound),
+                ..
+            }) => Document::new_with_id(&self.document_id),
+            Err(err) => return Err(err),
+        };
+        let mut properties = MutableDict::new();
+        properties.at("version").put_i64(version as i64);
+        if let Some((version, after)) = resume {
+            let mut progress = MutableDict::new();
+            progress.at("version").put_i64(version as i64);
+            progress.at("after").put_string(after);
+            properties.at("resume").put_value(&progress);
+        }
+        doc.set_properties(&properties);
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct MigrationState {
+    version: u64,
+    /** The version of the per-document step in progress, and the last document it migrated. */
+    resume: Option<(u64, String)>,
+}
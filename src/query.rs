@@ -16,8 +16,8 @@
 //
 
 use crate::{
-    Array, CblRef, CouchbaseLiteError, Database, Dict, Error, MutableDict, Result, Value, failure,
-    release, retain,
+    Array, CblRef, CouchbaseLiteError, Database, Dict, Error, Fleece, FleeceReference, MutableDict,
+    Result, ToFleece, Value, ValueType, failure, release, retain,
     slice::from_str,
     c_api::{
         CBLDatabase_CreateQuery, CBLError, CBLQuery, CBLQueryLanguage, CBLQuery_ColumnCount,
@@ -30,10 +30,20 @@ use crate::{
     Listener,
 };
 
-use std::{os::raw::c_uint};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::mem::ManuallyDrop;
+use std::os::raw::c_uint;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, channel};
 use ListenerToken;
 
 /** Query languages. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum QueryLanguage {
     JSON, // JSON query schema: github.com/couchbase/couchbase-lite-core/wiki/JSON-Query-Schema
     N1QL, // N1QL syntax: docs.couchbase.com/server/6.0/n1ql/n1ql-language-reference/index.html
@@ -49,7 +59,8 @@ unsafe extern "C" fn c_query_change_listener(
 ) {
     let callback = context as *const ChangeListener;
     let query = Query::wrap(query.cast::<CBLQuery>());
-    let token = ListenerToken::new(token);
+    // The token is only borrowed: dropping it would remove the listener.
+    let token = ManuallyDrop::new(ListenerToken::new(token));
 
     (*callback)(&query, &token);
 }
@@ -57,6 +68,9 @@ unsafe extern "C" fn c_query_change_listener(
 /** A compiled database query. */
 pub struct Query {
     cbl_ref: *mut CBLQuery,
+    parameter_names: Option<Vec<String>>,
+    // The values assigned through this object and its clones, which `bind` adds to
+    bound_parameters: Rc<RefCell<MutableDict>>,
 }
 
 impl CblRef for Query {
@@ -71,10 +85,14 @@ impl Query {
     This is fast, but not instantaneous. If you need to run the same query many times, keep the
     `Query` around instead of compiling it each time. If you need to run related queries
     with only some values different, create one query with placeholder parameter(s), and substitute
-    the desired value(s) with `set_parameters` before each time you run the query. */
+    the desired value(s) with `bind` or `set_parameters` before each time you run the query.
+
+    If the query string can't be parsed, the returned error locates the failure in `str`: see
+    `Error::query_parse_error`. */
     pub fn new(db: &Database, language: QueryLanguage, str: &str) -> Result<Self> {
         unsafe {
-            let mut pos: i32 = 0;
+            let parameter_names = referenced_parameters(language, str);
+            let mut pos: i32 = -1;
             let mut err = CBLError::default();
             let q = CBLDatabase_CreateQuery(
                 db.get_ref(),
@@ -84,17 +102,78 @@ impl Query {
                 &mut err,
             );
             if q.is_null() {
-                // TODO: Return the error pos somehow
-                return failure(err);
+                let mut error = Error::new(&err);
+                if pos >= 0 {
+                    error.query_position = Some(pos as usize);
+                }
+                return Err(error);
             }
 
-            Ok(Self { cbl_ref: q })
+            Ok(Self {
+                cbl_ref: q,
+                parameter_names: Some(parameter_names),
+                bound_parameters: Rc::new(RefCell::new(MutableDict::new())),
+            })
         }
     }
 
     pub(crate) fn wrap(cbl_ref: *mut CBLQuery) -> Self {
         Self {
             cbl_ref: unsafe { retain(cbl_ref) },
+            parameter_names: None,
+            bound_parameters: Rc::new(RefCell::new(MutableDict::new())),
+        }
+    }
+
+    /** Returns the names (without the `$`) of the parameters referenced by the query source,
+    or `None` if the source isn't known, as for a query received by a change listener. */
+    pub fn parameter_names(&self) -> Option<&[String]> {
+        self.parameter_names.as_deref()
+    }
+
+    /** Returns the names (without the `$`) of the parameters referenced by a query source, in
+    order of first use, without compiling it. A `$` in a string literal, a quoted identifier or
+    a comment doesn't start a parameter. */
+    pub fn referenced_parameters(language: QueryLanguage, str: &str) -> Vec<String> {
+        referenced_parameters(language, str)
+    }
+
+    /** Assigns a value to a single parameter of the query, keeping the values assigned by
+    `bind` and `set_parameters` through this object or its clones.
+    Fails with `InvalidParameter` if the query source doesn't reference `$name`. */
+    pub fn bind<T: ToFleece + ?Sized>(&self, name: &str, value: &T) -> Result<()> {
+        if let Some(names) = &self.parameter_names {
+            if !names.iter().any(|n| n == name) {
+                return Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+            }
+        }
+        let mut parameters = self.bound_parameters.borrow_mut();
+        value.to_fleece(parameters.at(name));
+        unsafe {
+            CBLQuery_SetParameters(self.get_ref(), parameters.get_ref());
+        }
+        Ok(())
+    }
+
+    /** Checks that every parameter referenced by the query source has been assigned a value,
+    and that no value was assigned to a parameter the query doesn't reference.
+    Fails with `InvalidParameter` otherwise. This is done by `execute` before running the query,
+    unless the query source isn't known (see `parameter_names`). */
+    pub fn validate_parameters(&self) -> Result<()> {
+        let names = match &self.parameter_names {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+        let parameters = self.parameters();
+        let all_assigned = names
+            .iter()
+            .all(|name| !parameters.get(name).is_type(ValueType::Undefined));
+        let none_unknown =
+            parameters.empty() || parameters.iter().all(|(key, _)| names.contains(&key));
+        if all_assigned && none_unknown {
+            Ok(())
+        } else {
+            Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter))
         }
     }
 
@@ -106,6 +185,7 @@ impl Query {
     e.g. `$PARAM` (N1QL) or `["$PARAM"]` (JSON). In this example, the `parameters` dictionary
     to this call should have a key `PARAM` that maps to the value of the parameter. */
     pub fn set_parameters(&self, parameters: &MutableDict) {
+        *self.bound_parameters.borrow_mut() = MutableDict::from_dict(&parameters.as_dict());
         unsafe {
             CBLQuery_SetParameters(self.get_ref(), parameters.get_ref());
         }
@@ -132,9 +212,24 @@ impl Query {
         }
     }
 
+    /** Returns the explanation of the query (see `explain`), parsed into a `QueryPlan`. */
+    pub fn plan(&self) -> Result<QueryPlan> {
+        self.explain()
+            .map(|explanation| QueryPlan::parse(&explanation))
+    }
+
     /** Runs the query, returning the results as a `ResultSet` object, which is an iterator
-    of `Row` objects, each of which has column values. */
+    of `Row` objects, each of which has column values.
+    Fails with `InvalidParameter` if the parameters don't match the query (see
+    `validate_parameters`); `execute_unchecked` runs it regardless. */
     pub fn execute(&self) -> Result<ResultSet> {
+        self.validate_parameters()?;
+        self.execute_unchecked()
+    }
+
+    /** Runs the query without checking its parameters first: the parameters without a value
+    evaluate to MISSING, as in Couchbase Lite. */
+    pub fn execute_unchecked(&self) -> Result<ResultSet> {
         unsafe {
             let mut err = CBLError::default();
             let r = CBLQuery_Execute(self.get_ref(), &mut err);
@@ -221,6 +316,8 @@ impl Clone for Query {
         unsafe {
             Self {
                 cbl_ref: retain(self.get_ref()),
+                parameter_names: self.parameter_names.clone(),
+                bound_parameters: self.bound_parameters.clone(),
             }
         }
     }
@@ -263,6 +360,237 @@ impl Drop for ResultSet {
     }
 }
 
+//////// EXPORT:
+
+/** How `ResultSet::write_csv` writes dictionaries and arrays. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NestedValues {
+    /** Write the whole value as JSON, in a single cell. */
+    Json,
+    /** Write each nested value in its own column, named by its dotted path, e.g. `address.city`
+    or `tags.0`. */
+    Flatten,
+}
+
+/** Options of `ResultSet::write_csv`. */
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub header: bool,
+    pub nested: NestedValues,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            header: true,
+            nested: NestedValues::Json,
+        }
+    }
+}
+
+impl ResultSet {
+    /** Returns the names of the columns of the results (see `Query::column_names`). */
+    pub fn column_names(&self) -> Vec<String> {
+        let query = Query::wrap(unsafe { CBLResultSet_GetQuery(self.get_ref()) });
+        query
+            .column_names()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /** Writes the remaining rows as JSON Lines: one JSON object per row, mapping column names to
+    values. Blobs are written as their metadata, never their contents.
+    Returns the number of rows written. */
+    pub fn write_json_lines<W: io::Write>(self, mut writer: W) -> io::Result<usize> {
+        let columns = self.column_names();
+        let mut count = 0;
+        for row in self {
+            let mut line = String::from("{");
+            for (i, column) in columns.iter().enumerate() {
+                let value = row.get(i as isize);
+                if value.is_type(ValueType::Undefined) {
+                    continue;
+                }
+                if line.len() > 1 {
+                    line.push(',');
+                }
+                line.push_str(&json_string(column));
+                line.push(':');
+                line.push_str(&export_json(&value));
+            }
+            line.push_str("}\n");
+            writer.write_all(line.as_bytes())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /** Writes the remaining rows as CSV, with a header line of column names if
+    `options.header` is set. Strings are written as-is, `NULL` and `MISSING` as empty cells,
+    and dictionaries and arrays according to `options.nested`. Blobs are written as their
+    metadata in JSON, never their contents. Flattening nested values reads all the rows before
+    writing, since the columns depend on them. Returns the number of rows written. */
+    pub fn write_csv<W: io::Write>(
+        mut self,
+        mut writer: W,
+        options: &CsvOptions,
+    ) -> io::Result<usize> {
+        let columns = self.column_names();
+        let rows: Vec<Vec<(String, String)>> = match options.nested {
+            NestedValues::Json => vec![],
+            NestedValues::Flatten => self
+                .by_ref()
+                .map(|row| {
+                    let mut cells = vec![];
+                    for (i, column) in columns.iter().enumerate() {
+                        flatten_value(column.clone(), &row.get(i as isize), &mut cells);
+                    }
+                    cells
+                })
+                .collect(),
+        };
+
+        let header: Vec<String> = match options.nested {
+            NestedValues::Json => columns,
+            NestedValues::Flatten => {
+                let mut header: Vec<String> = vec![];
+                for (name, _) in rows.iter().flatten() {
+                    if !header.contains(name) {
+                        header.push(name.clone());
+                    }
+                }
+                header
+            }
+        };
+        if options.header {
+            write_csv_line(
+                &mut writer,
+                header.iter().map(String::as_str),
+                options.delimiter,
+            )?;
+        }
+
+        match options.nested {
+            NestedValues::Json => {
+                let mut count = 0;
+                for row in self {
+                    let cells: Vec<String> = (0..header.len())
+                        .map(|i| csv_cell(&row.get(i as isize)))
+                        .collect();
+                    write_csv_line(
+                        &mut writer,
+                        cells.iter().map(String::as_str),
+                        options.delimiter,
+                    )?;
+                    count += 1;
+                }
+                Ok(count)
+            }
+            NestedValues::Flatten => {
+                for cells in &rows {
+                    let line = header.iter().map(|name| {
+                        cells
+                            .iter()
+                            .find(|(n, _)| n == name)
+                            .map_or("", |(_, cell)| cell.as_str())
+                    });
+                    write_csv_line(&mut writer, line, options.delimiter)?;
+                }
+                Ok(rows.len())
+            }
+        }
+    }
+}
+
+pub(crate) fn json_string(str: &str) -> String {
+    let mut json = String::with_capacity(str.len() + 2);
+    json.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// The JSON form of a value, with blobs reduced to their metadata.
+fn export_json(value: &Value) -> String {
+    match value.get_type() {
+        ValueType::Dict => {
+            let is_blob = value.is_blob();
+            let entries: Vec<String> = value
+                .as_dict()
+                .iter()
+                .filter(|(key, _)| !(is_blob && key == "data"))
+                .map(|(key, value)| format!("{}:{}", json_string(&key), export_json(&value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        ValueType::Array => {
+            let items: Vec<String> = value.as_array().iter().map(|v| export_json(&v)).collect();
+            format!("[{}]", items.join(","))
+        }
+        _ => value.to_json(),
+    }
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value.get_type() {
+        ValueType::Undefined | ValueType::Null => String::new(),
+        ValueType::String => value.as_string().unwrap_or_default().to_string(),
+        _ => export_json(value),
+    }
+}
+
+fn flatten_value(name: String, value: &Value, cells: &mut Vec<(String, String)>) {
+    match value.get_type() {
+        ValueType::Dict if !value.is_blob() => {
+            for (key, value) in value.as_dict().iter() {
+                flatten_value(format!("{}.{}", name, key), &value, cells);
+            }
+        }
+        ValueType::Array => {
+            for (i, value) in value.as_array().iter().enumerate() {
+                flatten_value(format!("{}.{}", name, i), &value, cells);
+            }
+        }
+        ValueType::Undefined => {}
+        _ => cells.push((name, csv_cell(value))),
+    }
+}
+
+fn write_csv_line<'a, W, I>(writer: &mut W, cells: I, delimiter: char) -> io::Result<()>
+where
+    W: io::Write,
+    I: Iterator<Item = &'a str>,
+{
+    let mut line = String::new();
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            line.push(delimiter);
+        }
+        if cell.contains([delimiter, '"', '\n', '\r']) {
+            line.push('"');
+            line.push_str(&cell.replace('"', "\"\""));
+            line.push('"');
+        } else {
+            line.push_str(cell);
+        }
+    }
+    line.push_str("\r\n");
+    writer.write_all(line.as_bytes())
+}
+
 //////// ROW:
 
 /** A single result row from a Query. */
@@ -323,3 +651,408 @@ impl Row {
         }
     }
 }
+
+//////// PREPARED QUERY CACHE:
+
+const DEFAULT_QUERY_CACHE_CAPACITY: usize = 32;
+
+// Incremented whenever an index is created or deleted in this process, through any connection.
+static INDEX_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/* Invalidates the prepared queries of every database, after an index change. */
+pub(crate) fn invalidate_prepared_queries() {
+    INDEX_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+type QueryCacheKey = (QueryLanguage, String);
+
+/** A least-recently-used cache of compiled queries, owned by a `Database`. */
+pub(crate) struct QueryCache {
+    capacity: usize,
+    // The value of `INDEX_GENERATION` when the entries were compiled:
+    generation: u64,
+    last_use: u64,
+    entries: HashMap<QueryCacheKey, (Query, u64)>,
+    // The keys of `entries`, by their last use:
+    by_use: BTreeMap<u64, QueryCacheKey>,
+}
+
+impl QueryCache {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+    }
+
+    // Removes the least recently used queries, down to `len` queries.
+    fn evict(&mut self, len: usize) {
+        while self.entries.len() > len {
+            match self.by_use.pop_first() {
+                Some((_, key)) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_QUERY_CACHE_CAPACITY,
+            generation: INDEX_GENERATION.load(Ordering::SeqCst),
+            last_use: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+}
+
+impl fmt::Debug for QueryCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueryCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.entries.len())
+            .finish()
+    }
+}
+
+impl Database {
+    /** Returns a compiled query for the given source, compiling it only if it isn't in the
+    database's cache of recently used queries. The returned `Query` shares its compiled form
+    with the cached one, including its parameters: assign them with `set_parameters` or `bind`
+    before each execution.
+
+    The cache is shared by the clones of this `Database`. It's cleared whenever an index is
+    created or deleted through `create_index`, `create_full_text_index` or `delete_index`, by
+    any connection of this process, since that can change how a query is best compiled. Index
+    changes made by another process aren't noticed: call `clear_prepared_queries` after them. */
+    pub fn prepared(&self, language: QueryLanguage, str: &str) -> Result<Query> {
+        let mut cache = self.query_cache.borrow_mut();
+        let generation = INDEX_GENERATION.load(Ordering::SeqCst);
+        if generation != cache.generation {
+            cache.clear();
+            cache.generation = generation;
+        }
+
+        cache.last_use += 1;
+        let last_use = cache.last_use;
+        let key = (language, str.to_string());
+        if let Some((query, used)) = cache.entries.get_mut(&key) {
+            let query = query.clone();
+            let previous_use = std::mem::replace(used, last_use);
+            cache.by_use.remove(&previous_use);
+            cache.by_use.insert(last_use, key);
+            return Ok(query);
+        }
+
+        let query = Query::new(self, language, str)?;
+        if cache.capacity > 0 {
+            let len = cache.capacity - 1;
+            cache.evict(len);
+            cache.entries.insert(key.clone(), (query.clone(), last_use));
+            cache.by_use.insert(last_use, key);
+        }
+        Ok(query)
+    }
+
+    /** Sets the maximum number of queries kept by `prepared` (32 by default); 0 disables
+    the cache. */
+    pub fn set_prepared_query_capacity(&self, capacity: usize) {
+        let mut cache = self.query_cache.borrow_mut();
+        cache.capacity = capacity;
+        cache.evict(capacity);
+    }
+
+    /** Removes all the queries from the cache of `prepared`. */
+    pub fn clear_prepared_queries(&self) {
+        self.query_cache.borrow_mut().clear();
+    }
+
+    /** Returns the number of queries in the cache of `prepared`. */
+    pub fn prepared_query_count(&self) -> usize {
+        let cache = self.query_cache.borrow();
+        if cache.generation == INDEX_GENERATION.load(Ordering::SeqCst) {
+            cache.entries.len()
+        } else {
+            0
+        }
+    }
+}
+
+//////// QUERY PLAN:
+
+/** A step of the SQLite query plan, as output by `EXPLAIN QUERY PLAN`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
+
+impl QueryPlanStep {
+    /** The name of the index this step searches or scans, if any. */
+    pub fn index(&self) -> Option<&str> {
+        ["USING INDEX ", "USING COVERING INDEX "]
+            .iter()
+            .find_map(|marker| self.detail.split(marker).nth(1))
+            .and_then(|rest| rest.split_whitespace().next())
+    }
+
+    /** True if this step reads every row of a table, instead of using an index. */
+    pub fn is_table_scan(&self) -> bool {
+        self.detail.starts_with("SCAN ")
+            && self.index().is_none()
+            && !self.detail.contains("VIRTUAL TABLE")
+    }
+}
+
+/** The parsed form of `Query::explain`: the SQLite translation of the query, the steps of
+the SQLite query plan, and the query in the JSON query schema. */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QueryPlan {
+    pub sql: String,
+    pub steps: Vec<QueryPlanStep>,
+    pub json: String,
+}
+
+impl QueryPlan {
+    /** Parses the output of `Query::explain`. Parts that can't be found are left empty. */
+    pub fn parse(explanation: &str) -> Self {
+        let mut sections = explanation.trim().splitn(3, "\n\n");
+        let sql = sections.next().unwrap_or_default().trim().to_string();
+        let steps = sections
+            .next()
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(4, '|');
+                Some(QueryPlanStep {
+                    id: fields.next()?.trim().parse().ok()?,
+                    parent: fields.next()?.trim().parse().ok()?,
+                    detail: fields.nth(1)?.trim().to_string(),
+                })
+            })
+            .collect();
+        let json = sections.next().unwrap_or_default().trim().to_string();
+        Self { sql, steps, json }
+    }
+
+    /** The names of the indexes used by the query, in the order of the plan. */
+    pub fn indexes(&self) -> Vec<&str> {
+        let mut indexes: Vec<&str> = vec![];
+        for index in self.steps.iter().filter_map(QueryPlanStep::index) {
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+        indexes
+    }
+
+    pub fn uses_index(&self, name: &str) -> bool {
+        self.indexes().contains(&name)
+    }
+
+    /** True if the query reads every row of a table, which an index could avoid. */
+    pub fn has_table_scan(&self) -> bool {
+        self.steps.iter().any(QueryPlanStep::is_table_scan)
+    }
+}
+
+//////// LIVE QUERY:
+
+/** The differences between two successive results of a live query. Rows are identified by the
+value of the key column given to `Query::live`; a row is "changed" if any of its columns
+changed. Keys are listed in the order of the results they come from. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResultSetDiff {
+    pub inserted: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ResultSetDiff {
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/** A notification of a live query: its new results, and how they differ from the previous
+ones. The first notification lists every row as inserted.
+
+The results are a copy of the query's `ResultSet`, as an array with a dictionary per row,
+mapping column names to values. */
+pub struct LiveQueryChange {
+    pub results: Fleece,
+    pub diff: ResultSetDiff,
+}
+
+/** A query kept running in the background, created by `Query::live`. Its results are delivered
+through the `changes` channel every time they change.
+
+# Lifetime
+
+The query stops being live when this object is dropped. */
+pub struct LiveQuery {
+    _listener: Listener<ChangeListener>,
+    changes: Receiver<LiveQueryChange>,
+}
+
+impl LiveQuery {
+    /** The channel receiving the new results of the query. */
+    pub const fn changes(&self) -> &Receiver<LiveQueryChange> {
+        &self.changes
+    }
+}
+
+impl Query {
+    /** Turns the query into a "live query", which sends its results, along with their
+    differences from the previous results, every time they change. Rows are identified by the
+    value of the column named `key_column`, typically `meta().id` given a name with `AS`.
+
+    Fails with `InvalidParameter` if the query has no column named `key_column`. */
+    pub fn live(&mut self, key_column: &str) -> Result<LiveQuery> {
+        let key_index = self
+            .column_names()
+            .iter()
+            .position(|name| *name == key_column)
+            .ok_or_else(|| Error::cbl_error(CouchbaseLiteError::InvalidParameter))?
+            as isize;
+
+        // The key and JSON content of each row of the previous results:
+        let previous: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+        let (sender, receiver) = channel();
+        let listener = self.add_listener(Box::new(move |query, token| {
+            let rows: Vec<(String, String)> = match query.copy_current_results(token) {
+                Ok(results) => results
+                    .map(|row| (row_key(&row.get(key_index)), row.as_dict().to_json()))
+                    .collect(),
+                Err(_) => return,
+            };
+            // A result set can only be read once, so the rows are sent as a copy.
+            let json: Vec<&str> = rows.iter().map(|(_, json)| json.as_str()).collect();
+            let results = match Fleece::parse_json(&format!("[{}]", json.join(","))) {
+                Ok(results) => results,
+                Err(_) => return,
+            };
+            let mut previous = previous.lock().unwrap();
+            let diff = diff_rows(&previous, &rows);
+            *previous = rows;
+            let _ = sender.send(LiveQueryChange { results, diff });
+        }));
+        Ok(LiveQuery {
+            _listener: listener,
+            changes: receiver,
+        })
+    }
+}
+
+fn row_key(value: &Value) -> String {
+    value
+        .as_string()
+        .map_or_else(|| value.to_json(), ToString::to_string)
+}
+
+fn diff_rows(old: &[(String, String)], new: &[(String, String)]) -> ResultSetDiff {
+    let old_rows: HashMap<&str, &str> = old.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let new_keys: HashSet<&str> = new.iter().map(|(k, _)| k.as_str()).collect();
+    let mut diff = ResultSetDiff::default();
+    for (key, json) in new {
+        match old_rows.get(key.as_str()) {
+            None => diff.inserted.push(key.clone()),
+            Some(old_json) if *old_json != json => diff.changed.push(key.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = old
+        .iter()
+        .filter(|(key, _)| !new_keys.contains(key.as_str()))
+        .map(|(key, _)| key.clone())
+        .collect();
+    diff
+}
+
+//////// PARAMETERS:
+
+// Collects the names of the `$parameters` referenced by a query source, in order of first use.
+fn referenced_parameters(language: QueryLanguage, str: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    match language {
+        QueryLanguage::N1QL => n1ql_parameters(str, &mut names),
+        QueryLanguage::JSON => {
+            if let Ok(json) = Fleece::parse_json(str) {
+                json_parameters(&json.root(), &mut names);
+            }
+        }
+    }
+    names
+}
+
+fn add_parameter(names: &mut Vec<String>, name: &str) {
+    if !name.is_empty() && !names.iter().any(|n| n == name) {
+        names.push(name.to_string());
+    }
+}
+
+// Tokenizes N1QL just enough to find the parameters: a `$` in a string literal, a quoted
+// identifier or a comment is not a parameter.
+fn n1ql_parameters(str: &str, names: &mut Vec<String>) {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut chars = str.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // Quotes are escaped by doubling them, or with a backslash in strings.
+            '\'' | '"' | '`' => {
+                while let Some((_, d)) = chars.next() {
+                    if d == '\\' && c != '`' {
+                        chars.next();
+                    } else if d == c && chars.next_if(|&(_, e)| e == c).is_none() {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.next_if(|&(_, d)| d == '-').is_some() => {
+                for (_, d) in chars.by_ref() {
+                    if d == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.next_if(|&(_, d)| d == '*').is_some() => {
+                while let Some((_, d)) = chars.next() {
+                    if d == '*' && chars.next_if(|&(_, e)| e == '/').is_some() {
+                        break;
+                    }
+                }
+            }
+            '$' => {
+                let start = i + 1;
+                let mut end = start;
+                while let Some((j, d)) = chars.next_if(|&(_, d)| is_name_char(d)) {
+                    end = j + d.len_utf8();
+                }
+                add_parameter(names, &str[start..end]);
+            }
+            _ => {}
+        }
+    }
+}
+
+// In the JSON schema, a parameter is an array whose first item is a string starting with `$`.
+fn json_parameters(value: &Value, names: &mut Vec<String>) {
+    match value.get_type() {
+        ValueType::Array => {
+            let array = value.as_array();
+            if let Some(name) = array.get(0).as_string().and_then(|s| s.strip_prefix('$')) {
+                add_parameter(names, name);
+            }
+            for item in array.iter() {
+                json_parameters(&item, names);
+            }
+        }
+        ValueType::Dict => {
+            for (_, item) in value.as_dict().iter() {
+                json_parameters(&item, names);
+            }
+        }
+        _ => {}
+    }
+}
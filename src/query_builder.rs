@@ -0,0 +1,1339 @@
+// Couchbase Lite query builder
+//
+// Copyright (c) 2020 Couchbase, Inc All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// A typed builder for queries, which can be rendered either as N1QL or in the JSON query schema,
+// so that queries can be assembled without formatting (and escaping) raw query strings.
+//
+//     select([prop("name"), meta_id()])
+//         .from_db()
+//         .where_(prop("type").eq(param("t")))
+//         .order_by([prop("name").ascending()])
+//         .limit(10)
+//         .build(&db, QueryLanguage::N1QL)
+
+use crate::{
+    CouchbaseLiteError, Database, Error, Fleece, MutableArray, MutableDict, Query, QueryLanguage,
+    Result, Slot, ToFleece, fleece::FleeceReference,
+};
+
+use std::fmt::Write;
+
+//////// EXPRESSIONS:
+
+/** A literal value in a query. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Missing,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+/** The properties of a document's metadata, accessed with `meta()` in N1QL. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaProperty {
+    Id,
+    Sequence,
+    RevisionId,
+    Deleted,
+    Expiration,
+}
+
+/** Operators taking a single operand. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Not,
+    Negate,
+    IsNull,
+    IsNotNull,
+    IsMissing,
+    IsNotMissing,
+    IsValued,
+    IsNotValued,
+}
+
+/** Operators taking two operands. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Like,
+    Concat,
+    Is,
+    IsNot,
+}
+
+/** The array quantifiers: `ANY`, `EVERY`, and `ANY AND EVERY`. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantifier {
+    Any,
+    Every,
+    AnyAndEvery,
+}
+
+/** An expression in a query. Expressions are created with the free functions of this module
+(`prop`, `meta_id`, `param`, `literal`, `func`, `any`...) and combined with the operator
+methods (`eq`, `and`, `like`...). */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Property {
+        path: String,
+        from: Option<String>,
+    },
+    Meta {
+        property: MetaProperty,
+        from: Option<String>,
+    },
+    Parameter(String),
+    Variable(String),
+    Literal(Literal),
+    Array(Vec<Expression>),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+    Between(Box<Expression>, Box<Expression>, Box<Expression>),
+    In(Box<Expression>, Vec<Expression>),
+    Function(String, Vec<Expression>),
+    Quantified {
+        quantifier: Quantifier,
+        variable: String,
+        array: Box<Expression>,
+        satisfies: Box<Expression>,
+    },
+}
+
+/** A property of the documents being queried, given as a dotted key path such as
+`address.city` or `items[0].price`. A key containing a `.` must escape it as `\.`. */
+pub fn prop(path: &str) -> Expression {
+    Expression::Property {
+        path: path.to_string(),
+        from: None,
+    }
+}
+
+/** A metadata property of the documents being queried. */
+pub fn meta(property: MetaProperty) -> Expression {
+    Expression::Meta {
+        property,
+        from: None,
+    }
+}
+
+/** The document ID, `meta().id` in N1QL. */
+pub fn meta_id() -> Expression {
+    meta(MetaProperty::Id)
+}
+
+/** The document's sequence, `meta().sequence` in N1QL. */
+pub fn meta_sequence() -> Expression {
+    meta(MetaProperty::Sequence)
+}
+
+/** The document's revision ID, `meta().revisionID` in N1QL. */
+pub fn meta_revision_id() -> Expression {
+    meta(MetaProperty::RevisionId)
+}
+
+/** Whether the document is deleted, `meta().deleted` in N1QL. */
+pub fn meta_deleted() -> Expression {
+    meta(MetaProperty::Deleted)
+}
+
+/** The document's expiration timestamp, `meta().expiration` in N1QL. */
+pub fn meta_expiration() -> Expression {
+    meta(MetaProperty::Expiration)
+}
+
+/** A query parameter, whose value is assigned with `Query::set_parameters`. The name is given
+without the leading `$`. Panics if the name isn't an identifier (see `is_identifier`). */
+pub fn param(name: &str) -> Expression {
+    assert_identifier("parameter", name);
+    Expression::Parameter(name.to_string())
+}
+
+/** A variable bound by an array quantifier (`any`, `every`, `any_and_every`), optionally
+followed by a key path into the array item, such as `item.price`. Panics if the variable name
+isn't an identifier (see `is_identifier`). */
+pub fn var(path: &str) -> Expression {
+    assert_identifier("variable", &path_components(path)[0]);
+    Expression::Variable(path.to_string())
+}
+
+/** A literal value. */
+pub fn literal<T: Into<Literal>>(value: T) -> Expression {
+    Expression::Literal(value.into())
+}
+
+/** The literal `NULL`. */
+pub fn null() -> Expression {
+    Expression::Literal(Literal::Null)
+}
+
+/** The literal `MISSING`. */
+pub fn missing() -> Expression {
+    Expression::Literal(Literal::Missing)
+}
+
+/** An array literal. */
+pub fn array<I>(items: I) -> Expression
+where
+    I: IntoIterator,
+    I::Item: Into<Expression>,
+{
+    Expression::Array(items.into_iter().map(Into::into).collect())
+}
+
+/** A call to a query function, given its name without the parentheses, e.g. `lower`. Panics if
+the name isn't an identifier (see `is_identifier`). */
+pub fn func<I>(name: &str, args: I) -> Expression
+where
+    I: IntoIterator,
+    I::Item: Into<Expression>,
+{
+    assert_identifier("function", name);
+    Expression::Function(
+        name.to_uppercase(),
+        args.into_iter().map(Into::into).collect(),
+    )
+}
+
+/** The negation of a boolean expression. */
+pub fn not(expression: Expression) -> Expression {
+    Expression::Unary(UnaryOperator::Not, Box::new(expression))
+}
+
+/** `ANY variable IN array SATISFIES condition END`: true if any item of the array satisfies
+the condition. The condition refers to the item through `var(variable)`. */
+pub fn any(variable: &str, array: Expression, satisfies: Expression) -> Expression {
+    quantified(Quantifier::Any, variable, array, satisfies)
+}
+
+/** `EVERY variable IN array SATISFIES condition END`: true if every item of the array
+satisfies the condition, or if the array is empty. */
+pub fn every(variable: &str, array: Expression, satisfies: Expression) -> Expression {
+    quantified(Quantifier::Every, variable, array, satisfies)
+}
+
+/** `ANY AND EVERY variable IN array SATISFIES condition END`: true if the array is not empty
+and every item satisfies the condition. */
+pub fn any_and_every(variable: &str, array: Expression, satisfies: Expression) -> Expression {
+    quantified(Quantifier::AnyAndEvery, variable, array, satisfies)
+}
+
+fn quantified(
+    quantifier: Quantifier,
+    variable: &str,
+    array: Expression,
+    satisfies: Expression,
+) -> Expression {
+    assert_identifier("variable", variable);
+    Expression::Quantified {
+        quantifier,
+        variable: variable.to_string(),
+        array: Box::new(array),
+        satisfies: Box::new(satisfies),
+    }
+}
+
+/** Returns true if `name` can be used as the name of a function, parameter or variable, which
+are written into queries as they are: an ASCII letter or `_`, followed by ASCII letters, digits
+and `_`. */
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn assert_identifier(kind: &str, name: &str) {
+    assert!(is_identifier(name), "invalid {} name {:?}", kind, name);
+}
+
+// Whether the names of the expression are identifiers, and its numbers can be written in a
+// query, for expressions built without the functions of this module.
+fn is_valid_expression(expression: &Expression) -> bool {
+    match expression {
+        Expression::Property { .. } | Expression::Meta { .. } => true,
+        Expression::Parameter(name) => is_identifier(name),
+        Expression::Variable(path) => is_identifier(&path_components(path)[0]),
+        Expression::Literal(Literal::Float(f)) => f.is_finite(),
+        Expression::Literal(_) => true,
+        Expression::Array(items) => items.iter().all(is_valid_expression),
+        Expression::Unary(_, operand) => is_valid_expression(operand),
+        Expression::Binary(_, lhs, rhs) => is_valid_expression(lhs) && is_valid_expression(rhs),
+        Expression::Between(value, low, high) => {
+            is_valid_expression(value) && is_valid_expression(low) && is_valid_expression(high)
+        }
+        Expression::In(value, values) => {
+            is_valid_expression(value) && values.iter().all(is_valid_expression)
+        }
+        Expression::Function(name, args) => {
+            is_identifier(name) && args.iter().all(is_valid_expression)
+        }
+        Expression::Quantified {
+            variable,
+            array,
+            satisfies,
+            ..
+        } => {
+            is_identifier(variable) && is_valid_expression(array) && is_valid_expression(satisfies)
+        }
+    }
+}
+
+// Aggregate and common functions:
+
+pub fn count(expression: Expression) -> Expression {
+    func("count", [expression])
+}
+
+pub fn sum(expression: Expression) -> Expression {
+    func("sum", [expression])
+}
+
+pub fn avg(expression: Expression) -> Expression {
+    func("avg", [expression])
+}
+
+pub fn min(expression: Expression) -> Expression {
+    func("min", [expression])
+}
+
+pub fn max(expression: Expression) -> Expression {
+    func("max", [expression])
+}
+
+pub fn lower(expression: Expression) -> Expression {
+    func("lower", [expression])
+}
+
+pub fn upper(expression: Expression) -> Expression {
+    func("upper", [expression])
+}
+
+pub fn length(expression: Expression) -> Expression {
+    func("length", [expression])
+}
+
+pub fn array_length(array: Expression) -> Expression {
+    func("array_length", [array])
+}
+
+pub fn array_contains(array: Expression, value: Expression) -> Expression {
+    func("array_contains", [array, value])
+}
+
+impl Expression {
+    /** Qualifies a property or `meta()` expression with the alias of its data source, as given
+    to `QueryBuilder::from_db_as`. Other expressions are returned unchanged. */
+    pub fn of(self, alias: &str) -> Self {
+        match self {
+            Self::Property { path, .. } => Self::Property {
+                path,
+                from: Some(alias.to_string()),
+            },
+            Self::Meta { property, .. } => Self::Meta {
+                property,
+                from: Some(alias.to_string()),
+            },
+            other => other,
+        }
+    }
+
+    fn unary(self, op: UnaryOperator) -> Self {
+        Self::Unary(op, Box::new(self))
+    }
+
+    fn binary<E: Into<Self>>(self, op: BinaryOperator, other: E) -> Self {
+        Self::Binary(op, Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn eq<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Equal, other)
+    }
+    pub fn not_eq<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::NotEqual, other)
+    }
+    pub fn lt<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Less, other)
+    }
+    pub fn le<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::LessOrEqual, other)
+    }
+    pub fn gt<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Greater, other)
+    }
+    pub fn ge<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::GreaterOrEqual, other)
+    }
+    pub fn and<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::And, other)
+    }
+    pub fn or<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Or, other)
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn add<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Add, other)
+    }
+    pub fn subtract<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Subtract, other)
+    }
+    pub fn multiply<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Multiply, other)
+    }
+    pub fn divide<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Divide, other)
+    }
+    pub fn modulo<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Modulo, other)
+    }
+    pub fn like<E: Into<Self>>(self, pattern: E) -> Self {
+        self.binary(BinaryOperator::Like, pattern)
+    }
+    pub fn concat<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Concat, other)
+    }
+    pub fn is<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::Is, other)
+    }
+    pub fn is_not<E: Into<Self>>(self, other: E) -> Self {
+        self.binary(BinaryOperator::IsNot, other)
+    }
+
+    pub fn negate(self) -> Self {
+        self.unary(UnaryOperator::Negate)
+    }
+    pub fn is_null(self) -> Self {
+        self.unary(UnaryOperator::IsNull)
+    }
+    pub fn is_not_null(self) -> Self {
+        self.unary(UnaryOperator::IsNotNull)
+    }
+    pub fn is_missing(self) -> Self {
+        self.unary(UnaryOperator::IsMissing)
+    }
+    pub fn is_not_missing(self) -> Self {
+        self.unary(UnaryOperator::IsNotMissing)
+    }
+    /** True if the value is neither `NULL` nor `MISSING`. */
+    pub fn is_valued(self) -> Self {
+        self.unary(UnaryOperator::IsValued)
+    }
+    pub fn is_not_valued(self) -> Self {
+        self.unary(UnaryOperator::IsNotValued)
+    }
+
+    pub fn between<L: Into<Self>, H: Into<Self>>(self, low: L, high: H) -> Self {
+        Self::Between(Box::new(self), Box::new(low.into()), Box::new(high.into()))
+    }
+
+    pub fn in_<I>(self, values: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Self>,
+    {
+        Self::In(Box::new(self), values.into_iter().map(Into::into).collect())
+    }
+
+    /** Names the expression in a `select`, making it the column name in the results. */
+    pub fn as_(self, alias: &str) -> SelectResult {
+        SelectResult::Expression(self, Some(alias.to_string()))
+    }
+
+    pub fn ascending(self) -> Ordering {
+        Ordering::Ascending(self)
+    }
+
+    pub fn descending(self) -> Ordering {
+        Ordering::Descending(self)
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i32> for Literal {
+    fn from(value: i32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<i64> for Literal {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<u32> for Literal {
+    fn from(value: u32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<f64> for Literal {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for Literal {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl<T: Into<Literal>> From<T> for Expression {
+    fn from(value: T) -> Self {
+        Self::Literal(value.into())
+    }
+}
+
+//////// SELECT RESULTS AND ORDERINGS:
+
+/** A column of the query results. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectResult {
+    /** All the properties of the documents of a data source, `*` (or `alias.*`) in N1QL. */
+    All(Option<String>),
+    Expression(Expression, Option<String>),
+}
+
+impl SelectResult {
+    /** Selects all the properties of the queried documents. */
+    pub fn all() -> Self {
+        Self::All(None)
+    }
+
+    /** Selects all the properties of the documents of the data source with the given alias. */
+    pub fn all_of(alias: &str) -> Self {
+        Self::All(Some(alias.to_string()))
+    }
+}
+
+impl From<Expression> for SelectResult {
+    fn from(expression: Expression) -> Self {
+        Self::Expression(expression, None)
+    }
+}
+
+/** A sort key of an `order_by` clause. An expression used directly sorts in ascending order. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ordering {
+    Ascending(Expression),
+    Descending(Expression),
+}
+
+impl From<Expression> for Ordering {
+    fn from(expression: Expression) -> Self {
+        Self::Ascending(expression)
+    }
+}
+
+//////// QUERY BUILDER:
+
+/** A query under construction. Start one with `select` or `select_distinct`, then render it
+with `to_n1ql` / `to_json`, or compile it directly with `build`. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryBuilder {
+    distinct: bool,
+    results: Vec<SelectResult>,
+    from: Option<String>,
+    where_: Option<Expression>,
+    group_by: Vec<Expression>,
+    having: Option<Expression>,
+    order_by: Vec<Ordering>,
+    limit: Option<Expression>,
+    offset: Option<Expression>,
+}
+
+/** Starts a query returning the given columns. */
+pub fn select<I>(results: I) -> QueryBuilder
+where
+    I: IntoIterator,
+    I::Item: Into<SelectResult>,
+{
+    QueryBuilder {
+        distinct: false,
+        results: results.into_iter().map(Into::into).collect(),
+        from: None,
+        where_: None,
+        group_by: vec![],
+        having: None,
+        order_by: vec![],
+        limit: None,
+        offset: None,
+    }
+}
+
+/** Starts a query returning the given columns, without duplicate rows. */
+pub fn select_distinct<I>(results: I) -> QueryBuilder
+where
+    I: IntoIterator,
+    I::Item: Into<SelectResult>,
+{
+    QueryBuilder {
+        distinct: true,
+        ..select(results)
+    }
+}
+
+impl QueryBuilder {
+    /** Queries the documents of the database. This is the default data source. */
+    #[must_use]
+    pub fn from_db(mut self) -> Self {
+        self.from = None;
+        self
+    }
+
+    /** Queries the documents of the database, under an alias that properties and `meta()`
+    expressions can be qualified with (see `Expression::of`). */
+    #[must_use]
+    pub fn from_db_as(mut self, alias: &str) -> Self {
+        self.from = Some(alias.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn where_(mut self, condition: Expression) -> Self {
+        self.where_ = Some(condition);
+        self
+    }
+
+    #[must_use]
+    pub fn group_by<I>(mut self, expressions: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Expression>,
+    {
+        self.group_by = expressions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    #[must_use]
+    pub fn having(mut self, condition: Expression) -> Self {
+        self.having = Some(condition);
+        self
+    }
+
+    #[must_use]
+    pub fn order_by<I>(mut self, orderings: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Ordering>,
+    {
+        self.order_by = orderings.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /** Limits the number of rows, given as a number or as an expression such as a `param`. */
+    #[must_use]
+    pub fn limit<E: Into<Expression>>(mut self, limit: E) -> Self {
+        self.limit = Some(limit.into());
+        self
+    }
+
+    /** Skips rows before returning results, given as a number or as an expression. */
+    #[must_use]
+    pub fn offset<E: Into<Expression>>(mut self, offset: E) -> Self {
+        self.offset = Some(offset.into());
+        self
+    }
+
+    /** Checks that the names of the functions, parameters and variables of the query are
+    identifiers, and that its numbers are finite, failing with `InvalidParameter` otherwise.
+    This only matters for expressions built directly from `Expression`'s variants, since the
+    functions of this module check the names, and `NaN` or infinite numbers can't be written in
+    a query. */
+    pub fn validate(&self) -> Result<()> {
+        let results = self.results.iter().filter_map(|result| match result {
+            SelectResult::All(_) => None,
+            SelectResult::Expression(expression, _) => Some(expression),
+        });
+        let order_by = self.order_by.iter().map(|ordering| match ordering {
+            Ordering::Ascending(expression) | Ordering::Descending(expression) => expression,
+        });
+        let valid = results
+            .chain(self.where_.iter())
+            .chain(self.group_by.iter())
+            .chain(self.having.iter())
+            .chain(order_by)
+            .chain(self.limit.iter())
+            .chain(self.offset.iter())
+            .all(is_valid_expression);
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter))
+        }
+    }
+
+    /** Renders the query as a N1QL string, after checking it with `validate`. */
+    pub fn to_n1ql(&self) -> Result<String> {
+        self.validate()?;
+        let mut n1ql = String::from("SELECT ");
+        if self.distinct {
+            n1ql.push_str("DISTINCT ");
+        }
+        let results: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| match result {
+                SelectResult::All(None) => "*".to_string(),
+                SelectResult::All(Some(alias)) => format!("{}.*", n1ql_identifier(alias)),
+                SelectResult::Expression(expression, None) => n1ql_expression(expression),
+                SelectResult::Expression(expression, Some(alias)) => format!(
+                    "{} AS {}",
+                    n1ql_expression(expression),
+                    n1ql_identifier(alias)
+                ),
+            })
+            .collect();
+        n1ql.push_str(&results.join(", "));
+
+        n1ql.push_str(" FROM _");
+        if let Some(alias) = &self.from {
+            let _ = write!(n1ql, " AS {}", n1ql_identifier(alias));
+        }
+        if let Some(condition) = &self.where_ {
+            let _ = write!(n1ql, " WHERE {}", n1ql_expression(condition));
+        }
+        if !self.group_by.is_empty() {
+            let group_by: Vec<String> = self.group_by.iter().map(n1ql_expression).collect();
+            let _ = write!(n1ql, " GROUP BY {}", group_by.join(", "));
+        }
+        if let Some(condition) = &self.having {
+            let _ = write!(n1ql, " HAVING {}", n1ql_expression(condition));
+        }
+        if !self.order_by.is_empty() {
+            let order_by: Vec<String> = self
+                .order_by
+                .iter()
+                .map(|ordering| match ordering {
+                    Ordering::Ascending(expression) => n1ql_expression(expression),
+                    Ordering::Descending(expression) => {
+                        format!("{} DESC", n1ql_expression(expression))
+                    }
+                })
+                .collect();
+            let _ = write!(n1ql, " ORDER BY {}", order_by.join(", "));
+        }
+        if let Some(limit) = &self.limit {
+            let _ = write!(n1ql, " LIMIT {}", n1ql_expression(limit));
+        }
+        if let Some(offset) = &self.offset {
+            let _ = write!(n1ql, " OFFSET {}", n1ql_expression(offset));
+        }
+        Ok(n1ql)
+    }
+
+    /** Renders the query in the JSON query schema, after checking it with `validate`. */
+    pub fn to_json(&self) -> Result<String> {
+        self.validate()?;
+        let mut query = MutableDict::new();
+        if self.distinct {
+            query.at("DISTINCT").put_bool(true);
+        }
+
+        let mut what = MutableArray::new();
+        for result in &self.results {
+            match result {
+                SelectResult::All(alias) => {
+                    let mut all = MutableArray::new();
+                    all.append().put_string(
+                        alias
+                            .as_ref()
+                            .map_or(".".to_string(), |a| format!(".{}.", a)),
+                    );
+                    what.append().put_value(&all);
+                }
+                SelectResult::Expression(expression, None) => {
+                    json_expression(expression, what.append());
+                }
+                SelectResult::Expression(expression, Some(alias)) => {
+                    let mut named = MutableArray::new();
+                    named.append().put_string("AS");
+                    json_expression(expression, named.append());
+                    named.append().put_string(alias);
+                    what.append().put_value(&named);
+                }
+            }
+        }
+        query.at("WHAT").put_value(&what);
+
+        if let Some(alias) = &self.from {
+            let mut source = MutableDict::new();
+            source.at("AS").put_string(alias);
+            let mut from = MutableArray::new();
+            from.append().put_value(&source);
+            query.at("FROM").put_value(&from);
+        }
+        if let Some(condition) = &self.where_ {
+            json_expression(condition, query.at("WHERE"));
+        }
+        if !self.group_by.is_empty() {
+            let mut group_by = MutableArray::new();
+            for expression in &self.group_by {
+                json_expression(expression, group_by.append());
+            }
+            query.at("GROUP_BY").put_value(&group_by);
+        }
+        if let Some(condition) = &self.having {
+            json_expression(condition, query.at("HAVING"));
+        }
+        if !self.order_by.is_empty() {
+            let mut order_by = MutableArray::new();
+            for ordering in &self.order_by {
+                match ordering {
+                    Ordering::Ascending(expression) => {
+                        json_expression(expression, order_by.append());
+                    }
+                    Ordering::Descending(expression) => {
+                        let mut descending = MutableArray::new();
+                        descending.append().put_string("DESC");
+                        json_expression(expression, descending.append());
+                        order_by.append().put_value(&descending);
+                    }
+                }
+            }
+            query.at("ORDER_BY").put_value(&order_by);
+        }
+        if let Some(limit) = &self.limit {
+            json_expression(limit, query.at("LIMIT"));
+        }
+        if let Some(offset) = &self.offset {
+            json_expression(offset, query.at("OFFSET"));
+        }
+        Ok(query.to_json())
+    }
+
+    /** Compiles the query in the given language. */
+    pub fn build(&self, db: &Database, language: QueryLanguage) -> Result<Query> {
+        let str = match language {
+            QueryLanguage::N1QL => self.to_n1ql()?,
+            QueryLanguage::JSON => self.to_json()?,
+        };
+        Query::new(db, language, &str)
+    }
+
+    /** Compiles the query for keyset pagination, ordered by `key`. See `PagedQuery`. */
+    pub fn paginate(
+        &self,
+        db: &Database,
+        language: QueryLanguage,
+        key: Expression,
+        page_size: u32,
+    ) -> Result<PagedQuery> {
+        PagedQuery::new(db, language, self, key, page_size)
+    }
+}
+
+//////// PAGED QUERY:
+
+const PAGE_KEY_COLUMN: &str = "_page_key";
+const PAGE_ID_COLUMN: &str = "_page_id";
+const PAGE_KEY_PARAMETER: &str = "_page_key";
+const PAGE_ID_PARAMETER: &str = "_page_id";
+
+/** A page of results of a `PagedQuery`. Each row is a dictionary mapping column names
+to values. */
+pub struct Page {
+    pub rows: Vec<MutableDict>,
+    /** The token to pass to `PagedQuery::page` to get the next page, or `None` if this is the
+    last page. */
+    pub next: Option<String>,
+}
+
+/** A query returning its results a page at a time, using keyset pagination: instead of skipping
+rows with `OFFSET`, which gets slower with each page and skips or repeats rows when documents
+are added or removed meanwhile, each page starts after the last row of the previous one.
+
+The rows are ordered by a key expression, then by document ID to break ties; the key should
+be valued for every row, since rows where it is `NULL` or `MISSING` only appear on the first
+page. The position of a page is given by an opaque continuation token.
+
+Only queries made with a `QueryBuilder` can be paged, since their ordering and conditions are
+rewritten; the column and parameter names `_page_key` and `_page_id` are reserved. */
+pub struct PagedQuery {
+    first: Query,
+    next: Query,
+    page_size: u32,
+}
+
+impl PagedQuery {
+    /** Compiles the paged form of `builder`, whose ordering, limit and offset are replaced.
+    Fails with `InvalidParameter` if `page_size` is zero, or if `builder` uses a reserved
+    column or parameter name. */
+    pub fn new(
+        db: &Database,
+        language: QueryLanguage,
+        builder: &QueryBuilder,
+        key: Expression,
+        page_size: u32,
+    ) -> Result<Self> {
+        if page_size == 0
+            || Query::referenced_parameters(QueryLanguage::N1QL, &builder.to_n1ql()?)
+                .iter()
+                .any(|name| is_reserved_page_name(name))
+        {
+            return Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+        }
+
+        let id = match &builder.from {
+            Some(alias) => meta_id().of(alias),
+            None => meta_id(),
+        };
+        let mut paged = builder.clone();
+        paged.results.push(key.clone().as_(PAGE_KEY_COLUMN));
+        paged.results.push(id.clone().as_(PAGE_ID_COLUMN));
+        paged.order_by = vec![key.clone().ascending(), id.clone().ascending()];
+        // One more row than a page is fetched, to tell whether there is a next page
+        paged.limit = Some((i64::from(page_size) + 1).into());
+        paged.offset = None;
+        let first = paged.build(db, language)?;
+        let columns = first.column_names();
+        if columns[..columns.len() - 2]
+            .iter()
+            .any(|name| is_reserved_page_name(name))
+        {
+            return Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+        }
+
+        let after_cursor = key.clone().gt(param(PAGE_KEY_PARAMETER)).or(key
+            .eq(param(PAGE_KEY_PARAMETER))
+            .and(id.gt(param(PAGE_ID_PARAMETER))));
+        paged.where_ = Some(match paged.where_ {
+            Some(condition) => condition.and(after_cursor),
+            None => after_cursor,
+        });
+        let next = paged.build(db, language)?;
+
+        Ok(Self {
+            first,
+            next,
+            page_size,
+        })
+    }
+
+    /** Assigns a value to a parameter of the query (see `Query::bind`). */
+    pub fn bind<T: ToFleece + ?Sized>(&self, name: &str, value: &T) -> Result<()> {
+        if is_reserved_page_name(name) {
+            return Err(Error::cbl_error(CouchbaseLiteError::InvalidParameter));
+        }
+        self.first.bind(name, value)?;
+        self.next.bind(name, value)
+    }
+
+    /** Runs the query, returning the first page if `token` is `None`, else the page following
+    the one that returned `token`. Fails with `InvalidParameter` if the token is invalid. */
+    pub fn page(&self, token: Option<&str>) -> Result<Page> {
+        let query = match token {
+            None => &self.first,
+            Some(token) => {
+                let cursor = decode_token(token)
+                    .ok_or_else(|| Error::cbl_error(CouchbaseLiteError::InvalidParameter))?;
+                let cursor = cursor.as_array();
+                self.next.bind(PAGE_KEY_PARAMETER, &cursor.get(0))?;
+                self.next.bind(PAGE_ID_PARAMETER, &cursor.get(1))?;
+                &self.next
+            }
+        };
+
+        let rows: Vec<String> = query
+            .execute()?
+            .map(|row| row.as_dict().to_json())
+            .collect();
+        let rows = Fleece::parse_json(&format!("[{}]", rows.join(",")))?;
+        let rows = rows.as_array();
+
+        let next = if rows.count() <= self.page_size {
+            None
+        } else {
+            let last = rows.get(self.page_size - 1).as_dict();
+            let mut cursor = MutableArray::new();
+            cursor.append().put_value(&last.get(PAGE_KEY_COLUMN));
+            cursor.append().put_value(&last.get(PAGE_ID_COLUMN));
+            Some(encode_token(&cursor.to_json()))
+        };
+        let rows = rows
+            .iter()
+            .take(self.page_size as usize)
+            .map(|row| {
+                let mut row = MutableDict::from_dict(&row.as_dict());
+                row.remove(PAGE_KEY_COLUMN);
+                row.remove(PAGE_ID_COLUMN);
+                row
+            })
+            .collect();
+        Ok(Page { rows, next })
+    }
+}
+
+fn is_reserved_page_name(name: &str) -> bool {
+    [
+        PAGE_KEY_COLUMN,
+        PAGE_ID_COLUMN,
+        PAGE_KEY_PARAMETER,
+        PAGE_ID_PARAMETER,
+    ]
+    .contains(&name)
+}
+
+// Tokens are the hex encoding of the JSON array `[key, docID]` of the last row of a page.
+fn encode_token(cursor: &str) -> String {
+    cursor.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_token(token: &str) -> Option<Fleece> {
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let cursor = Fleece::parse_json(std::str::from_utf8(&bytes).ok()?).ok()?;
+    if cursor.as_array().count() == 2 {
+        Some(cursor)
+    } else {
+        None
+    }
+}
+
+//////// N1QL RENDERING:
+
+// Identifiers are always quoted, so that keys can't collide with N1QL keywords.
+fn n1ql_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+fn n1ql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Splits a key path on its unescaped dots, removing the escapes.
+fn path_components(path: &str) -> Vec<String> {
+    let mut components = vec![String::new()];
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    components.last_mut().unwrap().push(escaped);
+                }
+            }
+            '.' => components.push(String::new()),
+            _ => components.last_mut().unwrap().push(c),
+        }
+    }
+    components
+}
+
+fn n1ql_path(path: &str) -> String {
+    path_components(path)
+        .iter()
+        .map(|component| {
+            // Keep array subscripts such as `items[0]` outside of the quoted key.
+            let key_end = component
+                .find('[')
+                .filter(|&i| component.ends_with(']') && i > 0)
+                .unwrap_or(component.len());
+            let (key, subscripts) = component.split_at(key_end);
+            format!("{}{}", n1ql_identifier(key), subscripts)
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+fn n1ql_operand(expression: &Expression) -> String {
+    match expression {
+        Expression::Unary(..)
+        | Expression::Binary(..)
+        | Expression::Between(..)
+        | Expression::In(..) => format!("({})", n1ql_expression(expression)),
+        // A minus sign after an operator would otherwise start a `--` comment
+        Expression::Literal(Literal::Int(i)) if *i < 0 => format!("({})", i),
+        Expression::Literal(Literal::Float(f)) if f.is_sign_negative() => format!("({:?})", f),
+        _ => n1ql_expression(expression),
+    }
+}
+
+/** The negation of a negative number, as a positive literal: Couchbase Lite translates a
+negation to `-` followed by its operand, which SQLite reads as a `--` comment. */
+fn negated_literal(expression: &Expression) -> Option<Expression> {
+    let negated = match expression {
+        Expression::Literal(Literal::Int(i)) if *i < 0 => i
+            .checked_neg()
+            .map_or(Literal::Float(-(*i as f64)), Literal::Int),
+        Expression::Literal(Literal::Float(f)) if f.is_sign_negative() => Literal::Float(-f),
+        _ => return None,
+    };
+    Some(Expression::Literal(negated))
+}
+
+fn n1ql_list(expressions: &[Expression]) -> String {
+    expressions
+        .iter()
+        .map(n1ql_expression)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn n1ql_expression(expression: &Expression) -> String {
+    match expression {
+        Expression::Property { path, from } => match from {
+            Some(alias) => format!("{}.{}", n1ql_identifier(alias), n1ql_path(path)),
+            None => n1ql_path(path),
+        },
+        Expression::Meta { property, from } => format!(
+            "meta({}).{}",
+            from.as_deref().map_or(String::new(), n1ql_identifier),
+            match property {
+                MetaProperty::Id => "id",
+                MetaProperty::Sequence => "sequence",
+                MetaProperty::RevisionId => "revisionID",
+                MetaProperty::Deleted => "deleted",
+                MetaProperty::Expiration => "expiration",
+            }
+        ),
+        Expression::Parameter(name) => format!("${}", name),
+        Expression::Variable(path) => {
+            let components = path_components(path);
+            let (variable, keys) = components.split_first().unwrap();
+            let mut n1ql = variable.clone();
+            for key in keys {
+                let _ = write!(n1ql, ".{}", n1ql_identifier(key));
+            }
+            n1ql
+        }
+        Expression::Literal(literal) => match literal {
+            Literal::Null => "NULL".to_string(),
+            Literal::Missing => "MISSING".to_string(),
+            Literal::Bool(true) => "TRUE".to_string(),
+            Literal::Bool(false) => "FALSE".to_string(),
+            Literal::Int(i) => i.to_string(),
+            Literal::Float(f) => format!("{:?}", f),
+            Literal::String(s) => n1ql_string(s),
+        },
+        Expression::Array(items) => format!("[{}]", n1ql_list(items)),
+        Expression::Unary(op, operand) => {
+            if let (UnaryOperator::Negate, Some(negated)) = (op, negated_literal(operand)) {
+                return n1ql_expression(&negated);
+            }
+            let operand = n1ql_operand(operand);
+            match op {
+                UnaryOperator::Not => format!("NOT {}", operand),
+                UnaryOperator::Negate => format!("-{}", operand),
+                UnaryOperator::IsNull => format!("{} IS NULL", operand),
+                UnaryOperator::IsNotNull => format!("{} IS NOT NULL", operand),
+                UnaryOperator::IsMissing => format!("{} IS MISSING", operand),
+                UnaryOperator::IsNotMissing => format!("{} IS NOT MISSING", operand),
+                UnaryOperator::IsValued => format!("{} IS VALUED", operand),
+                UnaryOperator::IsNotValued => format!("{} IS NOT VALUED", operand),
+            }
+        }
+        Expression::Binary(op, lhs, rhs) => format!(
+            "{} {} {}",
+            n1ql_operand(lhs),
+            binary_operator_name(*op),
+            n1ql_operand(rhs)
+        ),
+        Expression::Between(value, low, high) => format!(
+            "{} BETWEEN {} AND {}",
+            n1ql_operand(value),
+            n1ql_operand(low),
+            n1ql_operand(high)
+        ),
+        Expression::In(value, values) => {
+            format!("{} IN ({})", n1ql_operand(value), n1ql_list(values))
+        }
+        Expression::Function(name, args) => format!("{}({})", name, n1ql_list(args)),
+        Expression::Quantified {
+            quantifier,
+            variable,
+            array,
+            satisfies,
+        } => format!(
+            "{} {} IN {} SATISFIES {} END",
+            quantifier_name(*quantifier),
+            variable,
+            n1ql_operand(array),
+            n1ql_expression(satisfies)
+        ),
+    }
+}
+
+const fn binary_operator_name(op: BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Equal => "=",
+        BinaryOperator::NotEqual => "!=",
+        BinaryOperator::Less => "<",
+        BinaryOperator::LessOrEqual => "<=",
+        BinaryOperator::Greater => ">",
+        BinaryOperator::GreaterOrEqual => ">=",
+        BinaryOperator::And => "AND",
+        BinaryOperator::Or => "OR",
+        BinaryOperator::Add => "+",
+        BinaryOperator::Subtract => "-",
+        BinaryOperator::Multiply => "*",
+        BinaryOperator::Divide => "/",
+        BinaryOperator::Modulo => "%",
+        BinaryOperator::Like => "LIKE",
+        BinaryOperator::Concat => "||",
+        BinaryOperator::Is => "IS",
+        BinaryOperator::IsNot => "IS NOT",
+    }
+}
+
+const fn quantifier_name(quantifier: Quantifier) -> &'static str {
+    match quantifier {
+        Quantifier::Any => "ANY",
+        Quantifier::Every => "EVERY",
+        Quantifier::AnyAndEvery => "ANY AND EVERY",
+    }
+}
+
+//////// JSON RENDERING:
+
+fn json_path(path: &str, from: Option<&String>) -> String {
+    from.map_or(format!(".{}", path), |alias| {
+        format!(".{}.{}", alias.replace('.', "\\."), path)
+    })
+}
+
+fn json_operation(operator: &str, operands: &[&Expression], slot: Slot) {
+    let mut operation = MutableArray::new();
+    operation.append().put_string(operator);
+    for operand in operands {
+        json_expression(operand, operation.append());
+    }
+    slot.put_value(&operation);
+}
+
+fn json_expression(expression: &Expression, slot: Slot) {
+    match expression {
+        Expression::Property { path, from } => {
+            let mut property = MutableArray::new();
+            property.append().put_string(json_path(path, from.as_ref()));
+            slot.put_value(&property);
+        }
+        Expression::Meta { property, from } => {
+            let key = match property {
+                MetaProperty::Id => "_id",
+                MetaProperty::Sequence => "_sequence",
+                MetaProperty::RevisionId => "_revisionID",
+                MetaProperty::Deleted => "_deleted",
+                MetaProperty::Expiration => "_expiration",
+            };
+            let mut meta = MutableArray::new();
+            meta.append().put_string(json_path(key, from.as_ref()));
+            slot.put_value(&meta);
+        }
+        Expression::Parameter(name) => {
+            let mut parameter = MutableArray::new();
+            parameter.append().put_string(format!("${}", name));
+            slot.put_value(&parameter);
+        }
+        Expression::Variable(path) => {
+            let mut variable = MutableArray::new();
+            variable.append().put_string(format!("?{}", path));
+            slot.put_value(&variable);
+        }
+        Expression::Literal(literal) => match literal {
+            Literal::Null => slot.put_null(),
+            Literal::Missing => json_operation("MISSING", &[], slot),
+            Literal::Bool(b) => slot.put_bool(*b),
+            Literal::Int(i) => slot.put_i64(*i),
+            Literal::Float(f) => slot.put_f64(*f),
+            Literal::String(s) => slot.put_string(s),
+        },
+        Expression::Array(items) => {
+            json_operation("[]", &items.iter().collect::<Vec<&Expression>>(), slot);
+        }
+        Expression::Unary(op, operand) => {
+            if let (UnaryOperator::Negate, Some(negated)) = (op, negated_literal(operand)) {
+                return json_expression(&negated, slot);
+            }
+            let null = Expression::Literal(Literal::Null);
+            let missing = Expression::Literal(Literal::Missing);
+            match op {
+                UnaryOperator::Not => json_operation("NOT", &[operand], slot),
+                UnaryOperator::Negate => json_operation("-", &[operand], slot),
+                UnaryOperator::IsNull => json_operation("IS", &[operand, &null], slot),
+                UnaryOperator::IsNotNull => json_operation("IS NOT", &[operand, &null], slot),
+                UnaryOperator::IsMissing => json_operation("IS", &[operand, &missing], slot),
+                UnaryOperator::IsNotMissing => {
+                    json_operation("IS NOT", &[operand, &missing], slot);
+                }
+                UnaryOperator::IsValued => json_operation("IS VALUED", &[operand], slot),
+                UnaryOperator::IsNotValued => {
+                    let valued = Expression::Unary(UnaryOperator::IsValued, operand.clone());
+                    json_operation("NOT", &[&valued], slot);
+                }
+            }
+        }
+        Expression::Binary(op, lhs, rhs) => {
+            json_operation(binary_operator_name(*op), &[lhs, rhs], slot);
+        }
+        Expression::Between(value, low, high) => {
+            json_operation("BETWEEN", &[value, low, high], slot);
+        }
+        Expression::In(value, values) => {
+            let list = Expression::Array(values.clone());
+            json_operation("IN", &[value, &list], slot);
+        }
+        Expression::Function(name, args) => {
+            json_operation(
+                &format!("{}()", name),
+                &args.iter().collect::<Vec<&Expression>>(),
+                slot,
+            );
+        }
+        Expression::Quantified {
+            quantifier,
+            variable,
+            array,
+            satisfies,
+        } => {
+            let mut operation = MutableArray::new();
+            operation.append().put_string(quantifier_name(*quantifier));
+            operation.append().put_string(variable);
+            json_expression(array, operation.append());
+            json_expression(satisfies, operation.append());
+            slot.put_value(&operation);
+        }
+    }
+}
@@ -18,14 +18,14 @@
 #![allow(non_upper_case_globals)]
 
 use std::{
-    ptr,
+    fmt, ptr,
     collections::{HashMap, HashSet},
-    sync::mpsc::channel,
-    time::Duration,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 use crate::{
     CblRef, CouchbaseLiteError, Database, Dict, Document, Error, ErrorCode, ListenerToken,
-    MutableDict, Result, check_error, release, retain,
+    MutableDict, NetworkError, Result, Value, base64, check_error, release, retain,
     slice::{from_str, from_bytes, self},
     c_api::{
         CBLListener_Remove, CBLAuth_CreatePassword, CBLAuth_CreateSession, CBLAuthenticator,
@@ -41,7 +41,7 @@ use crate::{
         kCBLReplicatorConnecting, kCBLReplicatorIdle, kCBLReplicatorOffline, kCBLReplicatorStopped,
         kCBLReplicatorTypePull, kCBLReplicatorTypePush, kCBLReplicatorTypePushAndPull,
     },
-    MutableArray, Listener, error,
+    MutableArray, Listener, error, migration, warn,
 };
 
 // WARNING: THIS API IS UNIMPLEMENTED SO FAR
@@ -276,8 +276,25 @@ unsafe extern "C" fn c_replication_pull_filter(
             callback(&document, is_deleted, is_access_removed)
         })
 }
+/** Wraps a filter, if any, to reject the documents with the given IDs. */
+fn excluding_filter(
+    excluded: HashSet<String>,
+    filter: Option<ReplicationFilter>,
+) -> ReplicationFilter {
+    Box::new(move |document, is_deleted, is_access_removed| {
+        if excluded.contains(document.id()) {
+            return false;
+        }
+        match &filter {
+            Some(filter) => filter(document, is_deleted, is_access_removed),
+            None => true,
+        }
+    })
+}
+
 fn read_document_flags(flags: CBLDocumentFlags) -> (bool, bool) {
-    (flags & DELETED != 0, flags & ACCESS_REMOVED != 0)
+    let flags = DocumentFlags::from_bits_truncate(flags);
+    (flags.is_deleted(), flags.is_access_removed())
 }
 
 /** Conflict-resolution callback for use in replications. This callback will be invoked
@@ -296,6 +313,7 @@ unsafe extern "C" fn c_replication_conflict_resolver(
     let repl_conf_context = context as *const ReplicationConfigurationContext;
 
     let doc_id = document_id.to_string().unwrap_or_default();
+    let (local, remote) = (local_document, remote_document);
     let local_document = if local_document.is_null() {
         None
     } else {
@@ -311,8 +329,14 @@ unsafe extern "C" fn c_replication_conflict_resolver(
         .conflict_resolver
         .as_ref()
         .map_or(ptr::null(), |callback| {
-            callback(&doc_id, local_document, remote_document)
-                .map_or(ptr::null(), |d| d.get_ref() as *const CBLDocument)
+            callback(&doc_id, local_document, remote_document).map_or(ptr::null(), |d| {
+                let resolved = d.get_ref() as *const CBLDocument;
+                // Couchbase Lite takes ownership of a new revision, like a merge of both sides
+                if resolved != local && resolved != remote {
+                    retain(d.get_ref());
+                }
+                resolved
+            })
         })
 }
 
@@ -322,19 +346,27 @@ pub enum EncryptionError {
     Permanent, // The replicator will bypass the document and not try encrypting/decrypting the document until a new revision is created
 }
 
+/** What an encryptor or decryptor is given about the property it encrypts or decrypts. */
+#[derive(Debug)]
+pub struct PropertyCryptoContext {
+    pub document_id: Option<String>, // The ID of the document
+    pub properties: Dict,            // The properties of the document
+    pub key_path: Option<String>,    // The path of the encryptable property, like `address.zip`
+    /** The encryption algorithm. An encryptor may set it, and the decryptor gets it back. */
+    pub algorithm: Option<String>,
+    /** The ID of the encryption key. An encryptor may set it, and the decryptor gets it back. */
+    pub kid: Option<String>,
+}
+
 /** Callback that encrypts encryptable properties in documents pushed by the replicator.
 \note   If a null result or an error is returned, the document will be failed to
         replicate with the kCBLErrorCrypto error. For security reason, the encryption
         cannot be skipped. */
-pub type PropertyEncryptor = fn(
-    document_id: Option<String>,
-    properties: Dict,
-    key_path: Option<String>,
-    input: Vec<u8>,
-    algorithm: Option<String>,
-    kid: Option<String>,
-    error: &Error,
-) -> std::result::Result<Vec<u8>, EncryptionError>;
+pub type PropertyEncryptor = Box<
+    dyn Fn(&mut PropertyCryptoContext, &[u8]) -> std::result::Result<Vec<u8>, EncryptionError>
+        + Send
+        + Sync,
+>;
 #[no_mangle]
 pub extern "C" fn c_property_encryptor(
     context: *mut ::std::os::raw::c_void,
@@ -352,29 +384,34 @@ pub extern "C" fn c_property_encryptor(
 
         let mut result = FLSliceResult_New(0);
         if let Some(input) = input.to_vec() {
+            let mut crypto_context = PropertyCryptoContext {
+                document_id: document_id.to_string(),
+                properties: Dict::wrap(properties, &properties),
+                key_path: key_path.to_string(),
+                algorithm: None,
+                kid: None,
+            };
             result = (*repl_conf_context)
                 .property_encryptor
-                .map(|callback| {
-                    callback(
-                        document_id.to_string(),
-                        Dict::wrap(properties, &properties),
-                        key_path.to_string(),
-                        input,
-                        algorithm.as_ref().and_then(|s| s.clone().to_string()),
-                        kid.as_ref().and_then(|s| s.clone().to_string()),
-                        &error,
-                    )
-                })
+                .as_ref()
+                .map(|callback| callback(&mut crypto_context, &input))
                 .map_or(FLSliceResult_New(0), |v| match v {
-                    Ok(v) => FLSlice_Copy(from_bytes(&v[..]).get_ref()),
+                    Ok(v) => {
+                        if let (Some(value), false) =
+                            (&crypto_context.algorithm, algorithm.is_null())
+                        {
+                            ptr::write(algorithm, FLSlice_Copy(from_str(value).get_ref()));
+                        }
+                        if let (Some(value), false) = (&crypto_context.kid, kid.is_null()) {
+                            ptr::write(kid, FLSlice_Copy(from_str(value).get_ref()));
+                        }
+                        FLSlice_Copy(from_bytes(&v[..]).get_ref())
+                    }
                     Err(err) => {
                         match err {
                             EncryptionError::Temporary => {
                                 error!("Encryption callback returned with transient error");
-                                error = Error {
-                                    code: ErrorCode::WebSocket(503),
-                                    internal_info: None,
-                                };
+                                error = Error::from_code(ErrorCode::WebSocket(503));
                             }
                             EncryptionError::Permanent => {
                                 error!("Encryption callback returned with non transient error");
@@ -401,15 +438,11 @@ pub extern "C" fn c_property_encryptor(
 \note   The decryption will be skipped (the encrypted data will be kept) when a null result
         without an error is returned. If an error is returned, the document will be failed to replicate
         with the kCBLErrorCrypto error. */
-pub type PropertyDecryptor = fn(
-    document_id: Option<String>,
-    properties: Dict,
-    key_path: Option<String>,
-    input: Vec<u8>,
-    algorithm: Option<String>,
-    kid: Option<String>,
-    error: &Error,
-) -> std::result::Result<Vec<u8>, EncryptionError>;
+pub type PropertyDecryptor = Box<
+    dyn Fn(&PropertyCryptoContext, &[u8]) -> std::result::Result<Vec<u8>, EncryptionError>
+        + Send
+        + Sync,
+>;
 #[no_mangle]
 pub extern "C" fn c_property_decryptor(
     context: *mut ::std::os::raw::c_void,
@@ -427,29 +460,24 @@ pub extern "C" fn c_property_decryptor(
 
         let mut result = FLSliceResult_New(0);
         if let Some(input) = input.to_vec() {
+            let crypto_context = PropertyCryptoContext {
+                document_id: document_id.to_string(),
+                properties: Dict::wrap(properties, &properties),
+                key_path: key_path.to_string(),
+                algorithm: algorithm.to_string(),
+                kid: kid.to_string(),
+            };
             result = (*repl_conf_context)
                 .property_decryptor
-                .map(|callback| {
-                    callback(
-                        document_id.to_string(),
-                        Dict::wrap(properties, &properties),
-                        key_path.to_string(),
-                        input.to_vec(),
-                        algorithm.to_string(),
-                        kid.to_string(),
-                        &error,
-                    )
-                })
+                .as_ref()
+                .map(|callback| callback(&crypto_context, &input))
                 .map_or(FLSliceResult_New(0), |v| match v {
                     Ok(v) => FLSlice_Copy(from_bytes(&v[..]).get_ref()),
                     Err(err) => {
                         match err {
                             EncryptionError::Temporary => {
                                 error!("Decryption callback returned with transient error");
-                                error = Error {
-                                    code: ErrorCode::WebSocket(503),
-                                    internal_info: None,
-                                };
+                                error = Error::from_code(ErrorCode::WebSocket(503));
                             }
                             EncryptionError::Permanent => {
                                 error!("Decryption callback returned with non transient error");
@@ -513,6 +541,9 @@ pub struct ReplicatorConfiguration {
     //-- Filtering:
     pub channels: MutableArray, // Optional set of channels to pull from
     pub document_ids: MutableArray, // Optional set of document IDs to replicate
+    /** The IDs of documents that are neither pushed nor pulled, like the schema version
+    recorded by `migration::Migrator`, which only describes the local database. */
+    pub excluded_document_ids: Vec<String>,
     //-- Advanced HTTP settings:
     /** The option to remove the restriction that does not allow the replicator to save the parent-domain
     cookies, the cookies whose domains are the parent domain of the remote host, from the HTTP
@@ -524,6 +555,424 @@ pub struct ReplicatorConfiguration {
     pub accept_parent_domain_cookies: bool,
 }
 
+/** The longest heartbeat or retry wait time, in seconds, that Couchbase Lite accepts: it converts
+them to milliseconds in a signed 32-bit integer. */
+pub const MAX_REPLICATOR_INTERVAL: u32 = i32::MAX as u32 / 1000;
+
+/** Why a replicator configuration is invalid, as found by `ReplicatorConfiguration::validate`
+before Couchbase Lite sees it. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigurationError {
+    /** The builder was given neither a URL nor a local database to replicate with. */
+    MissingEndpoint,
+    /** The URL isn't a `ws:` or `wss:` URL with a host. */
+    InvalidUrl(String),
+    /** The heartbeat interval, in seconds, is over `MAX_REPLICATOR_INTERVAL`. */
+    HeartbeatOutOfRange(u32),
+    /** The maximum wait time between retries, in seconds, is over `MAX_REPLICATOR_INTERVAL`. */
+    MaxAttemptWaitTimeOutOfRange(u32),
+    /** The pinned server certificate is neither a single PEM certificate nor a DER SEQUENCE.
+    Only the framing is checked, not the certificate itself. */
+    InvalidPinnedCertificate,
+    /** The trusted root certificates aren't a list of PEM blocks of DER SEQUENCEs. */
+    InvalidTrustedRootCertificates,
+    /** A channel name is empty. */
+    EmptyChannel,
+    /** A document ID is empty. */
+    EmptyDocumentId,
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingEndpoint => fmt.write_str("no URL or local database to replicate with"),
+            Self::InvalidUrl(url) => write!(fmt, "invalid URL {:?}: expected ws:// or wss://", url),
+            Self::HeartbeatOutOfRange(seconds) => write!(
+                fmt,
+                "heartbeat of {} seconds is over the maximum of {}",
+                seconds, MAX_REPLICATOR_INTERVAL
+            ),
+            Self::MaxAttemptWaitTimeOutOfRange(seconds) => write!(
+                fmt,
+                "retry wait time of {} seconds is over the maximum of {}",
+                seconds, MAX_REPLICATOR_INTERVAL
+            ),
+            Self::InvalidPinnedCertificate => {
+                fmt.write_str("pinned server certificate is not PEM or DER encoded")
+            }
+            Self::InvalidTrustedRootCertificates => {
+                fmt.write_str("trusted root certificates are not PEM encoded")
+            }
+            Self::EmptyChannel => fmt.write_str("empty channel name"),
+            Self::EmptyDocumentId => fmt.write_str("empty document ID"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+/** A configuration error becomes the error Couchbase Lite reports for the same problem: an
+invalid URL for a missing or invalid endpoint, a crypto error for certificates it can't read, a
+bad document ID for an empty one, and an invalid parameter otherwise. Since `Error` can't hold
+the details, like the invalid URL or interval, they're logged as a warning. */
+impl From<ConfigurationError> for Error {
+    fn from(err: ConfigurationError) -> Self {
+        warn!("Invalid replicator configuration: {}", err);
+        match err {
+            ConfigurationError::MissingEndpoint | ConfigurationError::InvalidUrl(_) => {
+                Self::from_code(ErrorCode::Network(NetworkError::InvalidURL))
+            }
+            ConfigurationError::InvalidPinnedCertificate
+            | ConfigurationError::InvalidTrustedRootCertificates => {
+                Self::cbl_error(CouchbaseLiteError::Crypto)
+            }
+            ConfigurationError::EmptyDocumentId => Self::cbl_error(CouchbaseLiteError::BadDocID),
+            ConfigurationError::HeartbeatOutOfRange(_)
+            | ConfigurationError::MaxAttemptWaitTimeOutOfRange(_)
+            | ConfigurationError::EmptyChannel => {
+                Self::cbl_error(CouchbaseLiteError::InvalidParameter)
+            }
+        }
+    }
+}
+
+impl ReplicatorConfiguration {
+    /** Returns a builder of a configuration replicating `database`, with the defaults of
+    Couchbase Lite: a one-shot push-and-pull replication with no filter. */
+    pub fn builder(database: &Database) -> ReplicatorConfigurationBuilder {
+        ReplicatorConfigurationBuilder::new(database)
+    }
+
+    /** Checks the settings that Couchbase Lite would otherwise reject, or only fail on when the
+    replicator connects. Certificates are only checked to be framed as PEM or DER; whether they
+    are valid X.509 certificates is left to Couchbase Lite. */
+    pub fn validate(&self) -> std::result::Result<(), ConfigurationError> {
+        if let Some(url) = &self.endpoint.url {
+            validate_url(url)?;
+        }
+        if self.heartbeat > MAX_REPLICATOR_INTERVAL {
+            return Err(ConfigurationError::HeartbeatOutOfRange(self.heartbeat));
+        }
+        if self.max_attempt_wait_time > MAX_REPLICATOR_INTERVAL {
+            return Err(ConfigurationError::MaxAttemptWaitTimeOutOfRange(
+                self.max_attempt_wait_time,
+            ));
+        }
+        if let Some(certificate) = &self.pinned_server_certificate {
+            if !is_der_sequence(certificate) && pem_certificate_count(certificate) != Some(1) {
+                return Err(ConfigurationError::InvalidPinnedCertificate);
+            }
+        }
+        if let Some(certificates) = &self.trusted_root_certificates {
+            if pem_certificate_count(certificates).unwrap_or(0) == 0 {
+                return Err(ConfigurationError::InvalidTrustedRootCertificates);
+            }
+        }
+        let is_empty = |value: Value| value.as_string().unwrap_or_default().is_empty();
+        if self.channels.iter().any(is_empty) {
+            return Err(ConfigurationError::EmptyChannel);
+        }
+        if self.document_ids.iter().any(is_empty) {
+            return Err(ConfigurationError::EmptyDocumentId);
+        }
+        Ok(())
+    }
+}
+
+/** A builder of `ReplicatorConfiguration`, starting from the defaults of Couchbase Lite. */
+#[derive(Debug)]
+pub struct ReplicatorConfigurationBuilder {
+    database: Database,
+    url: Option<String>,
+    endpoint: Option<Endpoint>,
+    replicator_type: ReplicatorType,
+    continuous: bool,
+    disable_auto_purge: bool,
+    max_attempts: u32,
+    max_attempt_wait_time: u32,
+    heartbeat: u32,
+    authenticator: Option<Authenticator>,
+    proxy: Option<ProxySettings>,
+    headers: HashMap<String, String>,
+    pinned_server_certificate: Option<Vec<u8>>,
+    trusted_root_certificates: Option<Vec<u8>>,
+    channels: Vec<String>,
+    document_ids: Vec<String>,
+    excluded_document_ids: Vec<String>,
+    accept_parent_domain_cookies: bool,
+}
+
+impl ReplicatorConfigurationBuilder {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: database.clone(),
+            url: None,
+            endpoint: None,
+            replicator_type: ReplicatorType::PushAndPull,
+            continuous: false,
+            disable_auto_purge: false,
+            max_attempts: 0,
+            max_attempt_wait_time: 0,
+            heartbeat: 0,
+            authenticator: None,
+            proxy: None,
+            headers: HashMap::new(),
+            pinned_server_certificate: None,
+            trusted_root_certificates: None,
+            channels: vec![],
+            document_ids: vec![],
+            excluded_document_ids: vec![migration::DEFAULT_DOCUMENT_ID.to_string()],
+            accept_parent_domain_cookies: false,
+        }
+    }
+
+    /** Replicates with the remote database at `url`, a `ws:` or `wss:` URL. */
+    #[must_use]
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(url.to_string());
+        self.endpoint = None;
+        self
+    }
+
+    /** Replicates with another local database. */
+    #[must_use]
+    pub fn local_database(mut self, database: &Database) -> Self {
+        self.endpoint = Some(Endpoint::new_with_local_db(database));
+        self.url = None;
+        self
+    }
+
+    #[must_use]
+    pub fn replicator_type(mut self, replicator_type: ReplicatorType) -> Self {
+        self.replicator_type = replicator_type;
+        self
+    }
+
+    #[must_use]
+    pub const fn continuous(mut self, continuous: bool) -> Self {
+        self.continuous = continuous;
+        self
+    }
+
+    #[must_use]
+    pub const fn disable_auto_purge(mut self, disable_auto_purge: bool) -> Self {
+        self.disable_auto_purge = disable_auto_purge;
+        self
+    }
+
+    /** Sets the maximum number of connection attempts; 0 is Couchbase Lite's default. */
+    #[must_use]
+    pub const fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /** Sets the maximum wait time between connection attempts; 0 is Couchbase Lite's default,
+    300 seconds. */
+    #[must_use]
+    pub const fn max_attempt_wait_time(mut self, wait_time: Duration) -> Self {
+        self.max_attempt_wait_time = duration_seconds(wait_time);
+        self
+    }
+
+    /** Sets the WebSocket heartbeat interval; 0 is Couchbase Lite's default, 300 seconds. */
+    #[must_use]
+    pub const fn heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = duration_seconds(heartbeat);
+        self
+    }
+
+    #[must_use]
+    pub fn authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    #[must_use]
+    pub fn proxy(mut self, proxy: ProxySettings) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /** Adds an HTTP header to the WebSocket request. */
+    #[must_use]
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /** Pins TLS connections to an X.509 certificate, in PEM or DER format. */
+    #[must_use]
+    pub fn pinned_server_certificate(mut self, certificate: Vec<u8>) -> Self {
+        self.pinned_server_certificate = Some(certificate);
+        self
+    }
+
+    /** Sets the anchor certificates, in PEM format. */
+    #[must_use]
+    pub fn trusted_root_certificates(mut self, certificates: Vec<u8>) -> Self {
+        self.trusted_root_certificates = Some(certificates);
+        self
+    }
+
+    /** Only pulls the documents in these channels. */
+    #[must_use]
+    pub fn channels<I, S>(mut self, channels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.channels = channels.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /** Only replicates the documents with these IDs. */
+    #[must_use]
+    pub fn document_ids<I, S>(mut self, document_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.document_ids = document_ids.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /** Never pushes nor pulls the documents with these IDs. Defaults to the ID of the document
+    where `migration::Migrator` records the schema version, which must stay local: a database
+    pulling it from an already migrated peer would skip its own migration steps. Pass an empty
+    list to replicate every document. */
+    #[must_use]
+    pub fn excluded_document_ids<I, S>(mut self, document_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.excluded_document_ids = document_ids.into_iter().map(Into::into).collect();
+        self
+    }
+
+    #[must_use]
+    pub const fn accept_parent_domain_cookies(mut self, accept: bool) -> Self {
+        self.accept_parent_domain_cookies = accept;
+        self
+    }
+
+    /** Builds and validates the configuration. */
+    pub fn build(self) -> std::result::Result<ReplicatorConfiguration, ConfigurationError> {
+        let endpoint = match (self.endpoint, &self.url) {
+            (Some(endpoint), _) => endpoint,
+            (None, Some(url)) => {
+                validate_url(url)?;
+                Endpoint::new_with_url(url)
+                    .map_err(|_| ConfigurationError::InvalidUrl(url.clone()))?
+            }
+            (None, None) => return Err(ConfigurationError::MissingEndpoint),
+        };
+        let config = ReplicatorConfiguration {
+            database: self.database,
+            endpoint,
+            replicator_type: self.replicator_type,
+            continuous: self.continuous,
+            disable_auto_purge: self.disable_auto_purge,
+            max_attempts: self.max_attempts,
+            max_attempt_wait_time: self.max_attempt_wait_time,
+            heartbeat: self.heartbeat,
+            authenticator: self.authenticator,
+            proxy: self.proxy,
+            headers: self.headers,
+            pinned_server_certificate: self.pinned_server_certificate,
+            trusted_root_certificates: self.trusted_root_certificates,
+            channels: string_array(&self.channels),
+            document_ids: string_array(&self.document_ids),
+            excluded_document_ids: self.excluded_document_ids,
+            accept_parent_domain_cookies: self.accept_parent_domain_cookies,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+const fn duration_seconds(duration: Duration) -> u32 {
+    if duration.as_secs() > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        duration.as_secs() as u32
+    }
+}
+
+/** An empty list is left unset, as Couchbase Lite takes an empty array for a filter. */
+fn string_array(strings: &[String]) -> MutableArray {
+    if strings.is_empty() {
+        return MutableArray::default();
+    }
+    let mut array = MutableArray::new();
+    for string in strings {
+        array.append().put_string(string);
+    }
+    array
+}
+
+fn validate_url(url: &str) -> std::result::Result<(), ConfigurationError> {
+    let invalid = || ConfigurationError::InvalidUrl(url.to_string());
+    let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit('@').next().unwrap_or_default();
+    if !(scheme.eq_ignore_ascii_case("ws") || scheme.eq_ignore_ascii_case("wss"))
+        || host.is_empty()
+        || host.starts_with(':')
+    {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/** Returns the number of `CERTIFICATE` blocks in PEM data, or `None` if it isn't well-formed PEM
+or a block doesn't hold a DER SEQUENCE. */
+fn pem_certificate_count(pem: &[u8]) -> Option<usize> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let pem = std::str::from_utf8(pem).ok()?;
+    let mut count = 0;
+    let mut rest = pem.trim();
+    while !rest.is_empty() {
+        let body = rest.strip_prefix(BEGIN)?;
+        let end = body.find(END)?;
+        let encoded: String = body[..end].split_whitespace().collect();
+        if !is_der_sequence(&base64::decode(&encoded)?) {
+            return None;
+        }
+        count += 1;
+        rest = body[end + END.len()..].trim_start();
+    }
+    Some(count)
+}
+
+/** Checks that DER data starts with an ASN.1 SEQUENCE header whose length spans all of the data,
+as the outer structure of an X.509 certificate does. The contents aren't parsed, so this doesn't
+tell whether the data is a certificate. */
+fn is_der_sequence(der: &[u8]) -> bool {
+    let (tag, first_length) = match der {
+        [tag, length, ..] => (*tag, *length),
+        _ => return false,
+    };
+    if tag != 0x30 {
+        return false;
+    }
+    let (header, length) = if first_length < 0x80 {
+        (2, first_length as usize)
+    } else {
+        let length_bytes = (first_length & 0x7F) as usize;
+        if length_bytes == 0 || length_bytes > 4 || der.len() < 2 + length_bytes {
+            return false;
+        }
+        let length = der[2..2 + length_bytes]
+            .iter()
+            .fold(0, |length, byte| length << 8 | *byte as usize);
+        (2 + length_bytes, length)
+    };
+    header + length == der.len()
+}
+
 //======== LIFECYCLE
 
 type ReplicatorsListeners<T> = Vec<Listener<Box<T>>>;
@@ -549,8 +998,17 @@ impl Replicator {
     /** Creates a replicator with the given configuration. */
     pub fn new(
         config: ReplicatorConfiguration,
-        context: Box<ReplicationConfigurationContext>,
+        mut context: Box<ReplicationConfigurationContext>,
     ) -> Result<Self> {
+        if !config.excluded_document_ids.is_empty() {
+            let excluded: HashSet<String> = config.excluded_document_ids.iter().cloned().collect();
+            context.push_filter = Some(excluding_filter(
+                excluded.clone(),
+                context.push_filter.take(),
+            ));
+            context.pull_filter = Some(excluding_filter(excluded, context.pull_filter.take()));
+        }
+
         unsafe {
             let headers = MutableDict::from_hashmap(&config.headers);
 
@@ -763,6 +1221,123 @@ impl Replicator {
         self.document_listeners.push(listener);
         self
     }
+
+    /** Returns a channel receiving every status change of the replicator from now on, for as
+    long as the replicator exists. Unlike `add_change_listener`, the statuses can be consumed
+    on any thread, or waited for. */
+    pub fn status_stream(&mut self) -> Receiver<ReplicatorStatus> {
+        let (sender, receiver) = channel();
+        let listener: ReplicatorChangeListener = Box::new(move |status| {
+            let _ = sender.send(status);
+        });
+        let listener = unsafe {
+            let ptr = Box::into_raw(Box::new(listener));
+            Listener::new(
+                ListenerToken::new(CBLReplicator_AddChangeListener(
+                    self.get_ref(),
+                    Some(c_replicator_change_listener),
+                    ptr.cast(),
+                )),
+                Box::from_raw(ptr),
+            )
+        };
+        self.change_listeners.push(listener);
+        receiver
+    }
+
+    /** Blocks until the replicator reaches the `activity` level, and returns its status then.
+    Returns early, with a `Stopped` status, if the replicator stops before that; returns `None`
+    if `timeout` elapses first. */
+    pub fn wait_until(
+        &self,
+        activity: ReplicatorActivityLevel,
+        timeout: Duration,
+    ) -> Option<ReplicatorStatus> {
+        self.wait_for_status(false, timeout, |status| {
+            status.activity == activity || status.activity == ReplicatorActivityLevel::Stopped
+        })
+    }
+
+    /** Runs the replicator once and blocks until it's done: until it stops, or, if it's
+    continuous, until it's idle, in which case it's then stopped. Returns the status the
+    replicator stopped with, or its error. If the replication, including stopping a continuous
+    replicator, takes longer than `timeout`, the replicator is stopped and a
+    `NetworkError::Timeout` error is returned. */
+    pub fn run_once(&mut self, timeout: Duration) -> Result<ReplicatorStatus> {
+        let deadline = Instant::now() + timeout;
+        let timed_out = |replicator: &mut Self| {
+            replicator.stop(None);
+            Err(Error::from_code(ErrorCode::Network(NetworkError::Timeout)))
+        };
+
+        let status = self.wait_for_status(true, timeout, |status| {
+            status.activity == ReplicatorActivityLevel::Stopped
+                || status.activity == ReplicatorActivityLevel::Idle
+        });
+        let mut status = match status {
+            Some(status) => status,
+            None => return timed_out(self),
+        };
+        if status.activity == ReplicatorActivityLevel::Idle {
+            unsafe { CBLReplicator_Stop(self.get_ref()) };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            status = match self.wait_for_status(false, remaining, |status| {
+                status.activity == ReplicatorActivityLevel::Stopped
+            }) {
+                Some(status) => status,
+                None => return timed_out(self),
+            };
+        }
+        std::mem::replace(&mut status.error, Ok(()))?;
+        Ok(status)
+    }
+
+    /** Waits for a status change matching `done`, listening to changes before optionally
+    starting the replicator, so that none is missed. The current status is only checked if the
+    replicator isn't being started, as it's stale until the replicator reports its new state. */
+    fn wait_for_status<F>(
+        &self,
+        start: bool,
+        timeout: Duration,
+        done: F,
+    ) -> Option<ReplicatorStatus>
+    where
+        F: Fn(&ReplicatorStatus) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = channel();
+        let callback: ReplicatorChangeListener = Box::new(move |status| {
+            let _ = sender.send(status);
+        });
+
+        unsafe {
+            let token = CBLReplicator_AddChangeListener(
+                self.get_ref(),
+                Some(c_replicator_change_listener),
+                std::mem::transmute(&callback),
+            );
+
+            let mut result = None;
+            if start {
+                CBLReplicator_Start(self.get_ref(), false);
+            } else {
+                let status = self.status();
+                if done(&status) {
+                    result = Some(status);
+                }
+            }
+            while result.is_none() {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(remaining) {
+                    Ok(status) if done(&status) => result = Some(status),
+                    Ok(_) => {}
+                    Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            CBLListener_Remove(token);
+            result
+        }
+    }
 }
 
 impl Drop for Replicator {
@@ -774,7 +1349,7 @@ impl Drop for Replicator {
 //======== STATUS AND PROGRESS
 
 /** The possible states a replicator can be in during its lifecycle. */
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ReplicatorActivityLevel {
     Stopped,    // The replicator is unstarted, finished, or hit a fatal error.
     Offline,    // The replicator is offline, as the remote host is unreachable.
@@ -862,7 +1437,7 @@ unsafe extern "C" fn c_replicator_document_change_listener(
         .filter_map(|document| {
             document.ID.to_string().map(|doc_id| ReplicatedDocument {
                 id: doc_id,
-                flags: document.flags,
+                flags: DocumentFlags::from_bits_truncate(document.flags),
                 error: check_error(&document.error),
             })
         })
@@ -872,19 +1447,189 @@ unsafe extern "C" fn c_replicator_document_change_listener(
 }
 
 /** Flags describing a replicated document. */
+#[deprecated(note = "use `DocumentFlags::DELETED`")]
 pub static DELETED: u32 = kCBLDocumentFlagsDeleted;
+#[deprecated(note = "use `DocumentFlags::ACCESS_REMOVED`")]
 pub static ACCESS_REMOVED: u32 = kCBLDocumentFlagsAccessRemoved;
 
+/** The set of flags describing a replicated document, or a document given to a replication
+filter. */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DocumentFlags(u32);
+
+impl DocumentFlags {
+    /** The document has been deleted. */
+    pub const DELETED: Self = Self(kCBLDocumentFlagsDeleted);
+    /** Lost access to the document on the server. */
+    pub const ACCESS_REMOVED: Self = Self(kCBLDocumentFlagsAccessRemoved);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /** Returns the flags of the raw bits, ignoring the unknown ones. */
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & (kCBLDocumentFlagsDeleted | kCBLDocumentFlagsAccessRemoved))
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /** Returns true if all the flags of `other` are set. */
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_deleted(&self) -> bool {
+        self.contains(Self::DELETED)
+    }
+
+    pub const fn is_access_removed(&self) -> bool {
+        self.contains(Self::ACCESS_REMOVED)
+    }
+}
+
+impl std::ops::BitOr for DocumentFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for DocumentFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::ops::BitAnd for DocumentFlags {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
 /** Information about a document that's been pushed or pulled. */
+#[derive(Debug)]
 pub struct ReplicatedDocument {
-    pub id: String,        // The document ID
-    pub flags: u32,        // Indicates whether the document was deleted or removed
-    pub error: Result<()>, // Error, if document failed to replicate
+    pub id: String,           // The document ID
+    pub flags: DocumentFlags, // Indicates whether the document was deleted or removed
+    pub error: Result<()>,    // Error, if document failed to replicate
+}
+
+impl ReplicatedDocument {
+    pub const fn succeeded(&self) -> bool {
+        self.error.is_ok()
+    }
+
+    pub const fn is_deleted(&self) -> bool {
+        self.flags.is_deleted()
+    }
+
+    pub const fn is_access_removed(&self) -> bool {
+        self.flags.is_access_removed()
+    }
+
+    /** Returns the revision ID of the document in `database` at the time of the call, if it
+    exists there, deleted or not. Couchbase Lite doesn't report the revision that was
+    replicated, so this may be a newer one. It reads the database: call it after the document
+    listener returns, not from it. */
+    pub fn local_revision_id(&self, database: &Database) -> Option<String> {
+        database
+            .get_document(&self.id)
+            .ok()
+            .and_then(|doc| doc.revision_id().map(str::to_string))
+    }
 }
 
 /** Direction of document transfer. */
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     Pulled,
     Pushed,
 }
+
+/** Numbers of documents replicated in one direction. */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionCounts {
+    pub succeeded: u64,      // Documents replicated successfully
+    pub failed: u64,         // Documents that failed to replicate
+    pub deleted: u64,        // Deletions replicated successfully
+    pub access_removed: u64, // Documents replicated successfully, whose access was removed
+}
+
+impl DirectionCounts {
+    /** Counts the documents of a `ReplicatedDocumentListener` call. */
+    pub fn of(documents: &[ReplicatedDocument]) -> Self {
+        let mut counts = Self::default();
+        counts.add(documents);
+        counts
+    }
+
+    pub fn add(&mut self, documents: &[ReplicatedDocument]) {
+        for document in documents {
+            if !document.succeeded() {
+                self.failed += 1;
+                continue;
+            }
+            self.succeeded += 1;
+            if document.is_deleted() {
+                self.deleted += 1;
+            }
+            if document.is_access_removed() {
+                self.access_removed += 1;
+            }
+        }
+    }
+
+    pub const fn total(&self) -> u64 {
+        self.succeeded + self.failed
+    }
+}
+
+/** Numbers of documents replicated in each direction, accumulated over the calls of a
+`ReplicatedDocumentListener`. */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicatedDocumentCounts {
+    pub pushed: DirectionCounts,
+    pub pulled: DirectionCounts,
+}
+
+impl ReplicatedDocumentCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, direction: Direction, documents: &[ReplicatedDocument]) {
+        self.get_mut(direction).add(documents);
+    }
+
+    pub const fn get(&self, direction: Direction) -> &DirectionCounts {
+        match direction {
+            Direction::Pushed => &self.pushed,
+            Direction::Pulled => &self.pulled,
+        }
+    }
+
+    fn get_mut(&mut self, direction: Direction) -> &mut DirectionCounts {
+        match direction {
+            Direction::Pushed => &mut self.pushed,
+            Direction::Pulled => &mut self.pulled,
+        }
+    }
+
+    pub const fn succeeded(&self) -> u64 {
+        self.pushed.succeeded + self.pulled.succeeded
+    }
+
+    pub const fn failed(&self) -> u64 {
+        self.pushed.failed + self.pulled.failed
+    }
+}
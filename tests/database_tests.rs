@@ -20,6 +20,7 @@ extern crate tempdir;
 extern crate lazy_static;
 
 use self::couchbase_lite::*;
+use self::couchbase_lite::maintenance::{IntegrityProblem, MaintenancePolicy, MaintenanceTrigger};
 use self::tempdir::TempDir;
 use lazy_static::lazy_static;
 use utils::init_logging;
@@ -170,7 +171,7 @@ fn db_encryption_key() {
     {
         let mut db = Database::open(utils::DB_NAME, Some(cfg_no_encryption.clone())).unwrap();
         assert!(db.get_document("foo").is_ok());
-        assert!(db.change_encryption_key(&encryption_key).is_ok());
+        assert!(db.change_encryption_key(Some(&encryption_key)).is_ok());
     }
 
     // Assert database can only be opened with ecryption & doc can be retrieved
@@ -259,3 +260,667 @@ fn document_borrow_check() {
     println!("v = {:?}", v);
 }
 */
+
+#[test]
+fn open_with_config() {
+    pub const DB_NAME: &str = "test_db";
+
+    init_logging();
+
+    let tmp_dir = TempDir::new("cbl_rust").expect("create temp dir");
+    let cfg = DatabaseConfig::new().directory(tmp_dir.path());
+    assert_eq!(cfg.get_directory(), Some(tmp_dir.path()));
+    assert!(cfg.get_encryption_key().is_none());
+
+    let db = Database::open_with_config(DB_NAME, &cfg).expect("open db");
+    let config = db.config();
+    assert_eq!(
+        config.get_directory().unwrap().canonicalize().unwrap(),
+        tmp_dir.path().canonicalize().unwrap()
+    );
+    assert!(config.get_encryption_key().is_none());
+
+    // The configuration read back can open the database again:
+    let other = Database::open_with_config(DB_NAME, &config).expect("open db");
+    assert_eq!(other.path(), db.path());
+    other.close().unwrap();
+    db.delete().unwrap();
+}
+
+#[cfg(feature = "legacy-key")]
+#[test]
+fn db_legacy_password_key() {
+    extern crate pbkdf2;
+    extern crate sha1;
+
+    // The PBKDF2-HMAC-SHA1 implementation matches the test vectors of RFC 6070:
+    for (password, salt, rounds, expected) in [
+        (
+            &b"password"[..],
+            &b"salt"[..],
+            1,
+            "0c60c80f961f0e71f3a9b524af6012062fe037a6",
+        ),
+        (
+            b"password",
+            b"salt",
+            2,
+            "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957",
+        ),
+        (
+            b"password",
+            b"salt",
+            4096,
+            "4b007901b765489abead49d926f721d065a429c1",
+        ),
+        (
+            b"passwordPASSWORDpassword",
+            b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+            4096,
+            "3d2eec4fe41c849b80c8d83662c0e44a8b291a964cf2f07038",
+        ),
+        (
+            b"pass\0word",
+            b"sa\0lt",
+            4096,
+            "56fa6aa75548099dcc37d7f03425e0c3",
+        ),
+    ] {
+        let mut key = vec![0; expected.len() / 2];
+        pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, salt, rounds, &mut key);
+        let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, expected);
+    }
+
+    // Matches the keys derived from passwords by Couchbase Lite before 3.0:
+    let legacy_key = EncryptionKey::new_from_password_sha1("password");
+    assert_eq!(
+        legacy_key.bytes(),
+        &[
+            0x80, 0x02, 0xfe, 0x85, 0x6d, 0x1f, 0xd2, 0x56, 0xf7, 0xa1, 0x76, 0x0c, 0x13, 0xe4,
+            0x4a, 0x14, 0x50, 0x96, 0xcd, 0x35, 0x49, 0xee, 0x34, 0x36, 0xf9, 0x11, 0x99, 0x5a,
+            0xa2, 0x17, 0xf1, 0x22
+        ]
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn db_local_encryption() {
+    use self::couchbase_lite::crypto::{self, KeyRing};
+    use encryptable::Encryptable;
+
+    utils::with_db(|db| {
+        let keys = Arc::new(KeyRing::with_key("k1", EncryptionKey::from_bytes([7; 32])));
+        db.set_local_key_provider(Some(keys.clone()));
+
+        {
+            let mut doc = Document::new_with_id("foo");
+            let mut props = doc.mutable_properties();
+            props.at("name").put_string("Alice");
+            MutableDict::try_set_encryptable_value(
+                &props,
+                "ssn",
+                &Encryptable::create_with_string("123-45-6789"),
+                db,
+            )
+            .unwrap();
+            let mut address = MutableDict::new();
+            address.at("zip").put_string("94040");
+            props
+                .at("address")
+                .try_put_encrypt(
+                    &Encryptable::create_with_dict(address.as_dict()),
+                    db,
+                    "address",
+                )
+                .unwrap();
+            props
+                .at("moved")
+                .try_put_encrypt(&Encryptable::create_with_string("secret"), db, "other")
+                .unwrap();
+            db.save_document(&mut doc).expect("save");
+        }
+
+        // The values are stored encrypted, as another connection without the keys reads them
+        let mut other_db = Database::open_with_config(db.name(), &db.config()).unwrap();
+        let stored = other_db.get_document("foo").unwrap();
+        let props = stored.properties();
+        assert!(!props.to_json().contains("123-45-6789"));
+        assert!(!props.to_json().contains("94040"));
+        assert!(crypto::is_locally_encrypted(
+            &props.get("ssn").get_encryptable_value()
+        ));
+
+        // They are decrypted transparently when read from the database that has the keys,
+        // except those that can't be, which are returned as stored
+        {
+            let doc = db.get_document("foo").unwrap();
+            let decrypted = doc.properties();
+            assert_eq!(
+                decrypted
+                    .get("ssn")
+                    .get_encryptable_value()
+                    .get_value()
+                    .as_string(),
+                Some("123-45-6789")
+            );
+            assert_eq!(
+                decrypted
+                    .get("address")
+                    .get_encryptable_value()
+                    .get_value()
+                    .as_dict()
+                    .get("zip")
+                    .as_string(),
+                Some("94040")
+            );
+            assert!(crypto::is_locally_encrypted(
+                &decrypted.get("moved").get_encryptable_value()
+            ));
+        }
+
+        // They are decrypted with their key path
+        let ssn = props
+            .get("ssn")
+            .try_get_encryptable_value(db, "ssn")
+            .unwrap();
+        assert!(!crypto::is_locally_encrypted(&ssn));
+        assert_eq!(ssn.get_value().as_string(), Some("123-45-6789"));
+        let address = props
+            .get("address")
+            .as_dict()
+            .try_get_encryptable_value(db, "address")
+            .unwrap();
+        assert_eq!(
+            address.get_value().as_dict().get("zip").as_string(),
+            Some("94040")
+        );
+
+        // A value doesn't decrypt at another key path than the one it was encrypted for
+        let crypto_error = Some(ErrorCode::CouchbaseLite(CouchbaseLiteError::Crypto));
+        assert_eq!(
+            props
+                .get("moved")
+                .try_get_encryptable_value(db, "moved")
+                .err()
+                .map(|err| err.code),
+            crypto_error
+        );
+        assert_eq!(
+            db.decrypt_local("moved", &props.get("moved").get_encryptable_value())
+                .err(),
+            Some(EncryptionError::Permanent)
+        );
+
+        // Values set with the plain setter are encrypted when saved, and the saved document keeps
+        // them as they were set
+        {
+            let mut doc = db.get_document("foo").unwrap();
+            let props = doc.mutable_properties();
+            MutableDict::set_encryptable_value(
+                &props,
+                "card",
+                &Encryptable::create_with_string("4111"),
+            );
+            db.save_document(&mut doc).expect("save");
+            assert_eq!(
+                doc.properties()
+                    .get("card")
+                    .get_encryptable_value()
+                    .get_value()
+                    .as_string(),
+                Some("4111")
+            );
+            let stored = other_db.get_document("foo").unwrap();
+            assert!(!stored.properties().to_json().contains("4111"));
+            assert!(!stored.properties().to_json().contains("123-45-6789"));
+            assert_eq!(
+                db.get_document("foo")
+                    .unwrap()
+                    .properties()
+                    .get("card")
+                    .get_encryptable_value()
+                    .get_value()
+                    .as_string(),
+                Some("4111")
+            );
+        }
+
+        // Values encrypted with a previous key are still decrypted
+        keys.rotate("k2", EncryptionKey::from_bytes([8; 32]));
+        let ssn = props
+            .get("ssn")
+            .try_get_encryptable_value(db, "ssn")
+            .unwrap();
+        assert_eq!(ssn.get_value().as_string(), Some("123-45-6789"));
+
+        // Another connection to the database doesn't have the keys: decryption fails
+        let other = db.clone();
+        assert_eq!(
+            props
+                .get("ssn")
+                .try_get_encryptable_value(&other_db, "ssn")
+                .err()
+                .map(|err| err.code),
+            crypto_error
+        );
+        assert!(props
+            .get("ssn")
+            .try_get_encryptable_value(&other, "ssn")
+            .is_ok());
+
+        // And stores new values in plaintext
+        let mut doc = other_db.get_document("foo").unwrap();
+        let mut props = doc.mutable_properties();
+        props
+            .at("pin")
+            .try_put_encrypt(&Encryptable::create_with_string("0000"), &other_db, "pin")
+            .unwrap();
+        other_db.save_document(&mut doc).expect("save");
+        assert!(db
+            .get_document("foo")
+            .unwrap()
+            .properties()
+            .to_json()
+            .contains("0000"));
+
+        // Without a current key, nothing is stored and the failure is reported
+        keys.remove_key("k2");
+        let mut doc = db.get_document("foo").unwrap();
+        let props = doc.mutable_properties();
+        assert_eq!(
+            MutableDict::try_set_encryptable_value(
+                &props,
+                "token",
+                &Encryptable::create_with_string("abc"),
+                db,
+            )
+            .err()
+            .map(|err| err.code),
+            crypto_error
+        );
+        assert_eq!(props.get("token").get_type(), ValueType::Undefined);
+        drop(props);
+        drop(doc);
+        drop(other);
+        other_db.close().unwrap();
+    });
+}
+
+#[test]
+fn db_encryption_key_options() {
+    let tmp_dir = TempDir::new("cbl_rust").expect("create temp dir");
+    let raw_key = EncryptionKey::from_bytes([7; 32]);
+    assert_eq!(raw_key.bytes(), &[7; 32]);
+
+    let cfg_plain = DatabaseConfig::new().directory(tmp_dir.path());
+    let cfg_raw = cfg_plain.clone().encryption_key(raw_key.clone());
+
+    // Encrypt with the raw key
+    {
+        let mut db = Database::open_with_config(utils::DB_NAME, &cfg_raw).unwrap();
+        let mut doc = Document::new_with_id("foo");
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+            .unwrap();
+        assert_eq!(db.config().get_encryption_key().unwrap().bytes(), &[7; 32]);
+    }
+    assert!(Database::open_with_config(utils::DB_NAME, &cfg_plain).is_err());
+
+    // Decrypt it again
+    {
+        let mut db = Database::open_with_config(utils::DB_NAME, &cfg_raw).unwrap();
+        assert!(db.change_encryption_key(None).is_ok());
+    }
+    assert!(Database::open_with_config(utils::DB_NAME, &cfg_raw).is_err());
+    let db = Database::open_with_config(utils::DB_NAME, &cfg_plain).unwrap();
+    assert!(db.get_document("foo").is_ok());
+    assert!(db.config().get_encryption_key().is_none());
+}
+
+#[test]
+fn db_stats() {
+    utils::with_db(|db| {
+        let empty = db.stats().unwrap();
+        assert_eq!(empty.document_count, 0);
+        assert_eq!(empty.deleted_count, 0);
+        assert_eq!(empty.attachments_size, 0);
+        assert!(empty.file_size > 0);
+
+        let mut doc = Document::new_with_id("with_blob");
+        let mut blob = Blob::new_from_data(&[1; 1000], "application/octet-stream");
+        doc.mutable_properties().at("blob").put_blob(&mut blob);
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+            .unwrap();
+        let mut doc = Document::new_with_id("deleted");
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+            .unwrap();
+        db.delete_document(&doc).unwrap();
+        db.create_index(
+            "idx",
+            &index::ValueIndexConfiguration::new(QueryLanguage::N1QL, "name"),
+        )
+        .unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.document_count, 1);
+        assert_eq!(stats.deleted_count, 1);
+        assert_eq!(stats.index_count, 1);
+        assert_eq!(stats.attachments_size, 1000);
+        assert_eq!(
+            stats.total_size(),
+            stats.file_size + stats.wal_size + stats.attachments_size
+        );
+    });
+}
+
+#[test]
+fn maintenance_policy() {
+    utils::with_db(|db| {
+        let policy = MaintenancePolicy::new()
+            .compact_after_deletions(2)
+            .optimize_every(Duration::from_secs(3600));
+
+        // Optimizing has never run, so it is due:
+        let report = db.maybe_run_maintenance(&policy).unwrap();
+        assert_eq!(report.runs.len(), 1);
+        assert_eq!(report.runs[0].maintenance_type, MaintenanceType::Optimize);
+        assert_eq!(report.runs[0].trigger, MaintenanceTrigger::Interval);
+        assert!(db.maybe_run_maintenance(&policy).unwrap().is_empty());
+        // The state isn't stored in the database itself:
+        assert_eq!(db.count(), 0);
+
+        for id in &["foo", "bar"] {
+            let mut doc = Document::new_with_id(id);
+            db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+                .unwrap();
+            db.delete_document(&doc).unwrap();
+        }
+        let report = db.maybe_run_maintenance(&policy).unwrap();
+        assert_eq!(report.runs.len(), 1);
+        assert_eq!(report.runs[0].maintenance_type, MaintenanceType::Compact);
+        assert_eq!(report.runs[0].trigger, MaintenanceTrigger::Deletions(2));
+        assert!(db.maybe_run_maintenance(&policy).unwrap().is_empty());
+
+        // On a background thread:
+        let policy = MaintenancePolicy::new().check_integrity_every(Duration::from_secs(3600));
+        let scheduler = db.start_maintenance(policy, Duration::from_millis(50));
+        let report = scheduler
+            .reports()
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.runs.len(), 1);
+        assert_eq!(
+            report.runs[0].maintenance_type,
+            MaintenanceType::IntegrityCheck
+        );
+        let report = scheduler
+            .reports()
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert!(report.is_empty());
+        drop(scheduler);
+    });
+}
+
+#[test]
+fn check_integrity() {
+    utils::with_db(|db| {
+        let mut doc = Document::new_with_id("with_blobs");
+        let mut blob = Blob::new_from_data(b"attached", "text/plain");
+        let mut props = doc.mutable_properties();
+        props.at("blob").put_blob(&mut blob);
+        let mut photos = MutableArray::new();
+        photos.append().put_blob(&mut blob);
+        props.at("photos").put_value(&photos);
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+            .unwrap();
+        assert!(db.check_integrity().unwrap().is_ok());
+
+        // Remove the blob's content behind the database's back:
+        let attachments = db.path().join("Attachments");
+        for entry in std::fs::read_dir(&attachments).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+
+        let report = db.check_integrity().unwrap();
+        assert!(!report.is_corrupt());
+        let paths: Vec<&str> = report
+            .problems
+            .iter()
+            .map(|problem| match problem {
+                IntegrityProblem::MissingBlob {
+                    document_id,
+                    path,
+                    digest,
+                } => {
+                    assert_eq!(document_id, "with_blobs");
+                    assert_eq!(digest, blob.digest());
+                    path.as_str()
+                }
+                problem => panic!("unexpected problem {:?}", problem),
+            })
+            .collect();
+        assert_eq!(paths, vec!["blob", "photos[0]"]);
+    });
+}
+
+#[test]
+fn backup_and_restore() {
+    init_logging();
+    let tmp_dir = TempDir::new("cbl_rust").expect("create temp dir");
+    let cfg = DatabaseConfig::new()
+        .directory(tmp_dir.path())
+        .encryption_key(EncryptionKey::from_bytes([3; 32]));
+    let mut db = Database::open_with_config(utils::DB_NAME, &cfg).unwrap();
+    let mut doc = Document::new_with_id("foo");
+    let mut blob = Blob::new_from_data(b"attached", "text/plain");
+    doc.mutable_properties().at("blob").put_blob(&mut blob);
+    db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+        .unwrap();
+    let index = index::ValueIndexConfiguration::new(QueryLanguage::N1QL, "blob");
+    db.create_index("idx_blob", &index).unwrap();
+
+    let mut reports = vec![];
+    let mut progress = |progress: backup::BackupProgress| reports.push(progress);
+    let backup_path = db
+        .backup_to(tmp_dir.path(), "backup", Some(&mut progress))
+        .unwrap();
+    let last = *reports.last().unwrap();
+    assert_eq!(last.bytes_copied, last.total_bytes);
+    assert!(reports
+        .windows(2)
+        .all(|pair| pair[0].bytes_copied < pair[1].bytes_copied));
+    assert!(db.backup_to(tmp_dir.path(), "backup", None).is_err());
+
+    let info = backup::BackupInfo::read(&backup_path).unwrap();
+    assert_eq!(info.source_name, utils::DB_NAME);
+    assert!(info.encrypted);
+    assert!(info.includes_metadata);
+
+    // Changes after the backup are undone by restoring it:
+    let mut doc = Document::new_with_id("bar");
+    db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+        .unwrap();
+    db.delete_index("idx_blob").unwrap();
+    assert_eq!(db.count(), 2);
+
+    // The database must be closed:
+    assert!(Database::restore_from(&backup_path, utils::DB_NAME, tmp_dir.path(), None).is_err());
+    assert_eq!(db.count(), 2);
+    db.close().unwrap();
+
+    Database::restore_from(&backup_path, utils::DB_NAME, tmp_dir.path(), None).unwrap();
+    assert!(!Database::recover_interrupted_restore(utils::DB_NAME, tmp_dir.path()).unwrap());
+    let mut db = Database::open_with_config(utils::DB_NAME, &cfg).unwrap();
+    assert_eq!(db.count(), 1);
+    let doc = db.get_document("foo").unwrap();
+    let blob = doc.properties().get("blob").as_blob().unwrap();
+    assert_eq!(blob.load_content().unwrap(), b"attached");
+    // So is the crate's state about the database, like the configurations of its indexes:
+    assert_eq!(
+        db.index_configuration("idx_blob").unwrap(),
+        Some(index.into())
+    );
+    assert!(db.check_integrity().unwrap().is_ok());
+
+    // The backup stays encrypted:
+    let plain = DatabaseConfig::new().directory(tmp_dir.path());
+    assert!(Database::open_with_config("backup", &plain).is_err());
+}
+
+#[test]
+fn export_import_jsonl() {
+    init_logging();
+    let tmp_dir = TempDir::new("cbl_rust").expect("create temp dir");
+    let cfg = DatabaseConfig::new().directory(tmp_dir.path());
+    let mut source = Database::open_with_config("source", &cfg).unwrap();
+    let mut doc = Document::new_with_id("foo");
+    doc.set_properties_as_json(r#"{"name":"Foo","tags":["a","b"],"nested":{"n":1}}"#)
+        .unwrap();
+    let mut blob = Blob::new_from_data(b"attached content", "text/plain");
+    doc.mutable_properties().at("blob").put_blob(&mut blob);
+    source
+        .save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+        .unwrap();
+    let mut doc = Document::new_with_id("bar");
+    source
+        .save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+        .unwrap();
+    let expiration = Timestamp(4102444800000);
+    source
+        .set_document_expiration("bar", Some(expiration))
+        .unwrap();
+
+    let check = |db: &Database| {
+        assert_eq!(db.count(), 2);
+        let foo = db.get_document("foo").unwrap();
+        assert_eq!(foo.properties().get("name").as_string(), Some("Foo"));
+        assert_eq!(foo.properties().get("tags").as_array().count(), 2);
+        let blob = foo.properties().get("blob").as_blob().unwrap();
+        assert_eq!(blob.load_content().unwrap(), b"attached content");
+        assert_eq!(blob.content_type(), Some("text/plain"));
+        assert_eq!(
+            db.document_expiration("bar").unwrap().map(|t| t.0),
+            Some(expiration.0)
+        );
+    };
+
+    // Blobs inline:
+    let mut dump = vec![];
+    assert_eq!(
+        source
+            .export_jsonl(&mut dump, &jsonl::ExportOptions::default())
+            .unwrap(),
+        2
+    );
+    assert_eq!(String::from_utf8_lossy(&dump).lines().count(), 2);
+    let mut target = Database::open_with_config("inline", &cfg).unwrap();
+    let options = jsonl::ImportOptions {
+        batch_size: 1,
+        ..Default::default()
+    };
+    let report = target.import_jsonl(&dump[..], &options).unwrap();
+    assert_eq!(report.imported, 2);
+    assert!(report.conflicts.is_empty());
+    check(&target);
+
+    // Importing again conflicts:
+    let report = target.import_jsonl(&dump[..], &options).unwrap();
+    assert_eq!(report.imported, 0);
+    assert_eq!(report.conflicts.len(), 2);
+
+    // Blobs in side files:
+    let blob_dir = tmp_dir.path().join("blobs");
+    let mut dump = vec![];
+    let options = jsonl::ExportOptions {
+        blobs: jsonl::BlobExport::Directory(blob_dir.clone()),
+        ..Default::default()
+    };
+    source.export_jsonl(&mut dump, &options).unwrap();
+    assert!(!String::from_utf8_lossy(&dump).contains("\"data\""));
+    let mut target = Database::open_with_config("side_files", &cfg).unwrap();
+    let options = jsonl::ImportOptions {
+        blob_directory: Some(blob_dir),
+        ..Default::default()
+    };
+    assert_eq!(
+        target.import_jsonl(&dump[..], &options).unwrap().imported,
+        2
+    );
+    check(&target);
+
+    assert!(target.import_jsonl(&b"not json\n"[..], &options).is_err());
+}
+
+#[test]
+fn migrator() {
+    utils::with_db(|db| {
+        for i in 0..5 {
+            let mut doc = Document::new_with_id(&format!("user{}", i));
+            doc.set_properties_as_json(&format!(r#"{{"type":"user","name":"First{} Last"}}"#, i))
+                .unwrap();
+            db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+                .unwrap();
+        }
+
+        let fail_on_user3 = std::rc::Rc::new(std::cell::Cell::new(true));
+        let fail = fail_on_user3.clone();
+        let migrator = || {
+            let fail = fail.clone();
+            migration::Migrator::new()
+                .batch_size(2)
+                .step(1, |db| {
+                    let mut doc = Document::new_with_id("settings");
+                    db.save_document_with_concurency_control(
+                        &mut doc,
+                        ConcurrencyControl::LastWriteWins,
+                    )
+                })
+                .transform(2, "type = 'user'", |properties| {
+                    let name = properties.get("name").as_string().unwrap().to_string();
+                    let mut parts = name.split(' ');
+                    properties.at("first").put_string(parts.next().unwrap());
+                    properties.at("last").put_string(parts.next().unwrap());
+                    properties.remove("name");
+                    Ok(())
+                })
+                .transform(10, "type = 'user'", move |properties| {
+                    if fail.get() && properties.get("first").as_string() == Some("First3") {
+                        return Err(Error::from_code(ErrorCode::CouchbaseLite(
+                            CouchbaseLiteError::UnexpectedError,
+                        )));
+                    }
+                    properties.at("version").put_i64(3);
+                    Ok(())
+                })
+        };
+
+        // The third step fails in its second batch, leaving the version of the second one:
+        assert!(migrator().run(db).is_err());
+        assert_eq!(migrator().current_version(db).unwrap(), 2);
+        assert!(db.get_document("settings").is_ok());
+        let user0 = db.get_document("user0").unwrap();
+        assert_eq!(user0.properties().get("first").as_string(), Some("First0"));
+        assert_eq!(user0.properties().get("version").as_i64(), Some(3));
+        let user2 = db.get_document("user2").unwrap();
+        assert_eq!(user2.properties().get("version").as_i64(), None);
+
+        // Running again resumes after the documents already migrated:
+        fail_on_user3.set(false);
+        let report = migrator().run(db).unwrap();
+        assert_eq!(report.from_version, 2);
+        assert_eq!(report.to_version, 10);
+        assert_eq!(report.applied, vec![10]);
+        assert_eq!(report.documents_migrated, 3);
+        for i in 0..5 {
+            let user = db.get_document(&format!("user{}", i)).unwrap();
+            assert_eq!(user.properties().get("version").as_i64(), Some(3));
+            assert_eq!(user.properties().get("last").as_string(), Some("Last"));
+        }
+
+        assert!(migrator().run(db).unwrap().applied.is_empty());
+    });
+}
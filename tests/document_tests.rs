@@ -291,3 +291,21 @@ fn database_document_expiration() {
         assert_eq!(expiration.unwrap().0, 1000000000);
     });
 }
+
+#[test]
+fn document_blob_property() {
+    utils::with_db(|db| {
+        let mut doc = Document::new_with_id("foo");
+        let mut blob = Blob::new_from_data(b"attached", "text/plain");
+        doc.mutable_properties().at("blob").put_blob(&mut blob);
+        db.save_document_with_concurency_control(&mut doc, ConcurrencyControl::LastWriteWins)
+            .unwrap();
+
+        // The blobs of a document's properties stay valid after the `Blob`s are dropped:
+        let doc = db.get_document("foo").expect("get_document");
+        for _ in 0..3 {
+            let blob = doc.properties().get("blob").as_blob().expect("as_blob");
+            assert_eq!(blob.load_content().unwrap(), b"attached");
+        }
+    });
+}
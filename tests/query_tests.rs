@@ -1,9 +1,13 @@
 extern crate couchbase_lite;
 
-use couchbase_lite::index::ValueIndexConfiguration;
+use couchbase_lite::index::{
+    FullTextIndexConfiguration, IndexConfiguration, IndexSchema, ValueIndexConfiguration,
+};
 
 use self::couchbase_lite::*;
 
+use std::time::Duration;
+
 pub mod utils;
 
 #[test]
@@ -84,3 +88,552 @@ fn indexes() {
         assert_eq!(db.get_index_names().count(), 0);
     });
 }
+
+#[test]
+fn query_parse_error() {
+    utils::with_db(|db| {
+        let query_str = "SELECT i\nFROM _ WHERE i >";
+        let error = Query::new(db, QueryLanguage::N1QL, query_str)
+            .err()
+            .expect("query should not compile");
+        assert_eq!(
+            error.code,
+            ErrorCode::CouchbaseLite(CouchbaseLiteError::InvalidQuery)
+        );
+
+        let parse_error = error
+            .query_parse_error(query_str)
+            .expect("parse error position");
+        assert_eq!(error.query_position(), Some(parse_error.position));
+        assert_eq!(parse_error.query, query_str);
+        assert_eq!(parse_error.line, 2);
+        assert!(parse_error.position > "SELECT i\n".len());
+        assert_eq!(
+            parse_error.column,
+            parse_error.position - "SELECT i\n".len() + 1
+        );
+
+        let rendered = parse_error.render_caret();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2].trim_start(), "^");
+        assert_eq!(lines[2].len(), parse_error.column);
+    });
+}
+
+#[test]
+fn query_builder() {
+    use couchbase_lite::query_builder::*;
+
+    utils::with_db(|db| {
+        utils::add_doc(db, "doc-1", 1, "one");
+        utils::add_doc(db, "doc-2", 2, "two");
+        utils::add_doc(db, "doc-3", 3, "three");
+
+        let builder = select([prop("s").into(), meta_id().as_("id")])
+            .from_db()
+            .where_(prop("i").gt(param("min")).and(prop("s").is_valued()))
+            .order_by([prop("i").descending()])
+            .limit(10);
+        assert_eq!(
+            builder.to_n1ql().unwrap(),
+            "SELECT `s`, meta().id AS `id` FROM _ WHERE (`i` > $min) AND (`s` IS VALUED) \
+             ORDER BY `i` DESC LIMIT 10"
+        );
+
+        let mut params = MutableDict::new();
+        params.at("min").put_i64(1);
+        for language in [QueryLanguage::N1QL, QueryLanguage::JSON] {
+            let query = builder.build(db, language).expect("build query");
+            assert_eq!(query.column_names(), vec!["s", "id"]);
+            query.set_parameters(&params);
+            let rows: Vec<String> = query
+                .execute()
+                .expect("execute")
+                .map(|row| row.as_array().to_json())
+                .collect();
+            assert_eq!(rows, vec![r#"["three","doc-3"]"#, r#"["two","doc-2"]"#]);
+        }
+    });
+}
+
+#[test]
+fn query_builder_quoting() {
+    use couchbase_lite::query_builder::*;
+
+    utils::with_db(|db| {
+        let builder = select([SelectResult::all_of("db")])
+            .from_db_as("db")
+            .where_(
+                prop("it's")
+                    .of("db")
+                    .eq("it's")
+                    .or(prop(r"a\.b").in_([1, 2]))
+                    .or(any("v", prop("items"), var("v.k").eq(1)))
+                    .or(prop("tags[0]").not_eq(null())),
+            );
+        assert_eq!(
+            builder.to_n1ql().unwrap(),
+            "SELECT `db`.* FROM _ AS `db` WHERE (((`db`.`it's` = 'it''s') \
+             OR (`a.b` IN (1, 2))) OR ANY v IN `items` SATISFIES v.`k` = 1 END) \
+             OR (`tags`[0] != NULL)"
+        );
+        for language in [QueryLanguage::N1QL, QueryLanguage::JSON] {
+            builder.build(db, language).expect("build query");
+        }
+    });
+}
+
+#[test]
+fn query_builder_names() {
+    use couchbase_lite::query_builder::*;
+
+    assert!(is_identifier("lower"));
+    assert!(is_identifier("_v2"));
+    assert!(!is_identifier("2v"));
+    assert!(!is_identifier("lower() OR TRUE OR lower"));
+    assert!(std::panic::catch_unwind(|| func("count(*) --", [prop("i")])).is_err());
+    assert!(std::panic::catch_unwind(|| var("v) OR (1.k")).is_err());
+    assert!(std::panic::catch_unwind(|| param("p OR 1")).is_err());
+
+    utils::with_db(|db| {
+        let injected = Expression::Function("lower) OR (TRUE".to_string(), vec![prop("s")]);
+        let builder = select([prop("s")]).from_db().where_(injected);
+        assert!(builder.to_n1ql().is_err());
+        assert!(builder.build(db, QueryLanguage::JSON).is_err());
+
+        for f in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let builder = select([prop("s")]).from_db().where_(prop("i").gt(f));
+            assert_eq!(
+                builder.to_n1ql().err().map(|err| err.code),
+                Some(ErrorCode::CouchbaseLite(
+                    CouchbaseLiteError::InvalidParameter
+                ))
+            );
+        }
+        let builder = select([prop("s")]).from_db().where_(prop("i").gt(1.5));
+        assert_eq!(
+            builder.to_n1ql().unwrap(),
+            "SELECT `s` FROM _ WHERE `i` > 1.5"
+        );
+        builder.build(db, QueryLanguage::N1QL).expect("build query");
+
+        // Negative numbers are parenthesized, and negated into positive ones, as `--` starts a
+        // comment
+        let builder = select([literal(-1).negate().as_("one")]).from_db().where_(
+            prop("i")
+                .subtract(-2)
+                .gt(literal(-1.5).negate())
+                .or(prop("i").gt(-3)),
+        );
+        assert_eq!(
+            builder.to_n1ql().unwrap(),
+            "SELECT 1 AS `one` FROM _ WHERE ((`i` - (-2)) > (1.5)) OR (`i` > (-3))"
+        );
+        utils::add_doc(db, "doc-1", 1, "one");
+        for language in [QueryLanguage::N1QL, QueryLanguage::JSON] {
+            let query = builder.build(db, language).expect("build query");
+            let rows: Vec<String> = query
+                .execute()
+                .expect("execute")
+                .map(|row| row.as_array().to_json())
+                .collect();
+            assert_eq!(rows, vec!["[1]"]);
+        }
+    });
+}
+
+#[test]
+fn query_bind() {
+    utils::with_db(|db| {
+        utils::add_doc(db, "doc-1", 1, "one");
+        utils::add_doc(db, "doc-2", 2, "two");
+        utils::add_doc(db, "doc-3", 3, "three");
+
+        let query = Query::new(
+            db,
+            QueryLanguage::N1QL,
+            "SELECT s FROM _ WHERE i >= $min AND ARRAY_CONTAINS($names, s) \
+             AND s != '$quoted' AND s != 'it''s $escaped' ORDER BY i",
+        )
+        .expect("create query");
+        assert_eq!(
+            query.parameter_names(),
+            Some(&["min".to_string(), "names".to_string()][..])
+        );
+
+        // Missing and unknown parameters are rejected:
+        assert!(query.validate_parameters().is_err());
+        assert!(query.execute().is_err());
+        assert!(query.bind("quoted", "x").is_err());
+
+        query.bind("min", &2_u64).expect("bind");
+        assert!(query.validate_parameters().is_err());
+        assert!(query.execute().is_err());
+        assert_eq!(query.execute_unchecked().expect("execute").count(), 0);
+        query.bind("names", &["one", "three"]).expect("bind");
+        query.validate_parameters().expect("validate");
+        assert_eq!(query.parameters().get("min").as_i64(), Some(2));
+        let rows: Vec<String> = query
+            .execute()
+            .expect("execute")
+            .map(|row| row.get(0).as_string().unwrap().to_string())
+            .collect();
+        assert_eq!(rows, vec!["three"]);
+
+        let query = Query::new(
+            db,
+            QueryLanguage::JSON,
+            r#"{"WHAT": [[".s"]], "WHERE": ["AND", ["=", [".i"], [ "$i"]], ["!=", [".s"], "$s"]]}"#,
+        )
+        .expect("create query");
+        assert_eq!(query.parameter_names(), Some(&["i".to_string()][..]));
+        query.bind("i", &Some(1_i64)).expect("bind");
+        assert_eq!(query.execute().expect("execute").count(), 1);
+    });
+}
+
+#[test]
+fn referenced_parameters() {
+    let names = |language, str| Query::referenced_parameters(language, str);
+    assert_eq!(
+        names(
+            QueryLanguage::N1QL,
+            "SELECT $a, `$b`, \"$c\", '$d', 'it''s $e', 'it\\'s $f', $g_1 -- $h\n\
+             /* $i * / */ $a + $j/*$k*/-$l -$m"
+        ),
+        vec!["a", "g_1", "j", "l", "m"]
+    );
+    assert_eq!(
+        names(
+            QueryLanguage::JSON,
+            r#"{"WHAT": [["$a"], "$b", ["=", ["$c"], "[\"$d\"]"]], "WHERE": [" $e"]}"#
+        ),
+        vec!["a", "c"]
+    );
+    assert!(names(QueryLanguage::JSON, "[\"$a\"").is_empty());
+}
+
+#[test]
+fn live_query() {
+    utils::with_db(|db| {
+        utils::add_doc(db, "doc-1", 1, "one");
+        utils::add_doc(db, "doc-2", 2, "two");
+        utils::add_doc(db, "doc-3", 3, "three");
+
+        let mut query = Query::new(
+            db,
+            QueryLanguage::N1QL,
+            "SELECT meta().id AS id, i FROM _ ORDER BY i",
+        )
+        .expect("create query");
+        assert!(query.live("nope").is_err());
+
+        let live = query.live("id").expect("live");
+        let change = live
+            .changes()
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(change.diff.inserted, vec!["doc-1", "doc-2", "doc-3"]);
+        assert!(change.diff.removed.is_empty() && change.diff.changed.is_empty());
+        assert_eq!(change.results.as_array().count(), 3);
+
+        db.in_transaction(|db| {
+            utils::add_doc(db, "doc-4", 4, "four");
+            let mut doc = db.get_document("doc-2")?;
+            doc.mutable_properties().at("i").put_i64(20);
+            db.save_document(&mut doc)?;
+            let doc = db.get_document("doc-3")?;
+            db.delete_document(&doc)
+        })
+        .expect("transaction");
+
+        let change = live
+            .changes()
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(change.diff.inserted, vec!["doc-4"]);
+        assert_eq!(change.diff.removed, vec!["doc-3"]);
+        assert_eq!(change.diff.changed, vec!["doc-2"]);
+        let ids: Vec<String> = change
+            .results
+            .as_array()
+            .iter()
+            .map(|row| row.as_dict().get("id").as_string().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, vec!["doc-1", "doc-4", "doc-2"]);
+    });
+}
+
+#[test]
+fn query_plan() {
+    utils::with_db(|db| {
+        assert!(db
+            .create_index(
+                "idx_i",
+                &ValueIndexConfiguration::new(QueryLanguage::N1QL, "i"),
+            )
+            .unwrap());
+
+        let query = Query::new(db, QueryLanguage::N1QL, "SELECT s FROM _ WHERE i = 1")
+            .expect("create query");
+        let plan = query.plan().expect("plan");
+        assert!(plan.sql.starts_with("SELECT"));
+        assert!(plan.json.starts_with('{'));
+        assert!(!plan.steps.is_empty());
+        assert!(plan.uses_index("idx_i"));
+        assert_eq!(plan.indexes(), vec!["idx_i"]);
+        assert!(!plan.has_table_scan());
+
+        let query = Query::new(db, QueryLanguage::N1QL, "SELECT s FROM _ WHERE s = 'one'")
+            .expect("create query");
+        let plan = query.plan().expect("plan");
+        assert!(!plan.uses_index("idx_i"));
+        assert!(plan.has_table_scan());
+    });
+}
+
+#[test]
+fn sync_indexes() {
+    utils::with_db(|db| {
+        assert!(db
+            .create_index(
+                "obsolete",
+                &ValueIndexConfiguration::new(QueryLanguage::N1QL, "s"),
+            )
+            .unwrap());
+        assert!(db
+            .create_index(
+                "by_i",
+                &ValueIndexConfiguration::new(QueryLanguage::N1QL, "i"),
+            )
+            .unwrap());
+
+        let schema = IndexSchema::new()
+            .index(
+                "by_i",
+                ValueIndexConfiguration::new(QueryLanguage::N1QL, "i, s"),
+            )
+            .index(
+                "text",
+                FullTextIndexConfiguration::new(QueryLanguage::N1QL, "s", true, Some("en")),
+            );
+        let report = db.sync_indexes(&schema).expect("sync_indexes");
+        assert_eq!(report.created, vec!["text"]);
+        assert_eq!(report.recreated, vec!["by_i"]);
+        assert_eq!(report.dropped, vec!["obsolete"]);
+        assert!(report.unchanged.is_empty());
+        assert!(report.adopted.is_empty());
+
+        assert_eq!(
+            db.index_configuration("by_i").unwrap(),
+            Some(IndexConfiguration::Value(ValueIndexConfiguration::new(
+                QueryLanguage::N1QL,
+                "i, s"
+            )))
+        );
+        assert_eq!(db.index_configuration("obsolete").unwrap(), None);
+
+        let report = db.sync_indexes(&schema).expect("sync_indexes");
+        assert!(report.is_empty());
+        assert_eq!(report.unchanged, vec!["by_i", "text"]);
+
+        let mut names: Vec<String> = db
+            .get_index_names()
+            .iter()
+            .map(|name| name.as_string().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["by_i", "text"]);
+    });
+}
+
+#[test]
+fn paged_query() {
+    use couchbase_lite::query_builder::*;
+
+    utils::with_db(|db| {
+        for i in 0..7 {
+            utils::add_doc(db, &format!("doc-{}", i), i % 3, "x");
+        }
+        utils::add_doc(db, "other", 1, "y");
+
+        let paged = select([prop("i").into(), meta_id().as_("id")])
+            .where_(prop("s").eq(param("s")))
+            .paginate(db, QueryLanguage::N1QL, prop("i"), 3)
+            .expect("paginate");
+        paged.bind("s", "x").expect("bind");
+
+        let mut ids = vec![];
+        let mut token: Option<String> = None;
+        let mut pages = 0;
+        loop {
+            let page = paged.page(token.as_deref()).expect("page");
+            pages += 1;
+            for row in &page.rows {
+                assert_eq!(row.count(), 2);
+                ids.push(row.get("id").as_string().unwrap().to_string());
+            }
+            match page.next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(
+            ids,
+            vec!["doc-0", "doc-3", "doc-6", "doc-1", "doc-4", "doc-2", "doc-5"]
+        );
+
+        assert!(paged.page(Some("not a token")).is_err());
+        assert!(paged.bind("_page_key", &1).is_err());
+
+        // A full last page is the last one
+        let paged = select([meta_id().as_("id")])
+            .where_(prop("s").eq(literal("x")))
+            .paginate(db, QueryLanguage::N1QL, prop("i"), 7)
+            .expect("paginate");
+        let page = paged.page(None).expect("page");
+        assert_eq!(page.rows.len(), 7);
+        assert!(page.next.is_none());
+
+        // Reserved names
+        assert!(select([prop("i").as_("_page_key")])
+            .paginate(db, QueryLanguage::N1QL, prop("i"), 3)
+            .is_err());
+        assert!(select([prop("i").as_("i")])
+            .where_(prop("i").gt(param("_page_id")))
+            .paginate(db, QueryLanguage::N1QL, prop("i"), 3)
+            .is_err());
+    });
+}
+
+#[test]
+fn export_results() {
+    utils::with_db(|db| {
+        let mut doc = Document::new_with_id("doc-1");
+        doc.set_properties_as_json(
+            r#"{"name":"a, \"b\"","address":{"city":"Paris","zip":75001},"tags":["x","y"]}"#,
+        )
+        .unwrap();
+        db.save_document(&mut doc).expect("save");
+        let mut doc = Document::new_with_id("doc-2");
+        doc.set_properties_as_json(r#"{"name":"c","tags":[]}"#)
+            .unwrap();
+        db.save_document(&mut doc).expect("save");
+
+        let query = Query::new(
+            db,
+            QueryLanguage::N1QL,
+            "SELECT name, address, tags FROM _ ORDER BY name",
+        )
+        .expect("create query");
+
+        let mut out = vec![];
+        let count = query.execute().unwrap().write_json_lines(&mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"name\":\"a, \\\"b\\\"\",\"address\":{\"city\":\"Paris\",\"zip\":75001},\"tags\":[\"x\",\"y\"]}\n\
+             {\"name\":\"c\",\"tags\":[]}\n"
+        );
+
+        let mut out = vec![];
+        query
+            .execute()
+            .unwrap()
+            .write_csv(&mut out, &CsvOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "name,address,tags\r\n\
+             \"a, \"\"b\"\"\",\"{\"\"city\"\":\"\"Paris\"\",\"\"zip\"\":75001}\",\"[\"\"x\"\",\"\"y\"\"]\"\r\n\
+             c,,[]\r\n"
+        );
+
+        let mut out = vec![];
+        query
+            .execute()
+            .unwrap()
+            .write_csv(
+                &mut out,
+                &CsvOptions {
+                    delimiter: ';',
+                    nested: NestedValues::Flatten,
+                    ..CsvOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "name;address.city;address.zip;tags.0;tags.1\r\n\
+             \"a, \"\"b\"\"\";Paris;75001;x;y\r\n\
+             c;;;;\r\n"
+        );
+    });
+}
+
+#[test]
+fn prepared_queries() {
+    utils::with_db(|db| {
+        utils::add_doc(db, "doc-1", 1, "one");
+
+        let text = "SELECT s FROM _ WHERE i = $i";
+        let query = db.prepared(QueryLanguage::N1QL, text).expect("prepared");
+        assert_eq!(db.prepared_query_count(), 1);
+        query.bind("i", &1).unwrap();
+        assert_eq!(query.execute().unwrap().count(), 1);
+
+        // A cached query shares its compiled form, parameters included:
+        let again = db.prepared(QueryLanguage::N1QL, text).expect("prepared");
+        assert_eq!(db.prepared_query_count(), 1);
+        assert_eq!(again.parameters().get("i").as_i64(), Some(1));
+        assert_eq!(db.clone().prepared_query_count(), 1);
+
+        db.prepared(QueryLanguage::N1QL, "SELECT i FROM _").unwrap();
+        assert_eq!(db.prepared_query_count(), 2);
+        assert!(db.prepared(QueryLanguage::N1QL, "SELECT FROM").is_err());
+        assert_eq!(db.prepared_query_count(), 2);
+
+        // Least recently used queries are evicted first:
+        db.prepared(QueryLanguage::N1QL, text).unwrap();
+        db.set_prepared_query_capacity(1);
+        assert_eq!(db.prepared_query_count(), 1);
+        let query = db.prepared(QueryLanguage::N1QL, text).unwrap();
+        assert_eq!(query.parameters().get("i").as_i64(), Some(1));
+        db.prepared(QueryLanguage::N1QL, "SELECT i FROM _").unwrap();
+        assert_eq!(db.prepared_query_count(), 1);
+
+        // Index changes, through any connection, invalidate the cache:
+        let other = Database::open(
+            db.name(),
+            Some(DatabaseConfiguration {
+                directory: db.config().get_directory().unwrap(),
+                encryption_key: None,
+            }),
+        )
+        .unwrap();
+        other
+            .create_index(
+                "idx_i",
+                &ValueIndexConfiguration::new(QueryLanguage::N1QL, "i"),
+            )
+            .unwrap();
+        assert_eq!(db.prepared_query_count(), 0);
+        let query = db.prepared(QueryLanguage::N1QL, text).unwrap();
+        query.bind("i", &1).unwrap();
+        assert_eq!(db.prepared_query_count(), 1);
+
+        // Including an index re-created under the same name:
+        other.delete_index("idx_i").unwrap();
+        other
+            .create_index(
+                "idx_i",
+                &ValueIndexConfiguration::new(QueryLanguage::N1QL, "s"),
+            )
+            .unwrap();
+        other.close().unwrap();
+        let query = db.prepared(QueryLanguage::N1QL, text).unwrap();
+        assert_eq!(query.parameters().get("i").as_i64(), None);
+    });
+}
@@ -18,8 +18,15 @@
 extern crate couchbase_lite;
 
 use self::couchbase_lite::*;
+use self::couchbase_lite::conflict::{ConflictPolicy, ConflictStrategy, FieldMerge, MergeRule};
+use self::couchbase_lite::metrics::ReplicationMetrics;
 use encryptable::Encryptable;
-use std::{time::Duration, thread};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+    thread,
+};
 
 pub mod utils;
 
@@ -477,71 +484,232 @@ fn conflict_resolver_save_keep_remote() {
     });
 }
 
+#[test]
+fn conflict_policy() {
+    utils::with_db(|_| {
+        let revision = |json: &str| {
+            let mut doc = Document::new_with_id("foo");
+            doc.set_properties_as_json(json).unwrap();
+            Some(doc)
+        };
+        let value = |doc: Option<Document>| doc.map(|doc| doc.properties().to_json());
+
+        // Ready-made strategies
+        let local = || revision(r#"{"v":1,"at":"2022-01-02T00:00:00Z"}"#);
+        let remote = || revision(r#"{"v":2,"at":"2022-01-01T00:00:00Z"}"#);
+        assert_eq!(
+            value(ConflictStrategy::LocalWins.resolve("foo", local(), remote())),
+            value(local())
+        );
+        assert_eq!(
+            value(ConflictStrategy::RemoteWins.resolve("foo", local(), remote())),
+            value(remote())
+        );
+        assert!(ConflictStrategy::DeleteWins
+            .resolve("foo", local(), None)
+            .is_none());
+        assert_eq!(
+            value(ConflictStrategy::LastWriteWins("at".to_string()).resolve(
+                "foo",
+                local(),
+                remote()
+            )),
+            value(local())
+        );
+
+        // Three-way field merge
+        let merge = FieldMerge::new()
+            .rule("stats.views", MergeRule::Sum)
+            .rule("tags", MergeRule::Union)
+            .ancestor(move |_| revision(r#"{"title":"a","stats":{"views":10},"tags":["x","y"]}"#));
+        let merged = ConflictStrategy::Merge(merge).resolve(
+            "foo",
+            revision(r#"{"title":"b","stats":{"views":13},"tags":["x","y","z"]}"#),
+            revision(r#"{"title":"a","stats":{"views":15},"tags":["x","w"],"new":true}"#),
+        );
+        let merged = merged.unwrap();
+        let properties = merged.properties();
+        assert_eq!(properties.get("title").as_string(), Some("b"));
+        assert_eq!(
+            properties.get("stats").as_dict().get("views").as_i64(),
+            Some(18)
+        );
+        assert_eq!(
+            properties
+                .get("tags")
+                .as_array()
+                .iter()
+                .map(|tag| tag.as_string().unwrap().to_string())
+                .collect::<Vec<_>>(),
+            vec!["x", "z", "w"]
+        );
+        assert_eq!(properties.get("new").as_bool(), Some(true));
+
+        // Without ancestor, a counter keeps the greater value
+        let merged = ConflictStrategy::Merge(FieldMerge::new().rule("n", MergeRule::Sum)).resolve(
+            "foo",
+            revision(r#"{"n":3}"#),
+            revision(r#"{"n":2}"#),
+        );
+        assert_eq!(merged.unwrap().properties().get("n").as_i64(), Some(3));
+
+        // Composed policy
+        let policy = ConflictPolicy::new(ConflictStrategy::LocalWins)
+            .delete_wins()
+            .for_documents(|id| id.starts_with("remote:"), ConflictStrategy::RemoteWins);
+        assert_eq!(
+            value(policy.resolve("foo", local(), remote())),
+            value(local())
+        );
+        assert_eq!(
+            value(policy.resolve("remote:foo", local(), remote())),
+            value(remote())
+        );
+        assert!(policy.resolve("remote:foo", None, remote()).is_none());
+    });
+}
+
+#[test]
+fn conflict_policy_merge() {
+    let context1 = ReplicationConfigurationContext {
+        conflict_resolver: Some(
+            ConflictPolicy::new(ConflictStrategy::Merge(
+                FieldMerge::new()
+                    .rule("i", MergeRule::Sum)
+                    .fallback(MergeRule::Local),
+            ))
+            .into_resolver(),
+        ),
+        ..Default::default()
+    };
+    let context2 = ReplicationConfigurationContext::default();
+
+    let mut tester = utils::ReplicationThreeDbsTester::new(
+        utils::ReplicationTestConfiguration::default(),
+        utils::ReplicationTestConfiguration::default(),
+        Box::new(context1),
+        Box::new(context2),
+    );
+
+    tester.test(|local_db1, local_db2, central_db, repl1, _| {
+        // Save doc 'foo'
+        utils::add_doc(local_db1, "foo", 1, "Hello World!");
+        assert!(utils::check_callback_with_wait(
+            || local_db2.get_document("foo").is_ok(),
+            None
+        ));
+
+        // Modify 'foo' in DB 1, while its replication is stopped, and in DB 2
+        repl1.stop(None);
+        let mut foo = local_db1.get_document("foo").unwrap();
+        foo.mutable_properties().at("i").put_i64(2);
+        foo.mutable_properties().at("s").put_string("DB 1");
+        local_db1
+            .save_document_with_concurency_control(&mut foo, ConcurrencyControl::FailOnConflict)
+            .expect("save");
+
+        let mut foo = local_db2.get_document("foo").unwrap();
+        foo.mutable_properties().at("i").put_i64(3);
+        local_db2
+            .save_document_with_concurency_control(&mut foo, ConcurrencyControl::FailOnConflict)
+            .expect("save");
+        assert!(utils::check_callback_with_wait(
+            || central_db
+                .get_document("foo")
+                .unwrap()
+                .properties()
+                .get("i")
+                .as_i64_or_0()
+                == 3,
+            None
+        ));
+
+        // Restart DB 1 replication: the conflict is resolved with a merged revision, without an
+        // ancestor, so the counter keeps the greater value and the other fields the local ones
+        repl1.start(false);
+        assert!(utils::check_callback_with_wait(
+            || {
+                let foo = local_db1.get_document("foo").unwrap();
+                let properties = foo.properties();
+                properties.get("i").as_i64_or_0() == 3
+                    && properties.get("s").as_string() == Some("DB 1")
+            },
+            None
+        ));
+        assert!(utils::check_callback_with_wait(
+            || central_db
+                .get_document("foo")
+                .unwrap()
+                .properties()
+                .get("s")
+                .as_string()
+                == Some("DB 1"),
+            None
+        ));
+    });
+}
+
+#[test]
+fn field_merge_removed_property() {
+    fn document(json: &str) -> Document {
+        let mut doc = Document::new_with_id("foo");
+        doc.set_properties_as_json(json).unwrap();
+        doc
+    }
+
+    // 'b' and 'c.y' are removed locally, 'd' remotely, and 'a' is changed remotely
+    let merge =
+        FieldMerge::new().ancestor(|_| Some(document(r#"{"a":1,"b":2,"c":{"x":1,"y":2},"d":3}"#)));
+    let local = document(r#"{"a":1,"c":{"x":1},"d":3}"#);
+    let remote = document(r#"{"a":2,"b":2,"c":{"x":1,"y":2}}"#);
+
+    let merged = merge.merge("foo", &local, &remote);
+    let properties = merged.properties();
+    assert_eq!(properties.count(), 2);
+    assert_eq!(properties.get("a").as_i64(), Some(2));
+    assert_eq!(properties.get("b").get_type(), ValueType::Undefined);
+    assert_eq!(properties.get("d").get_type(), ValueType::Undefined);
+    let c = properties.get("c").as_dict();
+    assert_eq!(c.count(), 1);
+    assert_eq!(c.get("x").as_i64(), Some(1));
+    assert_eq!(properties.to_json(), r#"{"a":2,"c":{"x":1}}"#);
+}
+
 // Encryption/Decryption
 
 fn encryptor(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    input: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &mut PropertyCryptoContext,
+    input: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Ok(input.iter().map(|u| u ^ 48).collect())
 }
 fn decryptor(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    input: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &PropertyCryptoContext,
+    input: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Ok(input.iter().map(|u| u ^ 48).collect())
 }
 fn encryptor_err_temporary(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    _: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &mut PropertyCryptoContext,
+    _: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Err(EncryptionError::Temporary)
 }
 fn decryptor_err_temporary(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    _: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &PropertyCryptoContext,
+    _: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Err(EncryptionError::Temporary)
 }
 fn encryptor_err_permanent(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    _: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &mut PropertyCryptoContext,
+    _: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Err(EncryptionError::Permanent)
 }
 fn decryptor_err_permanent(
-    _document_id: Option<String>,
-    _properties: Dict,
-    _key_path: Option<String>,
-    _: Vec<u8>,
-    _algorithm: Option<String>,
-    _kid: Option<String>,
-    _error: &Error,
+    _context: &PropertyCryptoContext,
+    _: &[u8],
 ) -> std::result::Result<Vec<u8>, EncryptionError> {
     Err(EncryptionError::Permanent)
 }
@@ -549,13 +717,13 @@ fn decryptor_err_permanent(
 #[test]
 fn encryption_ok_decryption_ok() {
     let context1 = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
     let context2 = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -611,6 +779,89 @@ fn encryption_ok_decryption_ok() {
     });
 }
 
+#[test]
+fn encryption_with_captured_key_store() {
+    let keys: Arc<HashMap<String, u8>> =
+        Arc::new(vec![("k1".to_string(), 21)].into_iter().collect());
+    let decrypted = Arc::new(Mutex::new(vec![]));
+
+    let encryptor_keys = keys.clone();
+    let encryptor: PropertyEncryptor = Box::new(move |context, input| {
+        let key = encryptor_keys.get("k1").ok_or(EncryptionError::Permanent)?;
+        context.algorithm = Some("XOR".to_string());
+        context.kid = Some("k1".to_string());
+        Ok(input.iter().map(|u| u ^ key).collect())
+    });
+    let decryptor_keys = keys.clone();
+    let decryptor_log = decrypted.clone();
+    let decryptor = move || -> PropertyDecryptor {
+        let keys = decryptor_keys.clone();
+        let log = decryptor_log.clone();
+        Box::new(move |context, input| {
+            let kid = context.kid.as_deref().ok_or(EncryptionError::Permanent)?;
+            let key = keys.get(kid).ok_or(EncryptionError::Permanent)?;
+            log.lock().unwrap().push((
+                context.document_id.clone(),
+                context.key_path.clone(),
+                context.algorithm.clone(),
+            ));
+            Ok(input.iter().map(|u| u ^ key).collect())
+        })
+    };
+
+    let context1 = ReplicationConfigurationContext {
+        property_encryptor: Some(encryptor),
+        property_decryptor: Some(decryptor()),
+        ..Default::default()
+    };
+    let context2 = ReplicationConfigurationContext {
+        property_decryptor: Some(decryptor()),
+        ..Default::default()
+    };
+
+    let mut tester = utils::ReplicationThreeDbsTester::new(
+        utils::ReplicationTestConfiguration::default(),
+        utils::ReplicationTestConfiguration::default(),
+        Box::new(context1),
+        Box::new(context2),
+    );
+
+    tester.test(|local_db1, local_db2, _, _, _| {
+        {
+            let mut doc_db1 = Document::new_with_id("foo");
+            let mut props = doc_db1.mutable_properties();
+            props
+                .at("s")
+                .put_encrypt(&Encryptable::create_with_string("test_encryption"));
+            local_db1
+                .save_document_with_concurency_control(
+                    &mut doc_db1,
+                    ConcurrencyControl::FailOnConflict,
+                )
+                .expect("save");
+        }
+
+        // Check document is replicated with data decrypted in DB 2, with the key ID set by the
+        // encryptor
+        assert!(utils::check_callback_with_wait(
+            || local_db2.get_document("foo").is_ok(),
+            None
+        ));
+        let doc_db2 = local_db2.get_document("foo").unwrap();
+        let encryptable = doc_db2.properties().get("s").get_encryptable_value();
+        assert_eq!(encryptable.get_value().as_string(), Some("test_encryption"));
+        drop(encryptable);
+        assert_eq!(
+            decrypted.lock().unwrap().first(),
+            Some(&(
+                Some("foo".to_string()),
+                Some("s".to_string()),
+                Some("XOR".to_string())
+            ))
+        );
+    });
+}
+
 #[test]
 fn encryption_error_temporary() {
     let config = utils::ReplicationTestConfiguration {
@@ -619,8 +870,8 @@ fn encryption_error_temporary() {
     };
 
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor_err_temporary),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor_err_temporary)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -654,8 +905,8 @@ fn encryption_error_temporary() {
 
     // Change local DB 1 replicator to make the encryption work
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -681,8 +932,8 @@ fn decryption_error_temporary() {
     };
 
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor_err_temporary),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor_err_temporary)),
         ..Default::default()
     };
 
@@ -716,8 +967,8 @@ fn decryption_error_temporary() {
 
     // Change local DB replicator to make the decryption work
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -743,8 +994,8 @@ fn encryption_error_permanent() {
     };
 
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor_err_permanent),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor_err_permanent)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -778,8 +1029,8 @@ fn encryption_error_permanent() {
 
     // Change local DB 1 replicator to make the encryption work
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -825,8 +1076,8 @@ fn decryption_error_permanent() {
     };
 
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor_err_permanent),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor_err_permanent)),
         ..Default::default()
     };
 
@@ -860,8 +1111,8 @@ fn decryption_error_permanent() {
 
     // Change local DB replicator to make the decryption work
     let context = ReplicationConfigurationContext {
-        property_encryptor: Some(encryptor),
-        property_decryptor: Some(decryptor),
+        property_encryptor: Some(Box::new(encryptor)),
+        property_decryptor: Some(Box::new(decryptor)),
         ..Default::default()
     };
 
@@ -899,6 +1150,553 @@ fn decryption_error_permanent() {
     });
 }
 
+#[test]
+fn run_once() {
+    let config = utils::ReplicationTestConfiguration {
+        continuous: false,
+        ..Default::default()
+    };
+
+    let mut tester = utils::ReplicationTwoDbsTester::new(
+        config,
+        Box::new(ReplicationConfigurationContext::default()),
+    );
+
+    tester.test(|local_db, central_db, repl| {
+        utils::add_doc(local_db, "foo", 1234, "Hello World!");
+        let statuses = repl.status_stream();
+
+        // The one-shot replication is done when the call returns
+        let status = repl.run_once(Duration::from_secs(10)).unwrap();
+        assert_eq!(status.activity, ReplicatorActivityLevel::Stopped);
+        assert!(central_db.get_document("foo").is_ok());
+
+        let received: Vec<ReplicatorStatus> = statuses.try_iter().collect();
+        assert_eq!(
+            received.last().map(|status| status.activity),
+            Some(ReplicatorActivityLevel::Stopped)
+        );
+
+        // Already stopped
+        let status = repl
+            .wait_until(ReplicatorActivityLevel::Idle, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(status.activity, ReplicatorActivityLevel::Stopped);
+
+        // A continuous replicator is stopped once idle, and the status it stopped with returned
+        utils::add_doc(local_db, "bar", 1, "Hello again!");
+        let config = ReplicatorConfiguration::builder(local_db)
+            .local_database(central_db)
+            .continuous(true)
+            .build()
+            .unwrap();
+        let mut continuous =
+            Replicator::new(config, Box::new(ReplicationConfigurationContext::default())).unwrap();
+        let status = continuous.run_once(Duration::from_secs(10)).unwrap();
+        assert_eq!(status.activity, ReplicatorActivityLevel::Stopped);
+        assert_eq!(
+            continuous.status().activity,
+            ReplicatorActivityLevel::Stopped
+        );
+        assert!(central_db.get_document("bar").is_ok());
+    });
+}
+
+#[test]
+fn wait_until_idle() {
+    let mut tester = utils::ReplicationTwoDbsTester::new(
+        utils::ReplicationTestConfiguration::default(),
+        Box::new(ReplicationConfigurationContext::default()),
+    );
+
+    tester.test(|local_db, central_db, repl| {
+        let status = repl.wait_until(ReplicatorActivityLevel::Idle, Duration::from_secs(10));
+        assert_eq!(
+            status.map(|status| status.activity),
+            Some(ReplicatorActivityLevel::Idle)
+        );
+
+        let statuses = repl.status_stream();
+        utils::add_doc(local_db, "foo", 1234, "Hello World!");
+        assert!(utils::check_callback_with_wait(
+            || central_db.get_document("foo").is_ok(),
+            None
+        ));
+        assert!(statuses.recv_timeout(Duration::from_secs(10)).is_ok());
+    });
+}
+
+#[test]
+fn document_listener() {
+    let config = utils::ReplicationTestConfiguration {
+        continuous: false,
+        ..Default::default()
+    };
+
+    let mut tester = utils::ReplicationTwoDbsTester::new(
+        config,
+        Box::new(ReplicationConfigurationContext::default()),
+    );
+
+    tester.test(|local_db, central_db, _| {
+        utils::add_doc(local_db, "foo", 1, "Hello World!");
+        utils::add_doc(local_db, "bar", 2, "Hello World!");
+        let bar = local_db.get_document("bar").unwrap();
+        local_db.delete_document(&bar).unwrap();
+        utils::add_doc(central_db, "baz", 3, "Hello World!");
+
+        let documents: Arc<Mutex<Vec<(Direction, ReplicatedDocument)>>> = Arc::default();
+        let counts: Arc<Mutex<ReplicatedDocumentCounts>> = Arc::default();
+        let config = ReplicatorConfiguration::builder(local_db)
+            .local_database(central_db)
+            .disable_auto_purge(true)
+            .build()
+            .unwrap();
+        let mut repl =
+            Replicator::new(config, Box::new(ReplicationConfigurationContext::default()))
+                .unwrap()
+                .add_document_listener({
+                    let documents = documents.clone();
+                    let counts = counts.clone();
+                    Box::new(move |direction, replicated| {
+                        counts.lock().unwrap().add(direction, &replicated);
+                        documents
+                            .lock()
+                            .unwrap()
+                            .extend(replicated.into_iter().map(|document| (direction, document)));
+                    })
+                });
+        repl.run_once(Duration::from_secs(10)).unwrap();
+
+        let documents = documents.lock().unwrap();
+        let find = |id: &str| {
+            documents
+                .iter()
+                .find(|(_, document)| document.id == id)
+                .unwrap()
+        };
+
+        let (direction, foo) = find("foo");
+        assert_eq!(*direction, Direction::Pushed);
+        assert!(foo.succeeded());
+        assert!(foo.flags.is_empty());
+        assert_eq!(
+            foo.local_revision_id(local_db).as_deref(),
+            local_db.get_document("foo").unwrap().revision_id()
+        );
+
+        let (direction, bar) = find("bar");
+        assert_eq!(*direction, Direction::Pushed);
+        assert_eq!(bar.flags, DocumentFlags::DELETED);
+        assert!(bar.local_revision_id(local_db).is_some());
+
+        let (direction, baz) = find("baz");
+        assert_eq!(*direction, Direction::Pulled);
+        assert!(!baz.is_deleted());
+        assert!(baz.local_revision_id(local_db).is_some());
+
+        let counts = *counts.lock().unwrap();
+        assert_eq!(
+            counts.pushed,
+            DirectionCounts {
+                succeeded: 2,
+                failed: 0,
+                deleted: 1,
+                access_removed: 0,
+            }
+        );
+        assert_eq!(counts.get(Direction::Pulled).succeeded, 1);
+        assert_eq!(counts.failed(), 0);
+
+        // Failures are counted apart
+        let failed = ReplicatedDocument {
+            id: "qux".to_string(),
+            flags: DocumentFlags::DELETED | DocumentFlags::ACCESS_REMOVED,
+            error: Err(Error::from_code(ErrorCode::Network(NetworkError::Timeout))),
+        };
+        assert!(failed.flags.contains(DocumentFlags::ACCESS_REMOVED));
+        let counts = DirectionCounts::of(&[failed]);
+        assert_eq!(counts.failed, 1);
+        assert_eq!(counts.deleted, 0);
+        assert_eq!(counts.total(), 1);
+    });
+}
+
+#[test]
+fn replication_metrics() {
+    let config = utils::ReplicationTestConfiguration {
+        continuous: false,
+        ..Default::default()
+    };
+
+    let mut tester = utils::ReplicationTwoDbsTester::new(
+        config,
+        Box::new(ReplicationConfigurationContext::default()),
+    );
+
+    tester.test(|local_db, central_db, _| {
+        utils::add_doc(local_db, "foo", 1, "Hello World!");
+        utils::add_doc(local_db, "bar", 2, "Hello World!");
+        let bar = local_db.get_document("bar").unwrap();
+        local_db.delete_document(&bar).unwrap();
+
+        let metrics = ReplicationMetrics::new();
+        let config = ReplicatorConfiguration::builder(local_db)
+            .local_database(central_db)
+            .replicator_type(ReplicatorType::Push)
+            .disable_auto_purge(true)
+            .build()
+            .unwrap();
+        let context = metrics.instrument(Box::new(ReplicationConfigurationContext::default()));
+        let mut repl = metrics.attach(Replicator::new(config, context).unwrap());
+        repl.run_once(Duration::from_secs(10)).unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.documents_pushed, 2);
+        assert_eq!(snapshot.documents_pulled, 0);
+        assert_eq!(
+            snapshot.bytes_pushed,
+            local_db
+                .get_document("foo")
+                .unwrap()
+                .properties_as_json()
+                .len() as u64
+        );
+        assert_eq!(snapshot.bytes_pulled, 0);
+        assert_eq!(snapshot.error_count(), 0);
+        assert_eq!(snapshot.reconnects, 0);
+        assert!(snapshot.time_in(ReplicatorActivityLevel::Busy) > Duration::ZERO);
+        assert!(snapshot
+            .activity_time
+            .contains_key(&ReplicatorActivityLevel::Stopped));
+
+        // The error stopping the replicator is counted once
+        let config = ReplicatorConfiguration::builder(local_db)
+            .url("ws://localhost:1/db")
+            .max_attempts(1)
+            .build()
+            .unwrap();
+        let mut repl = metrics.attach(
+            Replicator::new(config, Box::new(ReplicationConfigurationContext::default())).unwrap(),
+        );
+        let err = repl.run_once(Duration::from_secs(10)).err().unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.error_count(), 1);
+        assert_eq!(snapshot.errors.get(&err.code), Some(&1));
+
+        metrics.reset();
+        assert_eq!(metrics.snapshot().documents_pushed, 0);
+    });
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn replication_metrics_prometheus() {
+    let mut snapshot = metrics::MetricsSnapshot {
+        documents_pushed: 3,
+        bytes_pushed: 120,
+        reconnects: 1,
+        ..Default::default()
+    };
+    snapshot
+        .errors
+        .insert(ErrorCode::Network(NetworkError::Timeout), 2);
+    snapshot
+        .activity_time
+        .insert(ReplicatorActivityLevel::Idle, Duration::from_millis(1500));
+
+    let text = snapshot.to_prometheus(&[("replicator", "main \"db\"")]);
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.contains(&"# TYPE cbl_replication_documents_total counter"));
+    assert!(lines.contains(
+        &r#"cbl_replication_documents_total{replicator="main \"db\"",direction="push"} 3"#
+    ));
+    assert!(lines.contains(
+        &r#"cbl_replication_documents_total{replicator="main \"db\"",direction="pull"} 0"#
+    ));
+    assert!(lines.contains(
+        &r#"cbl_replication_bytes_total{replicator="main \"db\"",direction="push"} 120"#
+    ));
+    assert!(lines.contains(
+        &r#"cbl_replication_errors_total{replicator="main \"db\"",domain="Network",code="Timeout"} 2"#
+    ));
+    assert!(lines.contains(
+        &r#"cbl_replication_activity_seconds_total{replicator="main \"db\"",level="idle"} 1.5"#
+    ));
+    assert!(lines.contains(&r#"cbl_replication_reconnects_total{replicator="main \"db\""} 1"#));
+}
+
+#[test]
+fn configuration_builder() {
+    utils::with_db(|db| {
+        let config = ReplicatorConfiguration::builder(db)
+            .url("wss://localhost:4984/db")
+            .continuous(true)
+            .heartbeat(Duration::from_secs(60))
+            .channels(vec!["news", "sports"])
+            .document_ids(vec!["foo".to_string()])
+            .header("X-Client", "tests")
+            .build()
+            .unwrap();
+        assert!(config.continuous);
+        assert_eq!(config.heartbeat, 60);
+        assert_eq!(config.replicator_type, ReplicatorType::PushAndPull);
+        assert_eq!(
+            config.endpoint.url.as_deref(),
+            Some("wss://localhost:4984/db")
+        );
+        assert_eq!(config.channels.count(), 2);
+        assert_eq!(config.document_ids.get(0).as_string(), Some("foo"));
+        assert_eq!(
+            config.headers.get("X-Client").map(String::as_str),
+            Some("tests")
+        );
+
+        // A one-byte DER SEQUENCE, alone and in PEM
+        let der = vec![0x30, 0x01, 0x00];
+        let pem = b"-----BEGIN CERTIFICATE-----\nMAEA\n-----END CERTIFICATE-----\n".to_vec();
+        assert!(ReplicatorConfiguration::builder(db)
+            .url("ws://localhost:4984/db")
+            .pinned_server_certificate(der)
+            .trusted_root_certificates(pem.clone())
+            .build()
+            .is_ok());
+
+        let invalid = |builder: ReplicatorConfigurationBuilder| builder.build().err().unwrap();
+        assert_eq!(
+            invalid(ReplicatorConfiguration::builder(db)),
+            ConfigurationError::MissingEndpoint
+        );
+        assert_eq!(
+            invalid(ReplicatorConfiguration::builder(db).url("http://localhost:4984/db")),
+            ConfigurationError::InvalidUrl("http://localhost:4984/db".to_string())
+        );
+        assert_eq!(
+            invalid(ReplicatorConfiguration::builder(db).url("wss:///db")),
+            ConfigurationError::InvalidUrl("wss:///db".to_string())
+        );
+        assert_eq!(
+            invalid(
+                ReplicatorConfiguration::builder(db)
+                    .url("wss://localhost/db")
+                    .heartbeat(Duration::from_secs(u64::from(MAX_REPLICATOR_INTERVAL) + 1))
+            ),
+            ConfigurationError::HeartbeatOutOfRange(MAX_REPLICATOR_INTERVAL + 1)
+        );
+        assert_eq!(
+            invalid(
+                ReplicatorConfiguration::builder(db)
+                    .url("wss://localhost/db")
+                    .pinned_server_certificate(b"not a certificate".to_vec())
+            ),
+            ConfigurationError::InvalidPinnedCertificate
+        );
+        assert_eq!(
+            invalid(
+                ReplicatorConfiguration::builder(db)
+                    .url("wss://localhost/db")
+                    .trusted_root_certificates(pem[..30].to_vec())
+            ),
+            ConfigurationError::InvalidTrustedRootCertificates
+        );
+        assert_eq!(
+            invalid(
+                ReplicatorConfiguration::builder(db)
+                    .url("wss://localhost/db")
+                    .channels(vec![""])
+            ),
+            ConfigurationError::EmptyChannel
+        );
+    });
+}
+
+#[test]
+fn configuration_error_into_error() {
+    utils::with_db(|db| {
+        let code = |builder: ReplicatorConfigurationBuilder| {
+            let build = || -> Result<ReplicatorConfiguration> { Ok(builder.build()?) };
+            build().err().unwrap().code
+        };
+        let builder = || ReplicatorConfiguration::builder(db).url("ws://localhost:4984/db");
+        assert_eq!(
+            code(ReplicatorConfiguration::builder(db)),
+            ErrorCode::Network(NetworkError::InvalidURL)
+        );
+        assert_eq!(
+            code(ReplicatorConfiguration::builder(db).url("http://localhost:4984/db")),
+            ErrorCode::Network(NetworkError::InvalidURL)
+        );
+        assert_eq!(
+            code(builder().pinned_server_certificate(b"not a certificate".to_vec())),
+            ErrorCode::CouchbaseLite(CouchbaseLiteError::Crypto)
+        );
+        assert_eq!(
+            code(builder().document_ids(vec![""])),
+            ErrorCode::CouchbaseLite(CouchbaseLiteError::BadDocID)
+        );
+        assert_eq!(
+            code(builder().heartbeat(Duration::from_secs(u64::from(u32::MAX)))),
+            ErrorCode::CouchbaseLite(CouchbaseLiteError::InvalidParameter)
+        );
+    });
+}
+
+#[test]
+fn configuration_builder_without_channels() {
+    let config = utils::ReplicationTestConfiguration {
+        continuous: false,
+        ..Default::default()
+    };
+
+    let mut tester = utils::ReplicationTwoDbsTester::new(
+        config,
+        Box::new(ReplicationConfigurationContext::default()),
+    );
+
+    tester.test(|local_db, central_db, _| {
+        utils::add_doc(local_db, "foo", 1, "Hello World!");
+        utils::add_doc(central_db, "bar", 2, "Hello World!");
+        utils::add_doc(local_db, migration::DEFAULT_DOCUMENT_ID, 1, "local");
+        utils::add_doc(central_db, migration::DEFAULT_DOCUMENT_ID, 2, "central");
+
+        // No channels nor document IDs: the filters are left unset, not set to empty arrays
+        let config = ReplicatorConfiguration::builder(local_db)
+            .local_database(central_db)
+            .build()
+            .unwrap();
+        assert_eq!(config.channels.count(), 0);
+        assert_eq!(config.document_ids.count(), 0);
+
+        let mut repl =
+            Replicator::new(config, Box::new(ReplicationConfigurationContext::default())).unwrap();
+        repl.run_once(Duration::from_secs(10)).unwrap();
+
+        assert!(central_db.get_document("foo").is_ok());
+        assert!(local_db.get_document("bar").is_ok());
+
+        // The schema version of each database stays local:
+        for (db, i) in [(&*local_db, 1), (&*central_db, 2)] {
+            let doc = db.get_document(migration::DEFAULT_DOCUMENT_ID).unwrap();
+            assert_eq!(doc.properties().get("i").as_i64(), Some(i));
+        }
+    });
+}
+
+#[cfg(feature = "crypto")]
+mod crypto_test {
+    use super::*;
+    use couchbase_lite::crypto::{self, KeyRing};
+
+    fn crypto_context(properties: &MutableDict, key_path: &str) -> PropertyCryptoContext {
+        PropertyCryptoContext {
+            document_id: Some("foo".to_string()),
+            properties: properties.as_dict(),
+            key_path: Some(key_path.to_string()),
+            algorithm: None,
+            kid: None,
+        }
+    }
+
+    #[test]
+    fn aes_gcm_key_rotation() {
+        let keys = Arc::new(KeyRing::with_key("k1", EncryptionKey::from_bytes([1; 32])));
+        let encryptor = crypto::encryptor(keys.clone());
+        let decryptor = crypto::decryptor(keys.clone());
+        let properties = MutableDict::new();
+
+        let mut context = crypto_context(&properties, "s");
+        let sealed = encryptor(&mut context, b"secret").unwrap();
+        assert_eq!(context.algorithm.as_deref(), Some(crypto::ALGORITHM));
+        assert_eq!(context.kid.as_deref(), Some("k1"));
+        assert_ne!(&sealed[12..18], b"secret");
+        assert_eq!(decryptor(&context, &sealed).unwrap(), b"secret");
+
+        // Values encrypted with the previous key are still decrypted after a rotation
+        keys.rotate("k2", EncryptionKey::from_bytes([2; 32]));
+        let mut rotated = crypto_context(&properties, "s");
+        let resealed = encryptor(&mut rotated, b"secret").unwrap();
+        assert_eq!(rotated.kid.as_deref(), Some("k2"));
+        assert_eq!(decryptor(&rotated, &resealed).unwrap(), b"secret");
+        assert_eq!(decryptor(&context, &sealed).unwrap(), b"secret");
+
+        // Tampered values, and values moved to another property, can never be decrypted
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decryptor(&context, &tampered),
+            Err(EncryptionError::Permanent)
+        );
+        let mut moved = crypto_context(&properties, "t");
+        moved.algorithm = context.algorithm.clone();
+        moved.kid = context.kid.clone();
+        assert_eq!(decryptor(&moved, &sealed), Err(EncryptionError::Permanent));
+
+        // Until a missing key is provided
+        keys.remove_key("k1");
+        assert_eq!(
+            decryptor(&context, &sealed),
+            Err(EncryptionError::Temporary)
+        );
+        keys.remove_key("k2");
+        assert_eq!(
+            encryptor(&mut crypto_context(&properties, "s"), b"secret"),
+            Err(EncryptionError::Temporary)
+        );
+    }
+
+    #[test]
+    fn aes_gcm_replication() {
+        let keys: Arc<KeyRing> =
+            Arc::new(KeyRing::with_key("k1", EncryptionKey::from_bytes([3; 32])));
+        let context1 = ReplicationConfigurationContext {
+            property_encryptor: Some(crypto::encryptor(keys.clone())),
+            ..Default::default()
+        };
+        let context2 = ReplicationConfigurationContext {
+            property_decryptor: Some(crypto::decryptor(keys.clone())),
+            ..Default::default()
+        };
+
+        let mut tester = utils::ReplicationThreeDbsTester::new(
+            utils::ReplicationTestConfiguration::default(),
+            utils::ReplicationTestConfiguration::default(),
+            Box::new(context1),
+            Box::new(context2),
+        );
+
+        tester.test(|local_db1, local_db2, central_db, _, _| {
+            {
+                let mut doc_db1 = Document::new_with_id("foo");
+                let mut props = doc_db1.mutable_properties();
+                props
+                    .at("s")
+                    .put_encrypt(&Encryptable::create_with_string("test_encryption"));
+                local_db1
+                    .save_document_with_concurency_control(
+                        &mut doc_db1,
+                        ConcurrencyControl::FailOnConflict,
+                    )
+                    .expect("save");
+            }
+
+            // Check document is replicated encrypted in central, and decrypted in DB 2
+            assert!(utils::check_callback_with_wait(
+                || local_db2.get_document("foo").is_ok(),
+                None
+            ));
+            let doc_central = central_db.get_document("foo").unwrap();
+            let encrypted = doc_central.properties().get("encrypted$s").as_dict();
+            assert_eq!(encrypted.get("alg").as_string(), Some(crypto::ALGORITHM));
+            assert_eq!(encrypted.get("kid").as_string(), Some("k1"));
+
+            let doc_db2 = local_db2.get_document("foo").unwrap();
+            let encryptable = doc_db2.properties().get("s").get_encryptable_value();
+            assert_eq!(encryptable.get_value().as_string(), Some("test_encryption"));
+            drop(encryptable);
+        });
+    }
+}
+
 #[cfg(feature = "unsafe-threads-test")]
 mod unsafe_test {
     use super::*;